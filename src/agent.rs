@@ -0,0 +1,211 @@
+//! SSH agent backends: where to find a running agent on each platform.
+//!
+//! - Windows: the Windows OpenSSH agent named pipe, then Pageant.
+//! - Unix (Linux/macOS): the socket named by `SSH_AUTH_SOCK`.
+//!
+//! Every backend yields the same type-erased `AgentClient`, so the
+//! authentication code in `ssh.rs` doesn't care how the agent is reached.
+//! The same sources back agent forwarding on the bootstrap session.
+
+use anyhow::{bail, Result};
+use russh::keys::agent::client::{AgentClient, AgentStream};
+use russh::keys::agent::AgentIdentity;
+use russh::keys::{Certificate, PublicKey};
+use std::fmt;
+use tokio::io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _};
+#[cfg(unix)]
+use std::path::PathBuf;
+
+/// An agent connection, independent of the underlying transport.
+pub type DynAgentClient = AgentClient<Box<dyn AgentStream + Send + Unpin + 'static>>;
+
+// Agent protocol messages (draft-miller-ssh-agent, section 5.1).
+const REQUEST_IDENTITIES: u8 = 11;
+const IDENTITIES_ANSWER: u8 = 12;
+
+/// Largest agent reply we'll read, as in OpenSSH.
+const MAX_REPLY_LEN: usize = 256 * 1024;
+
+// Windows OpenSSH agent named pipe path.
+#[cfg(windows)]
+const OPENSSH_AGENT_PIPE: &str = r"\\.\pipe\openssh-ssh-agent";
+
+/// A place where an SSH agent may be listening.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AgentSource {
+    /// Windows OpenSSH agent named pipe.
+    #[cfg(windows)]
+    NamedPipe(String),
+    /// PuTTY's Pageant.
+    #[cfg(windows)]
+    Pageant,
+    /// Unix-domain socket, usually from `SSH_AUTH_SOCK`.
+    #[cfg(unix)]
+    UnixSocket(PathBuf),
+}
+
+impl AgentSource {
+    /// Open a connection to this agent.
+    pub async fn connect(&self) -> Result<DynAgentClient> {
+        match self {
+            #[cfg(windows)]
+            AgentSource::NamedPipe(path) => {
+                let agent = AgentClient::connect_named_pipe(path).await?;
+                Ok(agent.dynamic())
+            }
+            #[cfg(windows)]
+            AgentSource::Pageant => {
+                // The `pageant` crate panics (unwrap) if Pageant isn't running, so we
+                // MUST check that the Pageant window exists before connecting.
+                if !is_pageant_running() {
+                    anyhow::bail!("Pageant is not running");
+                }
+                Ok(AgentClient::connect_pageant().await?.dynamic())
+            }
+            #[cfg(unix)]
+            AgentSource::UnixSocket(path) => {
+                let agent = AgentClient::connect_uds(path).await?;
+                Ok(agent.dynamic())
+            }
+        }
+    }
+}
+
+impl fmt::Display for AgentSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            #[cfg(windows)]
+            AgentSource::NamedPipe(_) => write!(f, "Windows OpenSSH agent"),
+            #[cfg(windows)]
+            AgentSource::Pageant => write!(f, "Pageant agent"),
+            #[cfg(unix)]
+            AgentSource::UnixSocket(path) => write!(f, "SSH agent at {}", path.display()),
+        }
+    }
+}
+
+/// Agents to try, in order, on this platform.
+#[cfg(windows)]
+pub fn sources() -> Vec<AgentSource> {
+    vec![
+        AgentSource::NamedPipe(OPENSSH_AGENT_PIPE.to_string()),
+        AgentSource::Pageant,
+    ]
+}
+
+/// Agents to try, in order, on this platform.
+#[cfg(unix)]
+pub fn sources() -> Vec<AgentSource> {
+    match std::env::var_os("SSH_AUTH_SOCK") {
+        Some(sock) if !sock.is_empty() => vec![AgentSource::UnixSocket(PathBuf::from(sock))],
+        _ => Vec::new(),
+    }
+}
+
+/// The keys and certificates an agent holds, and the agent to sign with them.
+///
+/// `AgentClient::request_identities` gives up on the first key blob russh
+/// can't parse, so a single key of an unsupported type (a FIDO key, say)
+/// would hide every other identity. Here each blob is parsed on its own and
+/// the ones russh can't use are skipped.
+pub async fn list_identities(agent: DynAgentClient) -> Result<(DynAgentClient, Vec<AgentIdentity>)> {
+    let mut stream = agent.into_inner();
+    stream.write_all(&[0, 0, 0, 1, REQUEST_IDENTITIES]).await?;
+    stream.flush().await?;
+    let len = stream.read_u32().await? as usize;
+    if len > MAX_REPLY_LEN {
+        bail!("agent reply too long ({} bytes)", len);
+    }
+    let mut reply = vec![0; len];
+    stream.read_exact(&mut reply).await?;
+
+    let identities = parse_identities(&reply)?;
+    Ok((AgentClient::connect(stream), identities))
+}
+
+/// Parse an IDENTITIES_ANSWER, keeping the identities russh can sign with.
+fn parse_identities(reply: &[u8]) -> Result<Vec<AgentIdentity>> {
+    let Some((&IDENTITIES_ANSWER, mut rest)) = reply.split_first() else {
+        bail!("agent refused to list identities");
+    };
+    let count = take_u32(&mut rest)?;
+    let mut identities = Vec::new();
+    for _ in 0..count {
+        let blob = take_string(&mut rest)?;
+        let comment = String::from_utf8_lossy(take_string(&mut rest)?).into_owned();
+        let algorithm = take_string(&mut &blob[..])
+            .map(String::from_utf8_lossy)
+            .unwrap_or_default();
+        let parsed = if algorithm.ends_with("-cert-v01@openssh.com") {
+            Certificate::from_bytes(blob).map(|certificate| AgentIdentity::Certificate {
+                certificate,
+                comment: comment.clone(),
+            })
+        } else {
+            PublicKey::from_bytes(blob).map(|key| AgentIdentity::PublicKey {
+                key,
+                comment: comment.clone(),
+            })
+        };
+        match parsed {
+            Ok(identity) => identities.push(identity),
+            Err(e) => log::debug!("SSH: skipping agent key {:?} ({}): {}", comment, algorithm, e),
+        }
+    }
+    Ok(identities)
+}
+
+/// Split `n` bytes off the front of `buf`.
+fn take<'a>(buf: &mut &'a [u8], n: usize) -> Result<&'a [u8]> {
+    if buf.len() < n {
+        bail!("truncated agent reply");
+    }
+    let (head, tail) = buf.split_at(n);
+    *buf = tail;
+    Ok(head)
+}
+
+fn take_u32(buf: &mut &[u8]) -> Result<u32> {
+    let bytes = take(buf, 4)?;
+    Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Split an SSH `string` (u32 length, then the bytes) off the front of `buf`.
+fn take_string<'a>(buf: &mut &'a [u8]) -> Result<&'a [u8]> {
+    let len = take_u32(buf)? as usize;
+    take(buf, len)
+}
+
+/// Relay an agent channel opened by the SSH server to the first local agent
+/// that answers, until either side closes.
+pub async fn proxy<S>(mut channel: S) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    for source in sources() {
+        match source.connect().await {
+            Ok(agent) => {
+                log::debug!("forwarding agent requests to {}", source);
+                let mut stream = agent.into_inner();
+                tokio::io::copy_bidirectional(&mut channel, &mut stream).await?;
+                return Ok(());
+            }
+            Err(e) => log::debug!("{} unavailable: {}", source, e),
+        }
+    }
+    anyhow::bail!("no local SSH agent is available to forward")
+}
+
+/// Check if PuTTY's Pageant is running by looking for its window.
+#[cfg(windows)]
+fn is_pageant_running() -> bool {
+    unsafe {
+        let class_name = b"Pageant\0";
+        let window_name = b"Pageant\0";
+        let hwnd = windows_sys::Win32::UI::WindowsAndMessaging::FindWindowA(
+            class_name.as_ptr(),
+            window_name.as_ptr(),
+        );
+        !hwnd.is_null()
+    }
+}
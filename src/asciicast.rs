@@ -0,0 +1,282 @@
+//! `--asciicast`: the remote screen as an asciinema v2 recording.
+//!
+//! The first line is the header; every later line is an event,
+//! `[seconds, "o", text]` for output or `[seconds, "r", "WxH"]` for a
+//! resize. The output events are the host bytes from each new remote state,
+//! written as they arrive, so any asciicast player shows what the client
+//! did. Only what the server sent is recorded: no predictions, no
+//! notification bar.
+//!
+//! Host bytes are a diff from the state the server based them on. When that
+//! isn't the state last written (the server diffed from an older state we
+//! had already moved past) they'd draw the wrong thing, so the new screen
+//! is written as a full repaint instead.
+
+use crate::display::repaint;
+use crate::jsonlines::JsonLines;
+use crate::terminal::Framebuffer;
+use crate::transport::proto::hostinput::HostMessage;
+use anyhow::{Context, Result};
+use prost::Message;
+use serde::Serialize;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::time::Instant;
+
+#[derive(Serialize)]
+struct Header {
+    version: u32,
+    width: usize,
+    height: usize,
+    timestamp: u64,
+}
+
+/// Turns remote states into cast events as they arrive.
+pub struct Asciicast {
+    out: JsonLines,
+    start: Instant,
+    /// The screen the events so far draw, and its state number.
+    shown: Framebuffer,
+    shown_num: u64,
+    /// The start of a UTF-8 sequence split across host byte strings.
+    partial: Vec<u8>,
+}
+
+impl Asciicast {
+    /// Cast to `path`, replacing any file there. The header waits for
+    /// `header`, when the window size is known.
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = std::fs::File::create(path)
+            .with_context(|| format!("Failed to create asciicast {}", path.display()))?;
+        Ok(Self {
+            out: JsonLines::new(file, "asciicast"),
+            start: Instant::now(),
+            shown: Framebuffer::new(1, 1),
+            shown_num: 0,
+            partial: Vec::new(),
+        })
+    }
+
+    /// Start the cast on a blank `width`x`height` screen, state 0.
+    pub fn header(&mut self, width: usize, height: usize) {
+        self.start = Instant::now();
+        self.shown = Framebuffer::new(width, height);
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        self.out.write(&Header { version: 2, width, height, timestamp });
+    }
+
+    /// The remote screen moved on to state `new_num`, made by applying
+    /// `diff` (a `HostMessage`) to state `old_num`; `fb` is the result.
+    pub fn state(&mut self, old_num: u64, new_num: u64, diff: &[u8], fb: &Framebuffer) {
+        let host_msg = if old_num == self.shown_num { HostMessage::decode(diff).ok() } else { None };
+        if let Some(host_msg) = host_msg {
+            for inst in host_msg.instruction {
+                if let Some(resize) = inst.resize {
+                    let (w, h) = (resize.width.unwrap_or_default(), resize.height.unwrap_or_default());
+                    if w > 0 && h > 0 {
+                        self.event("r", format!("{}x{}", w, h));
+                    }
+                }
+                if let Some(data) = inst.hostbytes.and_then(|hb| hb.hoststring) {
+                    let text = self.take_utf8(&data);
+                    if !text.is_empty() {
+                        self.event("o", text);
+                    }
+                }
+            }
+        } else {
+            log::debug!("asciicast: state {} is based on {}, not {}; repainting", new_num, old_num, self.shown_num);
+            let mut text = self.take_utf8(&[]);
+            if !self.partial.is_empty() {
+                text.push(char::REPLACEMENT_CHARACTER);
+                self.partial.clear();
+            }
+            if (fb.width, fb.height) != (self.shown.width, self.shown.height) {
+                self.event("r", format!("{}x{}", fb.width, fb.height));
+            }
+            text.push_str(&String::from_utf8_lossy(&repaint(fb)));
+            self.event("o", text);
+        }
+        self.shown = fb.clone();
+        self.shown_num = new_num;
+    }
+
+    /// `bytes` after whatever was held back, as text, holding back an
+    /// incomplete UTF-8 sequence at the end. Invalid bytes become U+FFFD.
+    fn take_utf8(&mut self, bytes: &[u8]) -> String {
+        self.partial.extend_from_slice(bytes);
+        let mut text = String::new();
+        let mut rest = &self.partial[..];
+        loop {
+            match std::str::from_utf8(rest) {
+                Ok(valid) => {
+                    text.push_str(valid);
+                    rest = &[];
+                    break;
+                }
+                Err(e) => {
+                    let (valid, after) = rest.split_at(e.valid_up_to());
+                    text.push_str(std::str::from_utf8(valid).unwrap_or_default());
+                    match e.error_len() {
+                        Some(len) => {
+                            text.push(char::REPLACEMENT_CHARACTER);
+                            rest = &after[len..];
+                        }
+                        None => {
+                            rest = after;
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        self.partial = rest.to_vec();
+        text
+    }
+
+    fn event(&mut self, kind: &str, data: String) {
+        let t = self.start.elapsed().as_secs_f64();
+        self.out.write(&(t, kind, data));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{Base64Key, Direction};
+    use crate::display::same_frame;
+    use crate::scratch::ScratchFile;
+    use crate::terminal::Terminal;
+    use crate::testserver::{exchange, settle, HostSource, TestServer};
+    use crate::transport::proto::hostinput;
+    use crate::transport::Transport;
+
+    /// Parse a cast and play it into a terminal, checking the format as we go.
+    fn play(path: &Path) -> (serde_json::Value, Terminal, usize) {
+        let text = std::fs::read_to_string(path).unwrap();
+        let mut lines = text.lines();
+        let header: serde_json::Value = serde_json::from_str(lines.next().unwrap()).unwrap();
+        assert_eq!(header["version"], 2);
+        let mut term = Terminal::new(
+            header["width"].as_u64().unwrap() as usize,
+            header["height"].as_u64().unwrap() as usize,
+        );
+        let (mut last, mut events) = (0.0, 0);
+        for line in lines {
+            let (t, kind, data): (f64, String, String) = serde_json::from_str(line).unwrap();
+            assert!(t >= last, "time went backwards: {}", line);
+            last = t;
+            events += 1;
+            match kind.as_str() {
+                "o" => term.process(data.as_bytes()),
+                "r" => {
+                    let (w, h) = data.split_once('x').unwrap();
+                    term.resize(w.parse().unwrap(), h.parse().unwrap());
+                }
+                other => panic!("unexpected event type {:?}", other),
+            }
+        }
+        (header, term, events)
+    }
+
+    fn host_message(resize: Option<(i32, i32)>, data: &[u8]) -> Vec<u8> {
+        let mut instruction = Vec::new();
+        if let Some((w, h)) = resize {
+            instruction.push(hostinput::Instruction {
+                resize: Some(hostinput::ResizeMessage { width: Some(w), height: Some(h) }),
+                ..Default::default()
+            });
+        }
+        instruction.push(hostinput::Instruction {
+            hostbytes: Some(hostinput::HostBytes { hoststring: Some(data.to_vec()) }),
+            ..Default::default()
+        });
+        HostMessage { instruction }.encode_to_vec()
+    }
+
+    #[test]
+    fn test_host_bytes_and_resizes() {
+        let file = ScratchFile::new("events.cast");
+        let path = file.path();
+        let mut cast = Asciicast::create(path).unwrap();
+        cast.header(20, 5);
+        let mut term = Terminal::new(20, 5);
+
+        let diff = host_message(Some((30, 6)), b"caf\xc3");
+        term.resize(30, 6);
+        term.process(b"caf\xc3");
+        cast.state(0, 1, &diff, &term.fb);
+        let diff = host_message(None, b"\xa9 \xff!");
+        term.process(b"\xa9 \xff!");
+        cast.state(1, 2, &diff, &term.fb);
+        drop(cast);
+
+        let text = std::fs::read_to_string(path).unwrap();
+        let events: Vec<(f64, String, String)> = text.lines().skip(1).map(|l| serde_json::from_str(l).unwrap()).collect();
+        let events: Vec<_> = events.into_iter().map(|(_, k, d)| (k, d)).collect();
+        assert_eq!(
+            events,
+            [
+                ("r".to_string(), "30x6".to_string()),
+                ("o".to_string(), "caf".to_string()),
+                ("o".to_string(), "\u{e9} \u{fffd}!".to_string()),
+            ]
+        );
+        let (header, played, _) = play(path);
+        assert_eq!((header["width"].as_u64(), header["height"].as_u64()), (Some(20), Some(5)));
+        assert!(header["timestamp"].as_u64().unwrap() > 0);
+        let row: String = played.fb.cells[0].iter().map(|c| c.character).collect();
+        assert_eq!(row.trim_end(), "caf\u{e9} \u{fffd}!");
+        assert_eq!((played.fb.width, played.fb.height), (30, 6));
+    }
+
+    #[test]
+    fn test_diff_from_an_older_state_repaints() {
+        let file = ScratchFile::new("repaint.cast");
+        let path = file.path();
+        let mut cast = Asciicast::create(path).unwrap();
+        cast.header(20, 5);
+        let mut one = Terminal::new(20, 5);
+        one.process(b"\x1b[1mone");
+        cast.state(0, 1, &host_message(None, b"\x1b[1mone"), &one.fb);
+        // State 2 is diffed from state 0, so its bytes alone would leave
+        // "one" on the screen.
+        let mut two = Terminal::new(20, 5);
+        two.process(b"\r\ntwo");
+        cast.state(0, 2, &host_message(None, b"\r\ntwo"), &two.fb);
+        drop(cast);
+
+        let (_, played, events) = play(path);
+        assert_eq!(events, 2);
+        assert!(same_frame(&played.fb, &two.fb));
+    }
+
+    #[tokio::test]
+    async fn test_session_plays_back() {
+        let file = ScratchFile::new("session.cast");
+        let path = file.path();
+        let key = Base64Key::from_str("AAAAAAAAAAAAAAAAAAAAAA").unwrap();
+        let mut server = TestServer::bind(&key, HostSource::Echo, 80, 24).await.unwrap();
+        let mut client = Transport::new(&key, server.local_addr(), Direction::ToServer, 80, 24)
+            .await
+            .unwrap();
+        client.cast_to(Asciicast::create(path).unwrap());
+        client.push_resize(50, 8);
+        for i in 0..20 {
+            client.push_user_input(format!("\u{e9}cho {}\r", i).as_bytes());
+            for _ in 0..5 {
+                exchange(&mut client, &mut server).await;
+            }
+        }
+        settle(&mut client, &mut server).await;
+
+        let (header, played, events) = play(path);
+        assert_eq!(header["width"], 80);
+        assert!(events > 1);
+        assert_eq!((played.fb.width, played.fb.height), (50, 8));
+        assert!(same_frame(&played.fb, client.latest_remote_framebuffer()));
+    }
+}
@@ -0,0 +1,391 @@
+//! Configuration file: client defaults and per-host profiles.
+//!
+//! The file is TOML, read from `--config FILE` or the default location
+//! (`%APPDATA%\mosh-client\config.toml` on Windows,
+//! `$XDG_CONFIG_HOME/mosh-client/config.toml` or `~/.config/...` elsewhere):
+//!
+//! ```toml
+//! predict = "always"
+//! identity = "~/.ssh/id_ed25519"
+//!
+//! [host."*.corp.example.com"]
+//! user = "alice"
+//! ssh-port = 2222
+//! port = "60001:60010"
+//! ```
+//!
+//! Keys are the long command-line flag names. A setting comes from, in order:
+//! the command line, the first matching `[host."pattern"]` profile (patterns
+//! are comma-separated globs with `!` negation, as in known_hosts), the
+//! top-level defaults, and finally the built-in default. Tables (`env`) are
+//! merged key by key with the same precedence; lists (`server-args`) are taken
+//! whole from the first source that sets them.
+
+use crate::ssh;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Every setting that can come from the config file or the command line.
+/// `None` means "not set here", so a lower-precedence source is consulted.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Profile {
+    pub user: Option<String>,
+    pub ssh_port: Option<u16>,
+    pub identity: Option<PathBuf>,
+    pub certificate: Option<PathBuf>,
+    pub strict_host_key_checking: Option<String>,
+    pub known_hosts: Option<PathBuf>,
+    pub global_known_hosts: Option<PathBuf>,
+    pub hash_known_hosts: Option<bool>,
+    pub password_file: Option<PathBuf>,
+    pub password_env: Option<String>,
+    pub server: Option<String>,
+    pub port: Option<String>,
+    pub upload_server: Option<PathBuf>,
+    pub connect_timeout: Option<u64>,
+    pub auth_timeout: Option<u64>,
+    pub server_timeout: Option<u64>,
+    pub predict: Option<String>,
+    pub pty: Option<bool>,
+    pub forward_agent: Option<bool>,
+    pub server_args: Option<Vec<String>>,
+    pub command_key: Option<String>,
+    pub env: Option<BTreeMap<String, String>>,
+}
+
+impl Profile {
+    /// The built-in defaults, used for anything no other source sets.
+    pub fn builtin() -> Profile {
+        Profile {
+            ssh_port: Some(22),
+            strict_host_key_checking: Some("ask".to_string()),
+            hash_known_hosts: Some(false),
+            server: Some("mosh-server".to_string()),
+            connect_timeout: Some(30),
+            auth_timeout: Some(60),
+            server_timeout: Some(30),
+            predict: Some("adaptive".to_string()),
+            pty: Some(false),
+            forward_agent: Some(false),
+            server_args: Some(Vec::new()),
+            command_key: Some("ctrl-^".to_string()),
+            env: Some(BTreeMap::new()),
+            ..Profile::default()
+        }
+    }
+
+    /// Fill every unset field from `fallback`.
+    pub fn or(self, fallback: Profile) -> Profile {
+        let env = match (self.env, fallback.env) {
+            (Some(mut env), Some(base)) => {
+                for (name, value) in base {
+                    env.entry(name).or_insert(value);
+                }
+                Some(env)
+            }
+            (env, base) => env.or(base),
+        };
+        // The password sources replace each other as a pair, so a profile's
+        // password-env isn't shadowed by a default password-file.
+        let (password_file, password_env) =
+            if self.password_file.is_some() || self.password_env.is_some() {
+                (self.password_file, self.password_env)
+            } else {
+                (fallback.password_file, fallback.password_env)
+            };
+        Profile {
+            user: self.user.or(fallback.user),
+            ssh_port: self.ssh_port.or(fallback.ssh_port),
+            identity: self.identity.or(fallback.identity),
+            certificate: self.certificate.or(fallback.certificate),
+            strict_host_key_checking: self
+                .strict_host_key_checking
+                .or(fallback.strict_host_key_checking),
+            known_hosts: self.known_hosts.or(fallback.known_hosts),
+            global_known_hosts: self.global_known_hosts.or(fallback.global_known_hosts),
+            hash_known_hosts: self.hash_known_hosts.or(fallback.hash_known_hosts),
+            password_file,
+            password_env,
+            server: self.server.or(fallback.server),
+            port: self.port.or(fallback.port),
+            upload_server: self.upload_server.or(fallback.upload_server),
+            connect_timeout: self.connect_timeout.or(fallback.connect_timeout),
+            auth_timeout: self.auth_timeout.or(fallback.auth_timeout),
+            server_timeout: self.server_timeout.or(fallback.server_timeout),
+            predict: self.predict.or(fallback.predict),
+            pty: self.pty.or(fallback.pty),
+            forward_agent: self.forward_agent.or(fallback.forward_agent),
+            server_args: self.server_args.or(fallback.server_args),
+            command_key: self.command_key.or(fallback.command_key),
+            env,
+        }
+    }
+
+    /// Check values that are only parsed later, so mistakes in the file are
+    /// reported up front with the offending setting.
+    fn validate(&self) -> Result<()> {
+        if let Some(ref mode) = self.strict_host_key_checking {
+            mode.parse::<ssh::StrictHostKeyChecking>()
+                .context("strict-host-key-checking")?;
+        }
+        if let Some(ref predict) = self.predict {
+            if !matches!(predict.as_str(), "always" | "adaptive" | "never") {
+                bail!("predict: expected always, adaptive or never, got '{}'", predict);
+            }
+        }
+        if let Some(ref port) = self.port {
+            ssh::parse_port_range(port).context("port")?;
+        }
+        if let Some(ref key) = self.command_key {
+            parse_command_key(key).context("command-key")?;
+        }
+        Ok(())
+    }
+
+    /// Expand a leading `~` in every path setting.
+    fn expand_paths(mut self) -> Profile {
+        for path in [
+            &mut self.identity,
+            &mut self.certificate,
+            &mut self.known_hosts,
+            &mut self.global_known_hosts,
+            &mut self.upload_server,
+            &mut self.password_file,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            *path = expand_tilde(path);
+        }
+        self
+    }
+}
+
+/// A parsed configuration file.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ConfigFile {
+    /// Top-level settings.
+    pub defaults: Profile,
+    /// `[host."pattern"]` profiles, in file order.
+    pub hosts: Vec<(String, Profile)>,
+}
+
+impl ConfigFile {
+    /// Parse the TOML text of a config file.
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut table: toml::Table = toml::from_str(text)?;
+        let hosts = match table.remove("host") {
+            Some(toml::Value::Table(hosts)) => hosts,
+            Some(_) => bail!("'host' must be a table of [host.\"pattern\"] sections"),
+            None => toml::Table::new(),
+        };
+
+        let defaults = Profile::deserialize(table)?;
+        defaults.validate()?;
+
+        let mut profiles = Vec::with_capacity(hosts.len());
+        for (pattern, value) in hosts {
+            let profile = Profile::deserialize(value)
+                .with_context(|| format!("in [host.\"{}\"]", pattern))?;
+            profile
+                .validate()
+                .with_context(|| format!("in [host.\"{}\"]", pattern))?;
+            profiles.push((pattern, profile.expand_paths()));
+        }
+
+        Ok(ConfigFile {
+            defaults: defaults.expand_paths(),
+            hosts: profiles,
+        })
+    }
+
+    /// Load the config file: `path` if given (which must exist), otherwise
+    /// the default location if there is a file there.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let (path, required) = match path {
+            Some(path) => (path.to_path_buf(), true),
+            None => match default_path() {
+                Some(path) => (path, false),
+                None => return Ok(Self::default()),
+            },
+        };
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if !required && e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Self::default())
+            }
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to read {}", path.display()))
+            }
+        };
+        log::debug!("Loaded config from {}", path.display());
+        Self::parse(&text).with_context(|| format!("Invalid config file {}", path.display()))
+    }
+
+    /// Settings for `host`: matching profiles in file order, then the defaults.
+    pub fn profile_for(&self, host: &str) -> Profile {
+        self.hosts
+            .iter()
+            .filter(|(pattern, _)| ssh::host_patterns_match(pattern, host))
+            .fold(Profile::default(), |profile, (_, matched)| {
+                profile.or(matched.clone())
+            })
+            .or(self.defaults.clone())
+    }
+}
+
+/// Default config file location for this platform.
+pub fn default_path() -> Option<PathBuf> {
+    #[cfg(windows)]
+    let base = std::env::var_os("APPDATA").map(PathBuf::from);
+    #[cfg(not(windows))]
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| ssh::home_dir().map(|home| home.join(".config")));
+    base.map(|dir| dir.join("mosh-client").join("config.toml"))
+}
+
+/// Parse a command key like `ctrl-^`, `^]` or `ctrl-a` into its control byte.
+pub fn parse_command_key(spec: &str) -> Result<u8> {
+    let lower = spec.to_ascii_lowercase();
+    let c = lower
+        .strip_prefix("ctrl-")
+        .or_else(|| lower.strip_prefix('^'))
+        .filter(|rest| rest.len() == 1)
+        .and_then(|rest| rest.bytes().next());
+    match c {
+        Some(c @ (b'a'..=b'z' | b'@' | b'[' | b'\\' | b']' | b'^' | b'_')) => {
+            Ok(c.to_ascii_uppercase() & 0x1F)
+        }
+        _ => bail!("expected ctrl-X or ^X (X a letter or one of @[\\]^_), got '{}'", spec),
+    }
+}
+
+fn expand_tilde(path: &Path) -> PathBuf {
+    match path.strip_prefix("~") {
+        Ok(rest) => match ssh::home_dir() {
+            Some(home) => home.join(rest),
+            None => path.to_path_buf(),
+        },
+        Err(_) => path.to_path_buf(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: &str = r#"
+predict = "never"
+server = "/usr/bin/mosh-server"
+connect-timeout = 10
+
+[env]
+EDITOR = "vim"
+TZ = "UTC"
+
+[host."*.corp.example.com,!legacy.corp.example.com"]
+user = "alice"
+ssh-port = 2222
+predict = "always"
+env = { TZ = "Europe/Berlin" }
+
+[host."*.example.com"]
+user = "bob"
+server-args = ["-v"]
+"#;
+
+    #[test]
+    fn test_host_profiles_take_precedence_over_defaults() {
+        let config = ConfigFile::parse(SAMPLE).unwrap();
+        let profile = config.profile_for("db.corp.example.com");
+        assert_eq!(profile.user.as_deref(), Some("alice"));
+        assert_eq!(profile.ssh_port, Some(2222));
+        assert_eq!(profile.predict.as_deref(), Some("always"));
+        assert_eq!(profile.server.as_deref(), Some("/usr/bin/mosh-server"));
+        assert_eq!(profile.server_args, Some(vec!["-v".to_string()]));
+        let env = profile.env.unwrap();
+        assert_eq!(env["TZ"], "Europe/Berlin");
+        assert_eq!(env["EDITOR"], "vim");
+    }
+
+    #[test]
+    fn test_negated_and_unmatched_hosts() {
+        let config = ConfigFile::parse(SAMPLE).unwrap();
+        let legacy = config.profile_for("legacy.corp.example.com");
+        assert_eq!(legacy.user.as_deref(), Some("bob"));
+        assert_eq!(legacy.predict.as_deref(), Some("never"));
+
+        let other = config.profile_for("elsewhere.org");
+        assert_eq!(other, config.defaults);
+    }
+
+    #[test]
+    fn test_command_line_overrides_file() {
+        let config = ConfigFile::parse(SAMPLE).unwrap();
+        let cli = Profile {
+            predict: Some("adaptive".to_string()),
+            env: Some([("EDITOR".to_string(), "nano".to_string())].into()),
+            ..Profile::default()
+        };
+        let profile = cli.or(config.profile_for("db.corp.example.com"));
+        assert_eq!(profile.predict.as_deref(), Some("adaptive"));
+        assert_eq!(profile.connect_timeout, Some(10));
+        let env = profile.env.unwrap();
+        assert_eq!(env["EDITOR"], "nano");
+        assert_eq!(env["TZ"], "Europe/Berlin");
+    }
+
+    #[test]
+    fn test_password_sources_replace_each_other() {
+        let config = ConfigFile::parse(
+            "password-file = \"~/.mosh-pass\"\n[host.\"ci-*\"]\npassword-env = \"CI_SSH_PASS\"\n",
+        )
+        .unwrap();
+        let ci = config.profile_for("ci-runner");
+        assert_eq!(ci.password_file, None);
+        assert_eq!(ci.password_env.as_deref(), Some("CI_SSH_PASS"));
+        let other = config.profile_for("laptop");
+        assert!(other.password_file.unwrap().ends_with(".mosh-pass"));
+    }
+
+    #[test]
+    fn test_invalid_settings_are_rejected() {
+        assert!(ConfigFile::parse("predcit = \"always\"").is_err());
+        assert!(ConfigFile::parse("predict = \"sometimes\"").is_err());
+        assert!(ConfigFile::parse("port = \"60010:60001\"").is_err());
+        assert!(ConfigFile::parse("ssh-port = \"22\"").is_err());
+        assert!(ConfigFile::parse("host = 1").is_err());
+        let err = ConfigFile::parse("[host.\"a*\"]\nstrict-host-key-checking = \"maybe\"")
+            .unwrap_err();
+        assert!(format!("{:#}", err).contains("host.\"a*\""), "{:#}", err);
+    }
+
+    #[test]
+    fn test_round_trips_through_toml() {
+        let config = ConfigFile::parse(SAMPLE).unwrap();
+        let profile = config.profile_for("db.corp.example.com");
+        let text = toml::to_string(&profile).unwrap();
+        let reparsed = ConfigFile::parse(&text).unwrap();
+        assert_eq!(reparsed.defaults, profile);
+    }
+
+    #[test]
+    fn test_parse_command_key() {
+        assert_eq!(parse_command_key("ctrl-^").unwrap(), 0x1E);
+        assert_eq!(parse_command_key("^]").unwrap(), 0x1D);
+        assert_eq!(parse_command_key("Ctrl-A").unwrap(), 0x01);
+        assert!(parse_command_key("ctrl-1").is_err());
+        assert!(parse_command_key("x").is_err());
+    }
+
+    #[test]
+    fn test_missing_explicit_file_is_an_error() {
+        let missing = std::env::temp_dir().join("mosh-client-no-such-config.toml");
+        assert!(ConfigFile::load(Some(&missing)).is_err());
+    }
+}
@@ -10,6 +10,8 @@ use aead::{Aead, KeyInit};
 use aes::Aes128;
 use anyhow::{bail, Context, Result};
 use ocb3::Ocb3;
+use std::fmt;
+use zeroize::Zeroizing;
 
 /// Nonce length for OCB3 (12 bytes).
 const NONCE_LEN: usize = 12;
@@ -42,9 +44,17 @@ impl Direction {
 }
 
 /// A 16-byte AES key parsed from Mosh's 22-character base64 format.
-#[derive(Clone)]
+///
+/// The key bytes are wiped when the value is dropped, and `Debug` never
+/// prints them.
 pub struct Base64Key {
-    key: [u8; 16],
+    key: Zeroizing<[u8; 16]>,
+}
+
+impl fmt::Debug for Base64Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Base64Key(<redacted>)")
+    }
 }
 
 impl Base64Key {
@@ -54,21 +64,37 @@ impl Base64Key {
             bail!("Mosh key must be exactly 22 base64 characters, got {}", s.len());
         }
         // Mosh keys are 22 chars of base64 with implicit "==" padding
-        let padded = format!("{}==", s);
-        let decoded = base64::Engine::decode(
-            &base64::engine::general_purpose::STANDARD,
-            &padded,
-        )
-        .context("Failed to decode base64 key")?;
+        let padded = Zeroizing::new(format!("{}==", s));
+        let decoded = Zeroizing::new(
+            base64::Engine::decode(&base64::engine::general_purpose::STANDARD, padded.as_str())
+                .context("Failed to decode base64 key")?,
+        );
 
         if decoded.len() != 16 {
             bail!("Decoded key must be 16 bytes, got {}", decoded.len());
         }
-        let mut key = [0u8; 16];
+        let mut key = Zeroizing::new([0u8; 16]);
         key.copy_from_slice(&decoded);
         Ok(Self { key })
     }
 
+    /// A fresh random key, as mosh-server makes for each session.
+    #[cfg_attr(not(unix), allow(dead_code))]
+    pub fn generate() -> Self {
+        let mut key = Zeroizing::new([0u8; 16]);
+        rand::RngCore::fill_bytes(&mut rand::rngs::OsRng, key.as_mut());
+        Self { key }
+    }
+
+    /// The 22-character form printed in `MOSH CONNECT`.
+    #[cfg_attr(not(unix), allow(dead_code))]
+    pub fn to_base64(&self) -> Zeroizing<String> {
+        Zeroizing::new(base64::Engine::encode(
+            &base64::engine::general_purpose::STANDARD_NO_PAD,
+            self.key.as_slice(),
+        ))
+    }
+
     /// Get the raw 16-byte key.
     pub fn as_bytes(&self) -> &[u8; 16] {
         &self.key
@@ -201,10 +227,20 @@ mod tests {
         assert_ne!(wire_server, wire_client);
     }
 
+    #[test]
+    fn test_generated_key_roundtrip() {
+        let key = Base64Key::generate();
+        let text = key.to_base64();
+        assert_eq!(text.len(), 22);
+        let parsed = Base64Key::from_str(&text).unwrap();
+        assert_eq!(parsed.as_bytes(), key.as_bytes());
+        assert_ne!(Base64Key::generate().as_bytes(), key.as_bytes());
+    }
+
     #[test]
     fn test_encrypt_decrypt_roundtrip() {
         // Create a test key (16 bytes of zeros, base64-encoded is "AAAAAAAAAAAAAAAAAAAAAA")
-        let key = Base64Key { key: [0u8; 16] };
+        let key = Base64Key { key: Zeroizing::new([0u8; 16]) };
         let session = Session::new(&key).unwrap();
 
         let plaintext = b"Hello, Mosh!";
@@ -224,9 +260,17 @@ mod tests {
         assert_eq!(key.as_bytes(), &[0u8; 16]);
     }
 
+    #[test]
+    fn test_base64_key_debug_is_redacted() {
+        let key = Base64Key::from_str("AbCdEfGhIjKlMnOpQrStUg").unwrap();
+        let shown = format!("{:?}", key);
+        assert_eq!(shown, "Base64Key(<redacted>)");
+        assert!(!shown.contains("AbCd"));
+    }
+
     #[test]
     fn test_tampered_datagram_fails() {
-        let key = Base64Key { key: [0u8; 16] };
+        let key = Base64Key { key: Zeroizing::new([0u8; 16]) };
         let session = Session::new(&key).unwrap();
 
         let nonce = make_nonce(Direction::ToServer, 1);
@@ -0,0 +1,507 @@
+//! Server-side display: framebuffers as escape sequences for the client.
+//!
+//! A mosh host diff carries VT bytes that the client runs through its own
+//! terminal on top of the state the diff was made from. `new_frame` emits
+//! the few bytes that turn one framebuffer into another, like mosh-server's
+//! `Display::new_frame`; `repaint` draws a whole frame, and is correct on
+//! top of any earlier screen.
+//!
+//! Both leave the client's terminal with default modes and rendition and a
+//! full-screen scroll region, which is what `new_frame` relies on.
+
+use crate::terminal::{Attributes, Cell, Color, Framebuffer};
+use std::fmt::Write as _;
+
+/// Whether two framebuffers look the same: size, cell contents and style,
+/// cursor and title. Dirty flags and drawing state are ignored.
+#[cfg(any(unix, test))]
+pub fn same_frame(a: &Framebuffer, b: &Framebuffer) -> bool {
+    a.width == b.width
+        && a.height == b.height
+        && a.cursor_row == b.cursor_row
+        && a.cursor_col == b.cursor_col
+        && a.cursor_visible == b.cursor_visible
+        && a.title == b.title
+        && a.cells.iter().zip(&b.cells).all(|(ra, rb)| {
+            ra.iter().zip(rb).all(|(x, y)| same_cell(x, y))
+        })
+}
+
+fn same_cell(a: &Cell, b: &Cell) -> bool {
+    a.character == b.character && same_style(a, b)
+}
+
+fn same_style(a: &Cell, b: &Cell) -> bool {
+    a.fg == b.fg && a.bg == b.bg && a.attrs == b.attrs
+}
+
+/// Whether a cell is what an erase with default colours leaves behind.
+fn is_blank(cell: &Cell) -> bool {
+    same_cell(cell, &Cell::default())
+}
+
+/// Redraw all of `fb` on a terminal of the same size, whatever it showed before.
+pub fn repaint(fb: &Framebuffer) -> Vec<u8> {
+    let mut out = String::new();
+    // Undo modes an earlier diff's bytes could have left that change where
+    // and how text lands: scroll region, origin and insert modes, autowrap.
+    out.push_str("\x1b[r\x1b[?6l\x1b[4l\x1b[?7h\x1b[0m\x1b[H\x1b[2J");
+
+    let mut pen = Cell::default();
+    for (r, row) in fb.cells.iter().enumerate() {
+        let Some(last) = row.iter().rposition(|c| !is_blank(c)) else {
+            continue;
+        };
+        let _ = write!(out, "\x1b[{}H", r + 1);
+        for cell in &row[..=last] {
+            if !same_style(cell, &pen) {
+                out.push_str(&sgr(cell));
+                pen = cell.clone();
+            }
+            out.push(cell.character);
+        }
+    }
+
+    if !same_style(&pen, &Cell::default()) {
+        out.push_str("\x1b[0m");
+    }
+    // An empty title has to be sent too, to clear an earlier one.
+    let _ = write!(out, "\x1b]0;{}\x07", fb.title);
+    let _ = write!(out, "\x1b[{};{}H", fb.cursor_row + 1, fb.cursor_col + 1);
+    out.push_str(if fb.cursor_visible { "\x1b[?25h" } else { "\x1b[?25l" });
+    out.into_bytes()
+}
+
+/// The bytes that turn a terminal showing `old` into one showing `new`.
+///
+/// The terminal must be in the state these diffs leave it in (see the module
+/// docs), already resized to `new`'s size if that changed. Falls back to a
+/// `repaint` when that is shorter.
+#[cfg(any(unix, test))]
+pub fn new_frame(old: &Framebuffer, new: &Framebuffer) -> Vec<u8> {
+    let mut base = old.clone();
+    if (base.width, base.height) != (new.width, new.height) {
+        base.resize(new.width, new.height);
+    }
+    let mut draw = Drawer {
+        out: String::new(),
+        pen: Cell::default(),
+        cursor: Some((base.cursor_row, base.cursor_col)),
+        width: new.width,
+    };
+
+    if base.title != new.title {
+        let _ = write!(draw.out, "\x1b]0;{}\x07", new.title);
+    }
+    draw.scroll(&mut base, new);
+    draw.erase_below(&mut base, new);
+    for (r, (old_row, new_row)) in base.cells.iter().zip(&new.cells).enumerate() {
+        draw.row(r, old_row, new_row);
+    }
+
+    if !same_style(&draw.pen, &Cell::default()) {
+        draw.out.push_str("\x1b[0m");
+    }
+    draw.move_to(new.cursor_row, new.cursor_col, None);
+    if base.cursor_visible != new.cursor_visible {
+        draw.out.push_str(if new.cursor_visible { "\x1b[?25h" } else { "\x1b[?25l" });
+    }
+
+    let full = repaint(new);
+    if full.len() < draw.out.len() {
+        full
+    } else {
+        draw.out.into_bytes()
+    }
+}
+
+/// Whether a cell is what an erase leaves behind with `bg` as background.
+#[cfg(any(unix, test))]
+fn is_erased(cell: &Cell, bg: Color) -> bool {
+    same_cell(cell, &Cell { bg, ..Cell::default() })
+}
+
+/// Output under construction, and what it has done to the terminal so far.
+#[cfg(any(unix, test))]
+struct Drawer {
+    out: String,
+    /// Current rendition (only the style fields matter).
+    pen: Cell,
+    /// `None` after writing the last column, when the terminal's next
+    /// character would wrap and only an absolute move is safe.
+    cursor: Option<(usize, usize)>,
+    width: usize,
+}
+
+#[cfg(any(unix, test))]
+impl Drawer {
+    /// Scroll the whole screen up if more rows line up with `new` that way.
+    fn scroll(&mut self, base: &mut Framebuffer, new: &Framebuffer) {
+        let height = new.height;
+        let blank = vec![Cell::default(); new.width];
+        let same_row = |a: &[Cell], b: &[Cell]| a.iter().zip(b).all(|(x, y)| same_cell(x, y));
+        // Rows that already show what they should, after scrolling `lines`.
+        let matching = |lines: usize| {
+            (0..height)
+                .filter(|&r| same_row(base.cells.get(r + lines).unwrap_or(&blank), &new.cells[r]))
+                .count()
+        };
+        let in_place = matching(0);
+        let Some((lines, best)) = (1..height)
+            .map(|lines| (lines, matching(lines)))
+            .max_by_key(|&(lines, n)| (n, std::cmp::Reverse(lines)))
+        else {
+            return;
+        };
+        if best <= in_place {
+            return;
+        }
+        if lines == 1 {
+            self.out.push_str("\x1b[S");
+        } else {
+            let _ = write!(self.out, "\x1b[{}S", lines);
+        }
+        base.cells.drain(..lines);
+        base.cells.extend(std::iter::repeat_n(blank, lines));
+    }
+
+    /// Clear from the first of `new`'s trailing blank rows down, when that
+    /// wipes out at least two rows of old text.
+    fn erase_below(&mut self, base: &mut Framebuffer, new: &Framebuffer) {
+        let is_blank_row = |row: &[Cell]| row.iter().all(is_blank);
+        let first = new
+            .cells
+            .iter()
+            .rposition(|row| !is_blank_row(row))
+            .map_or(0, |r| r + 1);
+        let stale = base.cells[first..].iter().filter(|row| !is_blank_row(row)).count();
+        if stale < 2 {
+            return;
+        }
+        self.move_to(first, 0, None);
+        self.set_style(&Cell::default());
+        self.out.push_str("\x1b[J");
+        for row in &mut base.cells[first..] {
+            row.fill(Cell::default());
+        }
+    }
+
+    /// Bring row `r` from `old` to `new`, erasing a uniform tail with EL.
+    fn row(&mut self, r: usize, old: &[Cell], new: &[Cell]) {
+        let Some(last_change) = (0..new.len()).rev().find(|&c| !same_cell(&old[c], &new[c])) else {
+            return;
+        };
+        let tail_bg = new[new.len() - 1].bg;
+        let tail = (0..new.len())
+            .rev()
+            .take_while(|&c| is_erased(&new[c], tail_bg))
+            .last();
+        // Only worth it when the tail still holds a change after its first cell.
+        let erase_from = tail.filter(|&t| t < last_change);
+
+        let end = erase_from.unwrap_or(last_change + 1);
+        for c in 0..end {
+            if !same_cell(&old[c], &new[c]) {
+                self.move_to(r, c, Some(new));
+                self.set_style(&new[c]);
+                self.out.push(new[c].character);
+                self.cursor = (c + 1 < self.width).then_some((r, c + 1));
+            }
+        }
+        if let Some(t) = erase_from {
+            self.move_to(r, t, Some(new));
+            if self.pen.bg != tail_bg {
+                self.set_style(&Cell { bg: tail_bg, ..Cell::default() });
+            }
+            self.out.push_str("\x1b[K");
+        }
+    }
+
+    /// Move the cursor the cheapest way. With the row's new contents, a
+    /// short hop forward may reprint the cells in between instead.
+    fn move_to(&mut self, row: usize, col: usize, line: Option<&[Cell]>) {
+        if self.cursor == Some((row, col)) {
+            return;
+        }
+        let mut best = if col == 0 {
+            format!("\x1b[{}H", row + 1)
+        } else {
+            format!("\x1b[{};{}H", row + 1, col + 1)
+        };
+        if let Some((_, c)) = self.cursor.filter(|&(r, _)| r == row) {
+            let relative = if col == 0 {
+                "\r".to_string()
+            } else if col + 1 == c {
+                "\x08".to_string()
+            } else if col < c {
+                format!("\x1b[{}D", c - col)
+            } else {
+                match line {
+                    Some(cells) if cells[c..col].iter().all(|x| same_style(x, &self.pen)) => {
+                        cells[c..col].iter().map(|x| x.character).collect()
+                    }
+                    _ => format!("\x1b[{}C", col - c),
+                }
+            };
+            if relative.len() < best.len() {
+                best = relative;
+            }
+        }
+        self.out.push_str(&best);
+        self.cursor = Some((row, col));
+    }
+
+    fn set_style(&mut self, cell: &Cell) {
+        if same_style(cell, &self.pen) {
+            return;
+        }
+        let full = sgr(cell);
+        let change = sgr_change(&self.pen, cell);
+        self.out.push_str(if change.len() < full.len() { &change } else { &full });
+        self.pen = Cell { character: ' ', dirty: false, ..cell.clone() };
+    }
+}
+
+/// The SGR sequence taking the rendition from `from` to `to` without a reset.
+#[cfg(any(unix, test))]
+fn sgr_change(from: &Cell, to: &Cell) -> String {
+    let mut params = Vec::new();
+    let (a, b) = (from.attrs, to.attrs);
+    for (was, is, on, off) in [
+        (a.bold, b.bold, "1", "22"),
+        (a.italic, b.italic, "3", "23"),
+        (a.underline, b.underline, "4", "24"),
+        (a.blink, b.blink, "5", "25"),
+        (a.inverse, b.inverse, "7", "27"),
+        (a.invisible, b.invisible, "8", "28"),
+        (a.strikethrough, b.strikethrough, "9", "29"),
+    ] {
+        if was != is {
+            params.push((if is { on } else { off }).to_string());
+        }
+    }
+    if from.fg != to.fg {
+        match to.fg {
+            Color::Default => params.push("39".to_string()),
+            color => push_color(&mut params, color, 38),
+        }
+    }
+    if from.bg != to.bg {
+        match to.bg {
+            Color::Default => params.push("49".to_string()),
+            color => push_color(&mut params, color, 48),
+        }
+    }
+    format!("\x1b[{}m", params.join(";"))
+}
+
+/// The SGR sequence selecting `cell`'s style from scratch.
+fn sgr(cell: &Cell) -> String {
+    let mut params = vec!["0".to_string()];
+    let Attributes {
+        bold,
+        italic,
+        underline,
+        blink,
+        inverse,
+        invisible,
+        strikethrough,
+    } = cell.attrs;
+    for (on, code) in [
+        (bold, "1"),
+        (italic, "3"),
+        (underline, "4"),
+        (blink, "5"),
+        (inverse, "7"),
+        (invisible, "8"),
+        (strikethrough, "9"),
+    ] {
+        if on {
+            params.push(code.to_string());
+        }
+    }
+    push_color(&mut params, cell.fg, 38);
+    push_color(&mut params, cell.bg, 48);
+    format!("\x1b[{}m", params.join(";"))
+}
+
+fn push_color(params: &mut Vec<String>, color: Color, base: u8) {
+    match color {
+        Color::Default => {}
+        Color::Indexed(n) if n < 8 => params.push(format!("{}", base - 8 + n)),
+        Color::Indexed(n) if n < 16 => params.push(format!("{}", base + 52 + n - 8)),
+        Color::Indexed(n) => params.push(format!("{};5;{}", base, n)),
+        Color::Rgb(r, g, b) => params.push(format!("{};2;{};{};{}", base, r, g, b)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::terminal::Terminal;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn screen(width: usize, height: usize, bytes: &[u8]) -> Terminal {
+        let mut term = Terminal::new(width, height);
+        term.process(bytes);
+        term
+    }
+
+    #[test]
+    fn test_repaint_reproduces_frame() {
+        let source = screen(
+            20,
+            5,
+            b"plain \x1b[1;31mbold red\x1b[0m\r\n\x1b[44m blue bg \x1b[0m\r\n\
+              \x1b[38;2;1;2;3;4mrgb ul\x1b[0m\x1b[5;20Hx\x1b]0;title\x07\x1b[3;7H",
+        );
+
+        // On top of a blank screen and on top of clutter with odd modes set.
+        for before in [
+            screen(20, 5, b""),
+            screen(20, 5, b"junk everywhere\x1b[2;4r\x1b[?6h\x1b[4h\x1b[?7l\x1b[7mmore\x1b[?25l"),
+        ] {
+            let mut replica = before;
+            replica.process(&repaint(&source.fb));
+            assert!(same_frame(&replica.fb, &source.fb), "{:?}", replica);
+        }
+    }
+
+    #[test]
+    fn test_repaint_full_last_row() {
+        // Writing the bottom-right cell mustn't scroll the screen.
+        let mut bytes = Vec::new();
+        for r in 0..3 {
+            bytes.extend_from_slice(format!("\x1b[{}H", r + 1).as_bytes());
+            bytes.extend_from_slice(b"abcd");
+        }
+        let source = screen(4, 3, &bytes);
+        let mut replica = screen(4, 3, b"");
+        replica.process(&repaint(&source.fb));
+        assert!(same_frame(&replica.fb, &source.fb));
+    }
+
+    #[test]
+    fn test_same_frame_ignores_drawing_state() {
+        let a = screen(10, 2, b"hi");
+        let b = screen(10, 2, b"h\x1b[1mX\x1b[0m\x08i\x1b[31m");
+        assert!(!same_frame(&a.fb, &screen(10, 2, b"ho").fb));
+        let mut b_fb = b.fb.clone();
+        b_fb.clear_dirty();
+        assert!(same_frame(&a.fb, &b_fb));
+    }
+
+    #[test]
+    fn test_new_frame_typing_is_one_byte() {
+        let old = screen(80, 24, b"$ ");
+        let new = screen(80, 24, b"$ l");
+        assert_eq!(new_frame(&old.fb, &new.fb), b"l");
+        assert!(new_frame(&new.fb, &new.fb).is_empty());
+    }
+
+    #[test]
+    fn test_new_frame_scrolls() {
+        let mut host = Terminal::new(40, 10);
+        for i in 0..10 {
+            host.process(format!("line {}\r\n", i).as_bytes());
+        }
+        let old = host.fb.clone();
+        host.process(b"line 10\r\n");
+        let diff = new_frame(&old, &host.fb);
+        assert!(diff.starts_with(b"\x1b[S"), "{:?}", String::from_utf8_lossy(&diff));
+        assert!(diff.len() < 20, "{:?}", String::from_utf8_lossy(&diff));
+
+        let mut client = screen(40, 10, b"");
+        client.process(&repaint(&old));
+        client.process(&diff);
+        assert!(same_frame(&client.fb, &host.fb));
+    }
+
+    #[test]
+    fn test_new_frame_style_changes_are_incremental() {
+        let old = screen(20, 2, b"");
+        let new = screen(20, 2, b"\x1b[1;4;31mab\x1b[32mc\x1b[22md");
+        assert_eq!(
+            String::from_utf8(new_frame(&old.fb, &new.fb)).unwrap(),
+            "\x1b[1;4;31mab\x1b[32mc\x1b[22md\x1b[0m"
+        );
+    }
+
+    /// Host output exercising whatever the emulator can do to a screen.
+    fn random_output(rng: &mut StdRng, width: usize, height: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        for _ in 0..rng.gen_range(1..12) {
+            let seq = match rng.gen_range(0..18) {
+                0..=4 => {
+                    let len = rng.gen_range(1..width * 2);
+                    (0..len)
+                        .map(|_| match rng.gen_range(0..20) {
+                            0 => 'é',
+                            1 => '─',
+                            _ => rng.gen_range(b' '..=b'~') as char,
+                        })
+                        .collect()
+                }
+                5 => "\r\n".repeat(rng.gen_range(1..height + 2)),
+                6 => format!("\x1b[{};{}H", rng.gen_range(1..=height), rng.gen_range(1..=width)),
+                7 => {
+                    let codes = ["0", "1", "3", "4", "7", "9", "22", "24", "27", "31", "44", "39", "49", "92",
+                        "103", "38;5;200", "48;5;17", "38;2;10;20;30", "48;2;1;2;3"];
+                    format!("\x1b[{}m", codes[rng.gen_range(0..codes.len())])
+                }
+                8 => format!("\x1b[{}K", rng.gen_range(0..3)),
+                9 => format!("\x1b[{}J", rng.gen_range(0..3)),
+                10 => format!("\x1b[{}S", rng.gen_range(1..4)),
+                11 => format!("\x1b[{}T", rng.gen_range(1..4)),
+                12 => format!("\x1b[{}{}", rng.gen_range(1..4), ['L', 'M', '@', 'P', 'X'][rng.gen_range(0..5)]),
+                13 => format!("\x1b]0;title {}\x07", rng.gen_range(0..3)),
+                14 => ["\x1b[?25l", "\x1b[?25h"][rng.gen_range(0..2)].to_string(),
+                15 => {
+                    let top = rng.gen_range(1..=height);
+                    format!("\x1b[{};{}r", top, rng.gen_range(top..=height))
+                }
+                16 => ["\x1b[4h", "\x1b[4l", "\x1b[?7l", "\x1b[?7h", "\x1b[r"][rng.gen_range(0..5)].to_string(),
+                _ => "\x08\t\x1b[2A\x1b[3C".to_string(),
+            };
+            out.extend_from_slice(seq.as_bytes());
+        }
+        out
+    }
+
+    #[test]
+    fn test_new_frame_round_trips() {
+        for seed in 0..300u64 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let (mut width, mut height) = (rng.gen_range(1..30), rng.gen_range(1..12));
+            let mut host = Terminal::new(width, height);
+            let mut client = Terminal::new(width, height);
+            for step in 0..20 {
+                let old = host.fb.clone();
+                if rng.gen_ratio(1, 10) {
+                    width = rng.gen_range(1..30);
+                    height = rng.gen_range(1..12);
+                    host.resize(width, height);
+                }
+                let output = random_output(&mut rng, width, height);
+                host.process(&output);
+
+                // The client applies each diff to a copy with a fresh parser.
+                let diff = new_frame(&old, &host.fb);
+                client = client.clone();
+                if (client.fb.width, client.fb.height) != (width, height) {
+                    client.resize(width, height);
+                }
+                client.process(&diff);
+                assert!(
+                    same_frame(&client.fb, &host.fb),
+                    "seed {} step {}: {:?} then {:?}",
+                    seed,
+                    step,
+                    String::from_utf8_lossy(&output),
+                    String::from_utf8_lossy(&diff)
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,32 @@
+//! JSON lines written as the session runs, for `--record` and `--asciicast`.
+//!
+//! Each value goes out as one line the moment it is written, so a file cut
+//! short by a crash is still readable up to its last line.
+
+use serde::Serialize;
+use std::io::Write as _;
+
+/// A file of JSON lines. The first write error is logged and stops the
+/// file; errors never end the session.
+pub struct JsonLines {
+    file: Option<std::fs::File>,
+    /// What the file is, for the error message: "recording", "asciicast".
+    what: &'static str,
+}
+
+impl JsonLines {
+    pub fn new(file: std::fs::File, what: &'static str) -> Self {
+        Self { file: Some(file), what }
+    }
+
+    /// Append `value` as one line.
+    pub fn write(&mut self, value: &impl Serialize) {
+        let Some(file) = self.file.as_mut() else { return };
+        let mut line = serde_json::to_string(value).unwrap_or_default();
+        line.push('\n');
+        if let Err(e) = file.write_all(line.as_bytes()) {
+            log::warn!("Failed to write {}, stopping it: {}", self.what, e);
+            self.file = None;
+        }
+    }
+}
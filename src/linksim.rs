@@ -0,0 +1,363 @@
+//! Deterministic in-process network for transport tests.
+//!
+//! `sim_pair` joins two `SimEndpoint`s with a `LossyLink` in each direction.
+//! A link decides the fate of every datagram from a seeded RNG — drop,
+//! duplicate, delay, hold back for reordering, queue behind a bandwidth cap —
+//! and releases it once tokio's clock reaches its delivery time. Tests run on
+//! a paused clock (`#[tokio::test(start_paused = true)]`) and step it with
+//! `tokio::time::advance`, so a given seed always plays out the same way.
+//!
+//! `FakeServer` is the far end: a `ServerTransport` on the simulated link
+//! with no host behind it, so it only acknowledges what the client sends.
+
+use crate::crypto::Base64Key;
+use crate::server::ServerTransport;
+use crate::transport::proto::transportinstruction::Instruction;
+use crate::userstream::UserStream;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+// ── Lossy link ─────────────────────────────────────────────────────────────
+
+/// What a link does to the datagrams crossing it.
+#[derive(Debug, Clone, Default)]
+pub struct Impairment {
+    /// Probability that a datagram is dropped.
+    pub loss: f64,
+    /// Probability that a datagram is delivered twice.
+    pub duplicate: f64,
+    /// Probability that a datagram is held back by an extra `delay` (at
+    /// least 10 ms), letting later ones overtake it.
+    pub reorder: f64,
+    /// One-way propagation delay.
+    pub delay: Duration,
+    /// Extra delay drawn uniformly from `0..=jitter` per datagram.
+    pub jitter: Duration,
+    /// Bytes per second the link carries; datagrams queue behind each other.
+    pub bandwidth: Option<u64>,
+}
+
+/// Counters for one direction of a simulated network.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LinkStats {
+    pub sent: u64,
+    pub dropped: u64,
+    pub duplicated: u64,
+    pub delivered: u64,
+}
+
+/// A datagram on its way.
+struct InFlight {
+    deliver_at: Instant,
+    /// Send order, to break ties between equal delivery times.
+    seq: u64,
+    datagram: Vec<u8>,
+}
+
+/// One direction of a simulated network.
+pub struct LossyLink {
+    impairment: Impairment,
+    rng: StdRng,
+    in_flight: Vec<InFlight>,
+    /// When the bandwidth cap frees up for the next datagram.
+    busy_until: Instant,
+    next_seq: u64,
+    stats: LinkStats,
+}
+
+impl LossyLink {
+    pub fn new(impairment: Impairment, seed: u64) -> Self {
+        Self {
+            impairment,
+            rng: StdRng::seed_from_u64(seed),
+            in_flight: Vec::new(),
+            busy_until: Instant::now(),
+            next_seq: 0,
+            stats: LinkStats::default(),
+        }
+    }
+
+    pub fn set_impairment(&mut self, impairment: Impairment) {
+        self.impairment = impairment;
+    }
+
+    pub fn stats(&self) -> LinkStats {
+        self.stats
+    }
+
+    /// Put a datagram on the link at `now`.
+    pub fn push(&mut self, now: Instant, datagram: &[u8]) {
+        self.stats.sent += 1;
+
+        // The datagram occupies the link even if it's lost further along.
+        let mut departs = now;
+        if let Some(bandwidth) = self.impairment.bandwidth {
+            let start = self.busy_until.max(now);
+            let wire_time = Duration::from_secs_f64(datagram.len() as f64 / bandwidth as f64);
+            self.busy_until = start + wire_time;
+            departs = self.busy_until;
+        }
+
+        if self.rng.gen_bool(self.impairment.loss.clamp(0.0, 1.0)) {
+            self.stats.dropped += 1;
+            return;
+        }
+        let copies = if self.rng.gen_bool(self.impairment.duplicate.clamp(0.0, 1.0)) {
+            self.stats.duplicated += 1;
+            2
+        } else {
+            1
+        };
+        for _ in 0..copies {
+            let deliver_at = departs + self.latency();
+            self.in_flight.push(InFlight {
+                deliver_at,
+                seq: self.next_seq,
+                datagram: datagram.to_vec(),
+            });
+            self.next_seq += 1;
+        }
+    }
+
+    fn latency(&mut self) -> Duration {
+        let imp = &self.impairment;
+        let mut latency = imp.delay;
+        if !imp.jitter.is_zero() {
+            latency += imp.jitter.mul_f64(self.rng.gen_range(0.0..=1.0));
+        }
+        if self.rng.gen_bool(imp.reorder.clamp(0.0, 1.0)) {
+            latency += imp.delay.max(Duration::from_millis(10));
+        }
+        latency
+    }
+
+    /// Take the earliest datagram due by `now`.
+    pub fn pop_due(&mut self, now: Instant) -> Option<Vec<u8>> {
+        let idx = self
+            .in_flight
+            .iter()
+            .enumerate()
+            .filter(|(_, f)| f.deliver_at <= now)
+            .min_by_key(|(_, f)| (f.deliver_at, f.seq))
+            .map(|(i, _)| i)?;
+        self.stats.delivered += 1;
+        Some(self.in_flight.swap_remove(idx).datagram)
+    }
+
+    /// When the next datagram arrives, if any is in flight.
+    pub fn next_due(&self) -> Option<Instant> {
+        self.in_flight.iter().map(|f| f.deliver_at).min()
+    }
+}
+
+// ── Endpoints ──────────────────────────────────────────────────────────────
+
+/// Which end of a simulated network.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Client,
+    Server,
+}
+
+impl Side {
+    fn index(self) -> usize {
+        self as usize
+    }
+
+    fn other(self) -> Side {
+        match self {
+            Side::Client => Side::Server,
+            Side::Server => Side::Client,
+        }
+    }
+
+    fn addr(self) -> SocketAddr {
+        match self {
+            Side::Client => SocketAddr::from(([192, 0, 2, 1], 49152)),
+            Side::Server => SocketAddr::from(([192, 0, 2, 2], 60001)),
+        }
+    }
+}
+
+/// Links indexed by the side that sends on them.
+type Links = Arc<Mutex<[LossyLink; 2]>>;
+
+/// One end of a simulated network, standing in for a connected UDP socket.
+#[derive(Clone)]
+pub struct SimEndpoint {
+    links: Links,
+    side: Side,
+}
+
+/// A simulated network: `up` carries client-to-server traffic, `down` the reverse.
+pub fn sim_pair(up: Impairment, down: Impairment, seed: u64) -> (SimEndpoint, SimEndpoint) {
+    let links = Arc::new(Mutex::new([
+        LossyLink::new(up, seed),
+        LossyLink::new(down, seed ^ 0x9e37_79b9_7f4a_7c15),
+    ]));
+    (
+        SimEndpoint { links: links.clone(), side: Side::Client },
+        SimEndpoint { links, side: Side::Server },
+    )
+}
+
+impl SimEndpoint {
+    fn incoming(&self) -> usize {
+        self.side.other().index()
+    }
+
+    pub fn send(&self, datagram: &[u8]) -> std::io::Result<usize> {
+        let mut links = self.links.lock().unwrap();
+        links[self.side.index()].push(Instant::now(), datagram);
+        Ok(datagram.len())
+    }
+
+    pub fn try_recv(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut links = self.links.lock().unwrap();
+        let Some(datagram) = links[self.incoming()].pop_due(Instant::now()) else {
+            return Err(std::io::ErrorKind::WouldBlock.into());
+        };
+        let n = datagram.len().min(buf.len());
+        buf[..n].copy_from_slice(&datagram[..n]);
+        Ok(n)
+    }
+
+    pub async fn readable(&self) -> std::io::Result<()> {
+        loop {
+            let due = self.links.lock().unwrap()[self.incoming()].next_due();
+            match due {
+                Some(at) if at <= Instant::now() => return Ok(()),
+                Some(at) => tokio::time::sleep_until(at).await,
+                None => tokio::time::sleep(Duration::from_millis(1)).await,
+            }
+        }
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.side.addr()
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.side.other().addr()
+    }
+
+    /// Change the impairment of traffic sent by `from`.
+    pub fn set_impairment(&self, from: Side, impairment: Impairment) {
+        self.links.lock().unwrap()[from.index()].set_impairment(impairment);
+    }
+
+    /// Counters for traffic sent by `from`.
+    pub fn stats(&self, from: Side) -> LinkStats {
+        self.links.lock().unwrap()[from.index()].stats()
+    }
+}
+
+// ── Fake server ────────────────────────────────────────────────────────────
+
+/// A mosh server with no host behind it: a `ServerTransport` on the far end
+/// of the link, acknowledging the client's states and never sending output.
+pub struct FakeServer {
+    transport: ServerTransport,
+    endpoint: SimEndpoint,
+}
+
+impl FakeServer {
+    pub fn new(key: &Base64Key, endpoint: SimEndpoint) -> Self {
+        Self {
+            transport: ServerTransport::with_sim_link(key, endpoint.clone(), 80, 24).unwrap(),
+            endpoint,
+        }
+    }
+
+    /// Send a datagram as-is, e.g. one that won't decrypt.
+    pub fn send_raw(&self, datagram: &[u8]) {
+        self.endpoint.send(datagram).unwrap();
+    }
+
+    /// The newest client state number and its contents.
+    pub fn latest(&self) -> (u64, &UserStream) {
+        (self.transport.client_state_num(), self.transport.client_input())
+    }
+
+    /// Every client instruction that arrived intact, in arrival order.
+    pub fn received(&self) -> &[Instruction] {
+        self.transport.received()
+    }
+
+    /// Take delivered datagrams and acknowledge them.
+    pub async fn poll(&mut self) {
+        self.transport.drain_recv().unwrap();
+        self.transport.tick().await.unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deliveries(impairment: Impairment, seed: u64, count: u8) -> Vec<(u8, Duration)> {
+        let start = Instant::now();
+        let mut link = LossyLink::new(impairment, seed);
+        for i in 0..count {
+            link.push(start + Duration::from_millis(i as u64), &[i; 100]);
+        }
+        let mut out = Vec::new();
+        while let Some(now) = link.next_due() {
+            while let Some(d) = link.pop_due(now) {
+                out.push((d[0], now - start));
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn test_clean_link_keeps_order_and_delay() {
+        let imp = Impairment { delay: Duration::from_millis(30), ..Default::default() };
+        let out = deliveries(imp, 1, 10);
+        let expected: Vec<(u8, Duration)> =
+            (0..10).map(|i| (i, Duration::from_millis(30 + i as u64))).collect();
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn test_impairments_are_deterministic() {
+        let imp = Impairment {
+            loss: 0.2,
+            duplicate: 0.1,
+            reorder: 0.2,
+            delay: Duration::from_millis(20),
+            jitter: Duration::from_millis(15),
+            bandwidth: None,
+        };
+        let a = deliveries(imp.clone(), 7, 200);
+        assert_eq!(a, deliveries(imp.clone(), 7, 200));
+        assert_ne!(a, deliveries(imp, 8, 200));
+
+        // Roughly the configured rates, with some arriving out of order.
+        let delivered = a.len();
+        assert!((140..=200).contains(&delivered), "{}", delivered);
+        assert!(a.windows(2).any(|w| w[0].0 > w[1].0));
+    }
+
+    #[test]
+    fn test_bandwidth_cap_spaces_datagrams() {
+        // 100-byte datagrams at 10 kB/s: one every 10 ms.
+        let imp = Impairment { bandwidth: Some(10_000), ..Default::default() };
+        let out = deliveries(imp, 1, 5);
+        let times: Vec<u64> = out.iter().map(|(_, t)| t.as_millis() as u64).collect();
+        assert_eq!(times, vec![10, 20, 30, 40, 50]);
+    }
+
+    #[test]
+    fn test_total_loss_and_duplication() {
+        let lossy = Impairment { loss: 1.0, ..Default::default() };
+        assert!(deliveries(lossy, 1, 20).is_empty());
+
+        let dup = Impairment { duplicate: 1.0, ..Default::default() };
+        assert_eq!(deliveries(dup, 1, 20).len(), 40);
+    }
+}
@@ -1,7 +1,7 @@
 //! Mosh client for Windows — a native Rust implementation.
 //!
 //! Usage:
-//!   mosh-client [OPTIONS] [user@]host [-- mosh-server-args...]
+//!   mosh-client [OPTIONS] [user@]host [-- command [args...]]
 //!
 //! This client:
 //! 1. Bootstraps via SSH to start mosh-server on the remote host
@@ -9,71 +9,283 @@
 //! 3. Renders terminal output natively using the Windows Console API
 //! 4. Provides predictive local echo for low-latency interaction
 
+mod agent;
+mod asciicast;
+mod config;
 mod crypto;
+mod display;
+mod jsonlines;
+#[cfg(test)]
+mod linksim;
 mod network;
 mod prediction;
+#[cfg(unix)]
+mod pty;
+mod recording;
 mod renderer;
+mod schedule;
+#[cfg(test)]
+mod scratch;
+mod server;
 mod ssh;
+mod status;
 mod terminal;
+#[cfg(test)]
+mod testserver;
 mod transport;
 mod userstream;
 
-use anyhow::{Context, Result};
-use clap::Parser;
-use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use anyhow::{bail, Context, Result};
+use clap::{Parser, Subcommand};
+use crossterm::event::{Event, EventStream, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use futures_util::{FutureExt as _, StreamExt as _};
 use prediction::PredictionMode;
-use std::net::SocketAddr;
+use status::{ExitStatus, SessionStats, StatsLog, StatusEvent, StatusReporter};
+use std::io::{IsTerminal, Read};
+use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
+use std::process::ExitCode;
 use std::time::Duration;
-
-const MOSH_COMMAND_KEY: u8 = 0x1E; // Ctrl-^
+use zeroize::Zeroizing;
 
 /// Mosh client for Windows — a native Rust implementation of the Mobile Shell client.
+///
+/// A host named like a subcommand can be reached as user@host, or with any
+/// option in front of it.
 #[derive(Parser, Debug)]
-#[command(name = "mosh-client", version, about)]
+#[command(
+    name = "mosh-client",
+    version,
+    about,
+    propagate_version = true,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true,
+    subcommand_value_name = "SUBCOMMAND",
+    disable_help_subcommand = true
+)]
 struct Cli {
+    #[command(subcommand)]
+    mode: Option<Mode>,
+
     /// Remote host in [user@]host format.
-    #[arg(value_name = "HOST")]
-    host: String,
+    #[arg(value_name = "HOST", required = true)]
+    host: Option<String>,
 
     /// SSH port (default: 22).
-    #[arg(short = 'p', long, default_value = "22")]
-    ssh_port: u16,
+    #[arg(short = 'p', long)]
+    ssh_port: Option<u16>,
 
     /// SSH identity file (private key).
     #[arg(short = 'i', long)]
     identity: Option<PathBuf>,
 
+    /// OpenSSH certificate for the identity file (default: <identity>-cert.pub).
+    #[arg(long, value_name = "FILE")]
+    certificate: Option<PathBuf>,
+
+    /// Host key policy for unknown hosts: yes, accept-new, no, ask (default: ask).
+    #[arg(long, value_name = "MODE")]
+    strict_host_key_checking: Option<ssh::StrictHostKeyChecking>,
+
+    /// Known hosts file to read and update (default: ~/.ssh/known_hosts).
+    #[arg(long, value_name = "FILE")]
+    known_hosts: Option<PathBuf>,
+
+    /// System-wide known hosts file, consulted read-only.
+    #[arg(long, value_name = "FILE")]
+    global_known_hosts: Option<PathBuf>,
+
+    /// Hash host names when adding them to known_hosts.
+    #[arg(long, overrides_with = "no_hash_known_hosts")]
+    hash_known_hosts: bool,
+
+    /// Record host names in the clear, even if the config file says to hash them.
+    #[arg(long, overrides_with = "hash_known_hosts")]
+    no_hash_known_hosts: bool,
+
     /// SSH password (if not using key-based auth).
-    /// WARNING: Visible in process list. Prefer key-based auth.
-    #[arg(long)]
+    /// WARNING: Visible in process list. Prefer --password-file or --password-env.
+    #[arg(long, conflicts_with_all = ["password_file", "password_env"])]
     password: Option<String>,
 
-    /// Path to mosh-server on the remote host.
-    #[arg(long, default_value = "mosh-server")]
-    server: String,
+    /// Read the SSH password from the first line of FILE.
+    #[arg(long, value_name = "FILE", conflicts_with = "password_env")]
+    password_file: Option<PathBuf>,
 
-    /// Prediction mode: always, adaptive, never.
-    #[arg(long, default_value = "adaptive")]
-    predict: String,
+    /// Read the SSH password from environment variable VAR (removed after reading).
+    #[arg(long, value_name = "VAR")]
+    password_env: Option<String>,
 
-    /// Connect directly to a running mosh-server (skip SSH bootstrap).
-    /// Format: IP:PORT with MOSH_KEY environment variable set.
+    /// Path to mosh-server on the remote host (default: mosh-server).
     #[arg(long)]
+    server: Option<String>,
+
+    /// UDP port or range for mosh-server, e.g. 60001 or 60001:60010.
+    #[arg(long, value_name = "PORT[:PORT2]", value_parser = parse_port_spec)]
+    port: Option<String>,
+
+    /// Static mosh-server binary to upload if the remote host has none.
+    #[arg(long, value_name = "FILE")]
+    upload_server: Option<PathBuf>,
+
+    /// Seconds to wait for the SSH connection and handshake (default: 30).
+    #[arg(long, value_name = "SECS")]
+    connect_timeout: Option<u64>,
+
+    /// Seconds to wait for SSH authentication, excluding time at prompts (default: 60).
+    #[arg(long, value_name = "SECS")]
+    auth_timeout: Option<u64>,
+
+    /// Seconds to wait for mosh-server to start (default: 30).
+    #[arg(long, value_name = "SECS")]
+    server_timeout: Option<u64>,
+
+    /// Prediction mode: always, adaptive, never (default: adaptive).
+    #[arg(long, value_parser = ["always", "adaptive", "never"])]
+    predict: Option<String>,
+
+    /// Key that starts in-session commands, e.g. ctrl-^ (default) or ctrl-].
+    #[arg(long, value_name = "KEY", value_parser = parse_command_key_spec)]
+    command_key: Option<String>,
+
+    /// Connect directly to a running mosh-server (skip SSH bootstrap).
+    /// Format: IP[:PORT]. The key comes from --key-file, --key-fd, MOSH_KEY,
+    /// or a "MOSH CONNECT <port> <key>" line piped on stdin.
+    #[arg(long, value_name = "IP[:PORT]")]
     direct: Option<String>,
 
-    /// Extra arguments to pass to mosh-server (after --).
-    #[arg(last = true)]
+    /// Read the --direct session key (or a MOSH CONNECT line) from FILE.
+    #[arg(long, value_name = "FILE", requires = "direct", conflicts_with = "key_fd")]
+    key_file: Option<PathBuf>,
+
+    /// Read the --direct session key (or a MOSH CONNECT line) from an inherited
+    /// file descriptor (a handle value on Windows), which is closed afterwards.
+    #[arg(long, value_name = "N", requires = "direct")]
+    key_fd: Option<i64>,
+
+    /// Set an environment variable for the remote shell (repeatable).
+    /// Local LANG/LC_* variables are forwarded automatically.
+    #[arg(long = "env", value_name = "NAME=VALUE", value_parser = parse_env_var)]
+    env: Vec<(String, String)>,
+
+    /// Request a PTY for the SSH session that starts mosh-server.
+    #[arg(long, overrides_with = "no_pty")]
+    pty: bool,
+
+    /// Don't request a PTY, even if the config file says to.
+    #[arg(long, overrides_with = "pty")]
+    no_pty: bool,
+
+    /// Forward the local SSH agent while mosh-server starts.
+    #[arg(short = 'A', long, overrides_with = "no_forward_agent")]
+    forward_agent: bool,
+
+    /// Don't forward the SSH agent, even if the config file says to.
+    #[arg(long, overrides_with = "forward_agent")]
+    no_forward_agent: bool,
+
+    /// Extra option for mosh-server, added after the defaults (repeatable).
+    #[arg(long = "server-arg", value_name = "ARG", allow_hyphen_values = true)]
     server_args: Vec<String>,
 
+    /// Command to run instead of the login shell (after --).
+    #[arg(last = true, value_name = "COMMAND")]
+    command: Vec<String>,
+
+    /// Config file (default: ~/.config/mosh-client/config.toml, or
+    /// %APPDATA%\mosh-client\config.toml on Windows).
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Print the effective settings for HOST as TOML and exit.
+    #[arg(long)]
+    print_config: bool,
+
+    /// Write connection progress and the exit reason to stderr as JSON lines.
+    #[arg(long)]
+    status_json: bool,
+
+    /// Append session statistics to FILE as JSON lines every 10 seconds.
+    #[arg(long, value_name = "FILE")]
+    stats_log: Option<PathBuf>,
+
+    /// Record every transport instruction to FILE for bug reports; play it
+    /// back with `mosh-client replay FILE`.
+    #[arg(long, value_name = "FILE")]
+    record: Option<PathBuf>,
+
+    /// Write the remote screen to FILE as an asciinema v2 recording.
+    #[arg(long, value_name = "FILE")]
+    asciicast: Option<PathBuf>,
+
     /// Enable verbose logging.
     #[arg(short, long)]
     verbose: bool,
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let cli = Cli::parse();
+/// The other things `mosh-client` can be run as.
+#[derive(Subcommand, Debug)]
+enum Mode {
+    /// Run as the host end of a session, in place of mosh-server (Unix only).
+    Server(server::ServerCli),
+    /// Replay a --record recording and print the screen it produces.
+    Replay(recording::ReplayCli),
+}
+
+impl Cli {
+    /// Settings given on the command line, highest precedence over the config file.
+    fn profile(&self) -> config::Profile {
+        config::Profile {
+            user: None,
+            ssh_port: self.ssh_port,
+            identity: self.identity.clone(),
+            certificate: self.certificate.clone(),
+            strict_host_key_checking: self.strict_host_key_checking.map(|m| m.to_string()),
+            known_hosts: self.known_hosts.clone(),
+            global_known_hosts: self.global_known_hosts.clone(),
+            hash_known_hosts: flag_pair(self.hash_known_hosts, self.no_hash_known_hosts),
+            password_file: self.password_file.clone(),
+            password_env: self.password_env.clone(),
+            server: self.server.clone(),
+            port: self.port.clone(),
+            upload_server: self.upload_server.clone(),
+            connect_timeout: self.connect_timeout,
+            auth_timeout: self.auth_timeout,
+            server_timeout: self.server_timeout,
+            predict: self.predict.clone(),
+            pty: flag_pair(self.pty, self.no_pty),
+            forward_agent: flag_pair(self.forward_agent, self.no_forward_agent),
+            server_args: (!self.server_args.is_empty()).then(|| self.server_args.clone()),
+            command_key: self.command_key.clone(),
+            env: (!self.env.is_empty()).then(|| self.env.iter().cloned().collect()),
+        }
+    }
+}
+
+/// A `--flag`/`--no-flag` pair as a setting: unset unless one was given.
+/// Clap lets only the last of the two through.
+fn flag_pair(on: bool, off: bool) -> Option<bool> {
+    (on || off).then_some(on)
+}
+
+fn main() -> ExitCode {
+    let mut cli = match Cli::try_parse() {
+        Ok(cli) => cli,
+        Err(e) => {
+            let _ = e.print();
+            // --help and --version also arrive here, on stdout
+            let exit = if e.use_stderr() { ExitStatus::Usage } else { ExitStatus::Success };
+            return ExitCode::from(exit.code());
+        }
+    };
+
+    // `server` mode forks before any runtime threads exist, so it
+    // dispatches ahead of the client's runtime.
+    match cli.mode.take() {
+        Some(Mode::Server(args)) => return server::main(args),
+        Some(Mode::Replay(args)) => return recording::main(args),
+        None => {}
+    }
 
     // Initialize logging
     let log_level = if cli.verbose { "debug" } else { "warn" };
@@ -81,90 +293,378 @@ async fn main() -> Result<()> {
         .format_timestamp_millis()
         .init();
 
+    // Secrets are taken out of the environment by `prepare`, before the
+    // runtime starts its threads: changing the environment isn't thread-safe.
+    let status = StatusReporter::new(cli.status_json);
+    let result = prepare(cli, status).and_then(|plan| match plan {
+        Some(plan) => {
+            let runtime = tokio::runtime::Runtime::new().context("Failed to start the async runtime")?;
+            runtime.block_on(run(plan, status))
+        }
+        None => Ok(ExitStatus::Success),
+    });
+    let (exit, message) = match result {
+        Ok(exit) => (exit, None),
+        Err(err) => {
+            eprintln!("Error: {:?}", err);
+            (ExitStatus::of(&err), Some(format!("{:#}", err)))
+        }
+    };
+    status.emit(&StatusEvent::Exit {
+        code: exit.code(),
+        status: exit,
+        message,
+    });
+    ExitCode::from(exit.code())
+}
+
+/// A session ready to connect, as `prepare` settled it.
+struct Plan {
+    remote: Remote,
+    predict_mode: PredictionMode,
+    command_key: u8,
+    outputs: SessionOutputs,
+}
+
+/// How to reach the mosh server.
+enum Remote {
+    /// `--direct`: it's already running here, with this key.
+    Direct(SocketAddr, crypto::Base64Key),
+    /// Start it over SSH.
+    Ssh(Box<ssh::SshConfig>),
+}
+
+/// Everything after argument parsing that doesn't need the runtime: merge
+/// the settings, open the output files and read the key or password. `None`
+/// if there's nothing left to do.
+fn prepare(mut cli: Cli, status: StatusReporter) -> Result<Option<Plan>> {
+    // Merge command line, config file and built-in defaults
+    let host = cli.host.as_deref().expect("clap requires HOST without a subcommand");
+    let (explicit_user, hostname) = parse_user_host(host);
+    let file = config::ConfigFile::load(cli.config.as_deref())?;
+    let settings = cli
+        .profile()
+        .or(file.profile_for(&hostname))
+        .or(config::Profile::builtin());
+    let username = explicit_user
+        .or_else(|| settings.user.clone())
+        .unwrap_or_else(local_user_name);
+
+    if cli.print_config {
+        let effective = config::Profile {
+            user: Some(username),
+            ..settings
+        };
+        println!("# Effective settings for {}", hostname);
+        print!("{}", toml::to_string(&effective)?);
+        return Ok(None);
+    }
+
+    // Open output files up front, so a bad path fails before connecting
+    let outputs = SessionOutputs {
+        stats_log: cli.stats_log.as_deref().map(StatsLog::open).transpose()?,
+        recorder: cli.record.as_deref().map(recording::Recorder::create).transpose()?,
+        cast: cli.asciicast.as_deref().map(asciicast::Asciicast::create).transpose()?,
+    };
+
     // Parse prediction mode
-    let predict_mode = match cli.predict.as_str() {
-        "always" => PredictionMode::Always,
-        "never" => PredictionMode::Never,
-        "adaptive" | _ => PredictionMode::Adaptive,
+    let predict_mode = match settings.predict.as_deref() {
+        Some("always") => PredictionMode::Always,
+        Some("never") => PredictionMode::Never,
+        _ => PredictionMode::Adaptive,
     };
+    let command_key = config::parse_command_key(settings.command_key.as_deref().unwrap_or("ctrl-^"))?;
 
     // Get connection details either via SSH bootstrap or direct connection
-    let (remote_addr, key_str) = if let Some(ref direct) = cli.direct {
-        // Direct connection mode: MOSH_KEY must be set
-        let key = std::env::var("MOSH_KEY")
-            .context("MOSH_KEY environment variable must be set for direct connection")?;
-        // Security: remove from env after reading
-        unsafe { std::env::remove_var("MOSH_KEY") };
-        let addr: SocketAddr = direct
-            .parse()
-            .context("Invalid direct address format (expected IP:PORT)")?;
-        (addr, key)
+    let remote = if let Some(ref direct) = cli.direct {
+        // Direct connection mode: the key is read once, then wiped
+        let input = if let Some(ref path) = cli.key_file {
+            Zeroizing::new(
+                std::fs::read(path)
+                    .with_context(|| format!("Failed to read key file {}", path.display()))?,
+            )
+        } else if let Some(fd) = cli.key_fd {
+            read_key_fd(fd)?
+        } else if let Ok(key) = std::env::var("MOSH_KEY") {
+            // Security: remove from env after reading
+            unsafe { std::env::remove_var("MOSH_KEY") };
+            Zeroizing::new(key.into_bytes())
+        } else if !std::io::stdin().is_terminal() {
+            let mut input = Zeroizing::new(Vec::with_capacity(4096));
+            std::io::stdin()
+                .read_to_end(&mut input)
+                .context("Failed to read the session key from stdin")?;
+            input
+        } else {
+            bail!(
+                "--direct needs a session key: use --key-file, --key-fd, MOSH_KEY, \
+                 or pipe a MOSH CONNECT line on stdin"
+            );
+        };
+        let (port, key) = parse_key_input(&input)?;
+        let addr = direct_address(direct, port)?;
+        status.emit(&StatusEvent::ServerStarted {
+            addr: addr.to_string(),
+        });
+        Remote::Direct(addr, key)
     } else {
         // SSH bootstrap mode
-        let (username, hostname) = parse_user_host(&cli.host);
-
         let mut ssh_config = ssh::SshConfig::new(&hostname, &username);
-        ssh_config = ssh_config.with_port(cli.ssh_port);
-
-        if let Some(ref password) = cli.password {
-            ssh_config = ssh_config.with_password(password);
+        ssh_config = ssh_config.with_port(settings.ssh_port.unwrap_or(22));
+
+        // Each copy of the password is wiped when it goes out of scope
+        if let Some(password) = cli.password.take().map(Zeroizing::new) {
+            ssh_config = ssh_config.with_password(&password);
+        } else if let Some(ref path) = settings.password_file {
+            ssh_config = ssh_config.with_password(&ssh::read_password_file(path)?);
+        } else if let Some(ref var) = settings.password_env {
+            let password = Zeroizing::new(std::env::var(var).with_context(|| {
+                format!("--password-env: environment variable {} is not set", var)
+            })?);
+            // Keep it out of the environment of anything we spawn (askpass)
+            unsafe { std::env::remove_var(var) };
+            ssh_config = ssh_config.with_password(&password);
         }
 
-        if let Some(ref identity) = cli.identity {
+        if let Some(ref identity) = settings.identity {
             ssh_config = ssh_config.with_identity_file(identity.clone());
         }
 
-        ssh_config.mosh_server_command = cli.server.clone();
+        if let Some(ref certificate) = settings.certificate {
+            ssh_config = ssh_config.with_certificate_file(certificate.clone());
+        }
 
-        if !cli.server_args.is_empty() {
-            ssh_config.mosh_server_args = cli.server_args.clone();
+        if let Some(ref mode) = settings.strict_host_key_checking {
+            ssh_config = ssh_config.with_strict_host_key_checking(mode.parse()?);
         }
+        ssh_config = ssh_config.with_hash_known_hosts(settings.hash_known_hosts == Some(true));
 
-        eprintln!("Connecting to {} via SSH...", hostname);
-        let session = ssh::bootstrap(&ssh_config).await?;
-        eprintln!(
-            "mosh-server started on port {}. Establishing UDP session...",
-            session.port
-        );
+        if let Some(ref known_hosts) = settings.known_hosts {
+            ssh_config = ssh_config.with_user_known_hosts_file(known_hosts.clone());
+        }
 
-        // Resolve remote address
-        let addr_str = format!("{}:{}", session.remote_ip, session.port);
-        let addr: SocketAddr = tokio::net::lookup_host(&addr_str)
-            .await?
-            .next()
-            .context("Failed to resolve remote address")?;
+        if let Some(ref global_known_hosts) = settings.global_known_hosts {
+            ssh_config = ssh_config.with_global_known_hosts_file(global_known_hosts.clone());
+        }
+
+        for (name, value) in settings.env.iter().flatten() {
+            ssh_config = ssh_config.with_env(name, value);
+        }
+
+        ssh_config = ssh_config
+            .with_pty(settings.pty == Some(true))
+            .with_agent_forwarding(settings.forward_agent == Some(true));
+
+        if let Some(ref port) = settings.port {
+            let (low, high) = ssh::parse_port_range(port)?;
+            ssh_config = ssh_config.with_udp_port_range(low, high);
+        }
+
+        if let Some(ref binary) = settings.upload_server {
+            ssh_config = ssh_config.with_server_binary(binary.clone());
+        }
+
+        ssh_config = ssh_config
+            .with_connect_timeout(Duration::from_secs(settings.connect_timeout.unwrap_or(30)))
+            .with_auth_timeout(Duration::from_secs(settings.auth_timeout.unwrap_or(60)))
+            .with_server_start_timeout(Duration::from_secs(settings.server_timeout.unwrap_or(30)));
+
+        if let Some(ref server) = settings.server {
+            ssh_config.mosh_server_command = server.clone();
+        }
+
+        ssh_config.mosh_server_args.extend(settings.server_args.iter().flatten().cloned());
 
-        (addr, session.key)
+        if !cli.command.is_empty() {
+            ssh_config = ssh_config.with_remote_command(cli.command.clone());
+        }
+
+        Remote::Ssh(Box::new(ssh_config))
     };
 
-    // Parse the encryption key
-    let key = crypto::Base64Key::from_str(&key_str)?;
+    Ok(Some(Plan {
+        remote,
+        predict_mode,
+        command_key,
+        outputs,
+    }))
+}
+
+/// Connect as planned and run the session.
+async fn run(plan: Plan, status: StatusReporter) -> Result<ExitStatus> {
+    let (remote_addr, key) = match plan.remote {
+        Remote::Direct(addr, key) => (addr, key),
+        Remote::Ssh(ssh_config) => {
+            eprintln!("Connecting to {} via SSH...", ssh_config.host);
+            status.emit(&StatusEvent::Connecting {
+                host: &ssh_config.host,
+                port: ssh_config.port,
+                user: &ssh_config.username,
+            });
+            // Ctrl-C is watched on its own task so it works even while a
+            // prompt is blocked reading the console.
+            let interrupt = tokio::spawn(async move {
+                if tokio::signal::ctrl_c().await.is_ok() {
+                    ssh::restore_terminal_echo();
+                    eprintln!("\nInterrupted.");
+                    let exit = ExitStatus::Interrupted;
+                    status.emit(&StatusEvent::Exit {
+                        code: exit.code(),
+                        status: exit,
+                        message: None,
+                    });
+                    std::process::exit(exit.code().into());
+                }
+            });
+            let session = ssh::bootstrap(&ssh_config).await;
+            interrupt.abort();
+            let session = session?;
+            eprintln!(
+                "mosh-server started on port {}. Establishing UDP session...",
+                session.port
+            );
+
+            // Resolve remote address
+            let addr_str = format!("{}:{}", session.remote_ip, session.port);
+            let addr: SocketAddr = tokio::net::lookup_host(&addr_str)
+                .await?
+                .next()
+                .context("Failed to resolve remote address")?;
+            status.emit(&StatusEvent::ServerStarted {
+                addr: addr.to_string(),
+            });
+
+            (addr, session.key)
+        }
+    };
 
     // Enter the main session
-    run_session(remote_addr, &key, predict_mode).await
+    run_session(remote_addr, &key, plan.predict_mode, plan.command_key, plan.outputs, status).await
 }
 
 /// Parse "[user@]host" into (username, hostname).
-fn parse_user_host(input: &str) -> (String, String) {
+fn parse_user_host(input: &str) -> (Option<String>, String) {
     if let Some(at_pos) = input.find('@') {
         let user = input[..at_pos].to_string();
         let host = input[at_pos + 1..].to_string();
-        (user, host)
+        (Some(user), host)
     } else {
-        // Default to current user
-        let user = std::env::var("USER")
-            .or_else(|_| std::env::var("USERNAME"))
-            .unwrap_or_else(|_| "root".to_string());
-        (user, input.to_string())
+        (None, input.to_string())
     }
 }
 
+/// The local user name, the default remote user.
+fn local_user_name() -> String {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_else(|_| "root".to_string())
+}
+
+/// Find the session key in `--direct` key input: either a bare 22-character
+/// key or a `MOSH CONNECT <port> <key>` line, possibly among other output.
+fn parse_key_input(input: &[u8]) -> Result<(Option<u16>, crypto::Base64Key)> {
+    let text = std::str::from_utf8(input).context("Session key input is not UTF-8")?;
+    for line in text.lines().map(str::trim) {
+        if let Some(rest) = line.strip_prefix("MOSH CONNECT ") {
+            let (port, key) = ssh::parse_connect_fields(rest)?;
+            return Ok((Some(port), key));
+        }
+        if line.len() == 22 {
+            if let Ok(key) = crypto::Base64Key::from_str(line) {
+                return Ok((None, key));
+            }
+        }
+    }
+    bail!("No session key or MOSH CONNECT line found in the key input")
+}
+
+/// Combine `--direct IP[:PORT]` with the port from a MOSH CONNECT line.
+fn direct_address(spec: &str, connect_port: Option<u16>) -> Result<SocketAddr> {
+    if let Ok(addr) = spec.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+    let ip: IpAddr = spec
+        .parse()
+        .context("Invalid direct address format (expected IP[:PORT])")?;
+    let port = connect_port.context(
+        "--direct needs a port, either as IP:PORT or from a MOSH CONNECT line",
+    )?;
+    Ok(SocketAddr::new(ip, port))
+}
+
+/// Read all of the key input from an inherited descriptor, closing it.
+fn read_key_fd(fd: i64) -> Result<Zeroizing<Vec<u8>>> {
+    #[cfg(unix)]
+    let mut file = {
+        use std::os::unix::io::FromRawFd;
+        let fd = i32::try_from(fd).ok().filter(|&fd| fd > 2).context("--key-fd must be above 2")?;
+        // Safety: the caller handed us this descriptor to read and close
+        unsafe { std::fs::File::from_raw_fd(fd) }
+    };
+    #[cfg(windows)]
+    let mut file = {
+        use std::os::windows::io::FromRawHandle;
+        // Safety: the caller handed us this handle to read and close
+        unsafe { std::fs::File::from_raw_handle(fd as isize as _) }
+    };
+    let mut input = Zeroizing::new(Vec::with_capacity(4096));
+    file.read_to_end(&mut input)
+        .with_context(|| format!("Failed to read the session key from descriptor {}", fd))?;
+    Ok(input)
+}
+
+/// Validate a `--port` value, keeping it as written.
+fn parse_port_spec(input: &str) -> Result<String> {
+    ssh::parse_port_range(input)?;
+    Ok(input.to_string())
+}
+
+/// Validate a `--command-key` value, keeping it as written.
+fn parse_command_key_spec(input: &str) -> Result<String> {
+    config::parse_command_key(input)?;
+    Ok(input.to_string())
+}
+
+/// Parse a `NAME=VALUE` environment assignment.
+fn parse_env_var(input: &str) -> Result<(String, String), String> {
+    match input.split_once('=') {
+        Some((name, value))
+            if !name.is_empty()
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+                && !name.starts_with(|c: char| c.is_ascii_digit()) =>
+        {
+            Ok((name.to_string(), value.to_string()))
+        }
+        _ => Err(format!("expected NAME=VALUE, got '{}'", input)),
+    }
+}
+
+/// How long without hearing from the server before the notification bar says so.
+const STALL_NOTICE_AFTER: Duration = Duration::from_secs(15);
+/// How often the stats overlay is refreshed while it's shown.
+const STATS_OVERLAY_REFRESH: Duration = Duration::from_secs(1);
+/// How often predictions are re-checked while some are outstanding.
+const PREDICTION_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Files the session writes as it runs, each opened by its option.
+struct SessionOutputs {
+    /// `--stats-log`
+    stats_log: Option<StatsLog>,
+    /// `--record`
+    recorder: Option<recording::Recorder>,
+    /// `--asciicast`
+    cast: Option<asciicast::Asciicast>,
+}
+
 /// Main session loop: manages the terminal, transport, and rendering.
 async fn run_session(
     remote_addr: SocketAddr,
     key: &crypto::Base64Key,
     predict_mode: PredictionMode,
-) -> Result<()> {
+    command_key: u8,
+    outputs: SessionOutputs,
+    status: StatusReporter,
+) -> Result<ExitStatus> {
     // Get terminal dimensions
     let (term_width, term_height) = crossterm::terminal::size().context("Failed to get terminal size")?;
     let width = term_width as usize;
@@ -179,6 +679,13 @@ async fn run_session(
         height,
     )
     .await?;
+    let SessionOutputs { mut stats_log, recorder, cast } = outputs;
+    if let Some(recorder) = recorder {
+        transport.record_to(recorder);
+    }
+    if let Some(cast) = cast {
+        transport.cast_to(cast);
+    }
 
     log::info!(
         "UDP socket bound to {}, connecting to {}",
@@ -208,9 +715,20 @@ async fn run_session(
     notification.set_message("mosh: Connecting...");
 
     // Main event loop
-    let render_interval = Duration::from_millis(16); // ~60fps max
-    let mut last_render = std::time::Instant::now();
+    let mut frames = schedule::RenderScheduler::new(Duration::from_millis(16)); // ~60fps max
+    let mut events = EventStream::new();
+    let mut input: Vec<Event> = Vec::new();
+    let mut next_stats_refresh = tokio::time::Instant::now();
     let mut command_pending = false;
+    let mut connected = false;
+    let mut stalled = false;
+    let mut show_stats = false;
+    // Pressing the command key and then this key sends the command key itself
+    let literal_key = command_key ^ 0x40;
+    let command_help = format!(
+        "mosh: commands: Ctrl-Z suspend, '.' quit, 's' stats, '{0}' literal Ctrl-{0}",
+        literal_key as char
+    );
 
     loop {
         // 1. Try to receive from network and update modeled remote state queue.
@@ -218,11 +736,22 @@ async fn run_session(
         if transport.take_remote_state_changed() {
             latest_remote_fb = transport.latest_remote_framebuffer().clone();
             notification.clear();
+            frames.mark_dirty();
         }
         if let Some(reason) = transport.remote_close_reason() {
             let _ = renderer::Renderer::cleanup();
             eprintln!("\nmosh: {}", reason);
-            return Ok(());
+            return Ok(if transport.shutdown_in_progress() {
+                ExitStatus::UserQuit
+            } else if transport.remote_closed_gracefully() {
+                ExitStatus::Success
+            } else {
+                ExitStatus::RemoteClosed
+            });
+        }
+        if !connected && transport.has_received_data() {
+            connected = true;
+            status.emit(&StatusEvent::Connected);
         }
         predictor.set_local_frame_acked(transport.acked_state_num());
         predictor.set_send_interval(transport.send_interval_ms());
@@ -230,12 +759,24 @@ async fn run_session(
         predictor.cull(&latest_remote_fb);
 
         // Update connection status notification
-        if transport.time_since_last_recv() > Duration::from_secs(15) {
+        if transport.time_since_last_recv() > STALL_NOTICE_AFTER {
             notification.set_message(&format!(
                 "mosh: Last contact {:.0}s ago",
                 transport.time_since_last_recv().as_secs_f64()
             ));
-        } else if !transport.has_received_data() {
+            if !stalled {
+                stalled = true;
+                status.emit(&StatusEvent::Stalled {
+                    seconds: transport.time_since_last_recv().as_secs(),
+                });
+            }
+            frames.mark_dirty();
+        } else if stalled {
+            stalled = false;
+            status.emit(&StatusEvent::Resumed);
+            frames.mark_dirty();
+        }
+        if !transport.has_received_data() {
             notification.set_message("mosh: Connecting...");
         }
 
@@ -250,9 +791,12 @@ async fn run_session(
             local_framebuffer = composed;
         }
 
-        // 2. Process user input (keyboard events)
-        while event::poll(Duration::from_millis(0))? {
-            match event::read()? {
+        // 2. Process user input (terminal events)
+        if !input.is_empty() {
+            frames.mark_dirty();
+        }
+        for event in input.drain(..) {
+            match event {
                 Event::Key(key_event) => {
                     if !matches!(key_event.kind, KeyEventKind::Press | KeyEventKind::Repeat) {
                         continue;
@@ -262,18 +806,16 @@ async fn run_session(
                     }
                     predictor.set_local_frame_sent(transport.sent_state_last_num());
 
-                    if is_command_key(&key_event) {
+                    if is_command_key(&key_event, command_key) {
                         if command_pending {
                             command_pending = false;
-                            let data = vec![MOSH_COMMAND_KEY];
+                            let data = vec![command_key];
                             transport.push_user_input(&data);
                             predictor.new_user_input_batch(&data, &local_framebuffer);
                             notification.clear();
                         } else {
                             command_pending = true;
-                            notification.set_message(
-                                "mosh: commands: Ctrl-Z suspend, '.' quit, '^' literal Ctrl-^",
-                            );
+                            notification.set_message(&command_help);
                         }
                         continue;
                     }
@@ -293,6 +835,12 @@ async fn run_session(
                             continue;
                         }
 
+                        if data.eq_ignore_ascii_case(b"s") && !data.eq_ignore_ascii_case(&[literal_key]) {
+                            show_stats = !show_stats;
+                            notification.clear();
+                            continue;
+                        }
+
                         if data == vec![0x1a] {
                             // Upstream suspends via SIGSTOP; no direct equivalent on Windows.
                             notification.set_message("mosh: suspend is not supported on this platform");
@@ -300,8 +848,8 @@ async fn run_session(
                         }
 
                         let mut out = Vec::with_capacity(1 + data.len());
-                        out.push(MOSH_COMMAND_KEY);
-                        if data != b"^" {
+                        out.push(command_key);
+                        if !matches!(data[..], [c] if c.eq_ignore_ascii_case(&literal_key)) {
                             out.extend_from_slice(&data);
                         }
                         transport.push_user_input(&out);
@@ -346,19 +894,30 @@ async fn run_session(
 
         // 3. Tick the transport (send acks, retransmit, etc.)
         transport.tick().await?;
+        if let Some(log) = stats_log.as_mut() {
+            log.poll(|| session_stats(&transport, &predictor));
+        }
 
         if transport.shutdown_in_progress() && transport.shutdown_acknowledged() {
-            return Ok(());
+            return Ok(ExitStatus::UserQuit);
         }
         if transport.shutdown_in_progress() && transport.shutdown_ack_timed_out() {
-            return Ok(());
+            return Ok(ExitStatus::UserQuit);
         }
         if transport.counterparty_shutdown_ack_sent() {
-            return Ok(());
+            return Ok(ExitStatus::Success);
         }
 
-        // 4. Render at a reasonable frame rate
-        if last_render.elapsed() >= render_interval {
+        // 4. Render what changed, at a reasonable frame rate
+        let now = tokio::time::Instant::now();
+        if predictor.has_predictions() {
+            frames.mark_dirty();
+        }
+        if show_stats && now >= next_stats_refresh {
+            next_stats_refresh = now + STATS_OVERLAY_REFRESH;
+            frames.mark_dirty();
+        }
+        if frames.due(now) {
             // Create a display copy of the framebuffer for overlay application
             let mut overlay_fb = latest_remote_fb.clone();
 
@@ -367,23 +926,64 @@ async fn run_session(
                 overlay_fb.cursor_col = pc;
             }
 
+            notification.set_detail(if show_stats {
+                session_stats(&transport, &predictor).overlay_lines()
+            } else {
+                Vec::new()
+            });
             notification.apply(&mut overlay_fb);
 
             render.render(&overlay_fb)?;
-
-            last_render = std::time::Instant::now();
+            frames.rendered(now);
         }
 
-        // Wait for socket readability or a short timer (like mosh's select() on
-        // stdin + network with a timeout). This avoids busy-looping while still
-        // waking up promptly when the server sends data.
+        // 5. Sleep until a packet or terminal event arrives, or until the
+        // next thing is due (like mosh's select() with its wait_time()).
+        let mut wake = schedule::Deadlines::new();
+        wake.add(transport.next_wakeup());
+        wake.add(frames.deadline());
+        let since_recv = transport.time_since_last_recv();
+        wake.add(Some(if since_recv < STALL_NOTICE_AFTER {
+            // When the stall notice appears...
+            now + (STALL_NOTICE_AFTER - since_recv)
+        } else {
+            // ...and each time its seconds count goes up.
+            now + (Duration::from_secs(1) - Duration::from_nanos(since_recv.subsec_nanos().into()))
+        }));
+        wake.add(stats_log.as_ref().map(|log| tokio::time::Instant::from_std(log.next_due())));
+        if show_stats {
+            wake.add(Some(next_stats_refresh));
+        }
+        if predictor.has_predictions() {
+            // Predictions age into glitches with nothing arriving.
+            wake.add(Some(now + PREDICTION_POLL_INTERVAL));
+        }
         tokio::select! {
             _ = transport.readable() => {},
-            _ = tokio::time::sleep(Duration::from_millis(3)) => {},
+            event = events.next() => {
+                // Take everything already queued, e.g. the rest of a paste.
+                let mut next = Some(event);
+                while let Some(event) = next {
+                    match event {
+                        Some(Ok(event)) => input.push(event),
+                        Some(Err(e)) => return Err(e).context("Failed to read terminal input"),
+                        None => bail!("terminal input closed"),
+                    }
+                    next = events.next().now_or_never();
+                }
+            }
+            _ = wake.sleep() => {},
         }
     }
 }
 
+fn session_stats(transport: &transport::Transport, predictor: &prediction::PredictionEngine) -> SessionStats {
+    SessionStats {
+        transport: transport.stats(),
+        prediction: predictor.stats(),
+    }
+}
+
 /// Convert a crossterm key event to a Mosh action.
 fn handle_key_event(event: &KeyEvent) -> Option<Vec<u8>> {
     // Match mosh's stdin behavior: act on keydown/autorepeat bytes only.
@@ -456,15 +1056,15 @@ fn handle_key_event(event: &KeyEvent) -> Option<Vec<u8>> {
     Some(out)
 }
 
-fn is_command_key(event: &KeyEvent) -> bool {
+fn is_command_key(event: &KeyEvent, command_key: u8) -> bool {
     if !event.modifiers.contains(KeyModifiers::CONTROL) {
         return false;
     }
 
-    matches!(
-        event.code,
-        KeyCode::Char('6') | KeyCode::Char('^')
-    )
+    match event.code {
+        KeyCode::Char(c) if c.is_ascii() => encode_ctrl_char(c as u8) == Some(command_key),
+        _ => false,
+    }
 }
 
 /// Map Ctrl+ASCII combinations to terminal control bytes.
@@ -560,8 +1160,178 @@ mod tests {
             KeyEventKind::Press,
         );
         let normal = key(KeyCode::Char('6'), KeyModifiers::NONE, KeyEventKind::Press);
-        assert!(is_command_key(&cmd));
-        assert!(!is_command_key(&normal));
+        assert!(is_command_key(&cmd, 0x1E));
+        assert!(!is_command_key(&normal, 0x1E));
+
+        let ctrl_bracket = key(
+            KeyCode::Char(']'),
+            KeyModifiers::CONTROL,
+            KeyEventKind::Press,
+        );
+        assert!(is_command_key(&ctrl_bracket, 0x1D));
+        assert!(!is_command_key(&cmd, 0x1D));
+    }
+
+    #[test]
+    fn test_command_after_double_dash() {
+        let cli = Cli::try_parse_from([
+            "mosh-client",
+            "--server-arg",
+            "-v",
+            "me@host",
+            "--",
+            "tmux",
+            "attach",
+            "-d",
+        ])
+        .unwrap();
+        assert_eq!(cli.host.as_deref(), Some("me@host"));
+        assert_eq!(cli.server_args, ["-v"]);
+        assert_eq!(cli.command, ["tmux", "attach", "-d"]);
+    }
+
+    #[test]
+    fn test_subcommands_and_hosts_named_like_them() {
+        let cli = Cli::try_parse_from(["mosh-client", "server", "new", "-s"]).unwrap();
+        assert!(matches!(cli.mode, Some(Mode::Server(_))));
+        assert!(cli.host.is_none());
+        let cli = Cli::try_parse_from(["mosh-client", "replay", "session.jsonl"]).unwrap();
+        assert!(matches!(cli.mode, Some(Mode::Replay(_))));
+
+        // Client options in front, or a user name, make it a host
+        for argv in [&["mosh-client", "-v", "server"][..], &["mosh-client", "me@server"]] {
+            let cli = Cli::try_parse_from(argv).unwrap();
+            assert!(cli.mode.is_none());
+            assert!(cli.host.as_deref().unwrap().ends_with("server"));
+        }
+        let cli = Cli::try_parse_from(["mosh-client", "--ssh-port", "2222", "replay", "--", "ls"]).unwrap();
+        assert!(cli.mode.is_none());
+        assert_eq!(cli.host.as_deref(), Some("replay"));
+        assert_eq!(cli.command, ["ls"]);
+
+        let cli = Cli::try_parse_from(["mosh-client", "help"]).unwrap();
+        assert_eq!(cli.host.as_deref(), Some("help"));
+        assert!(Cli::try_parse_from(["mosh-client"]).is_err());
+    }
+
+    #[test]
+    fn test_config_fills_unset_flags() {
+        let cli = Cli::try_parse_from(["mosh-client", "--predict", "never", "host"]).unwrap();
+        let file = config::ConfigFile::parse(
+            "predict = \"always\"\n[host.\"host\"]\nssh-port = 2222\npty = true\n",
+        )
+        .unwrap();
+        let settings = cli
+            .profile()
+            .or(file.profile_for("host"))
+            .or(config::Profile::builtin());
+        assert_eq!(settings.predict.as_deref(), Some("never"));
+        assert_eq!(settings.ssh_port, Some(2222));
+        assert_eq!(settings.pty, Some(true));
+        assert_eq!(settings.server.as_deref(), Some("mosh-server"));
+    }
+
+    #[test]
+    fn test_no_flags_override_the_config() {
+        let file = config::ConfigFile::parse("pty = true
+forward-agent = true
+hash-known-hosts = true
+").unwrap();
+        let settings = |args: &[&str]| {
+            let cli = Cli::try_parse_from(["mosh-client"].iter().chain(args).chain(&["host"])).unwrap();
+            cli.profile().or(file.profile_for("host"))
+        };
+        let off = settings(&["--no-pty", "--no-forward-agent", "--no-hash-known-hosts"]);
+        assert_eq!(
+            (off.pty, off.forward_agent, off.hash_known_hosts),
+            (Some(false), Some(false), Some(false))
+        );
+        let unset = settings(&[]);
+        assert_eq!(
+            (unset.pty, unset.forward_agent, unset.hash_known_hosts),
+            (Some(true), Some(true), Some(true))
+        );
+        // The last of a pair wins.
+        assert_eq!(settings(&["--no-pty", "--pty"]).pty, Some(true));
+        assert_eq!(settings(&["-A", "--no-forward-agent"]).forward_agent, Some(false));
+    }
+
+    #[test]
+    fn test_password_sources_conflict() {
+        assert!(Cli::try_parse_from(["mosh-client", "--password", "x", "--password-env", "P", "h"]).is_err());
+        assert!(Cli::try_parse_from(["mosh-client", "--password-file", "f", "--password-env", "P", "h"]).is_err());
+        let cli = Cli::try_parse_from(["mosh-client", "--password-env", "P", "h"]).unwrap();
+        assert_eq!(cli.profile().password_env.as_deref(), Some("P"));
+    }
+
+    #[test]
+    fn test_parse_key_input() {
+        let (port, key) = parse_key_input(b"AAAAAAAAAAAAAAAAAAAAAA\n").unwrap();
+        assert_eq!((port, key.as_bytes()), (None, &[0u8; 16]));
+
+        let piped = b"SSH: connecting...\r\nMOSH CONNECT 60007 AAAAAAAAAAAAAAAAAAAAAA\r\n";
+        let (port, _) = parse_key_input(piped).unwrap();
+        assert_eq!(port, Some(60007));
+
+        assert!(parse_key_input(b"MOSH CONNECT 60007 short\n").is_err());
+        assert!(parse_key_input(b"nothing here\n").is_err());
+        assert!(parse_key_input(b"").is_err());
+    }
+
+    #[test]
+    fn test_direct_address() {
+        assert_eq!(
+            direct_address("192.0.2.1:60001", Some(60002)).unwrap(),
+            "192.0.2.1:60001".parse().unwrap()
+        );
+        assert_eq!(
+            direct_address("192.0.2.1", Some(60002)).unwrap(),
+            "192.0.2.1:60002".parse().unwrap()
+        );
+        assert_eq!(
+            direct_address("::1", Some(60003)).unwrap(),
+            "[::1]:60003".parse().unwrap()
+        );
+        assert!(direct_address("192.0.2.1", None).is_err());
+        assert!(direct_address("not-an-ip", Some(1)).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_read_key_fd() {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let line = b"MOSH CONNECT 60008 AAAAAAAAAAAAAAAAAAAAAA\n";
+        assert_eq!(
+            unsafe { libc::write(fds[1], line.as_ptr().cast(), line.len()) },
+            line.len() as isize
+        );
+        unsafe { libc::close(fds[1]) };
+        let input = read_key_fd(fds[0] as i64).unwrap();
+        assert_eq!(parse_key_input(&input).unwrap().0, Some(60008));
+        assert!(read_key_fd(0).is_err());
+    }
+
+    #[test]
+    fn test_key_options_require_direct() {
+        assert!(Cli::try_parse_from(["mosh-client", "--key-file", "k", "host"]).is_err());
+        assert!(Cli::try_parse_from([
+            "mosh-client", "--direct", "192.0.2.1", "--key-file", "k", "--key-fd", "3", "host"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_env_var() {
+        assert_eq!(
+            parse_env_var("EDITOR=vim -u NONE").unwrap(),
+            ("EDITOR".to_string(), "vim -u NONE".to_string())
+        );
+        assert_eq!(parse_env_var("EMPTY=").unwrap(), ("EMPTY".to_string(), String::new()));
+        assert!(parse_env_var("NOVALUE").is_err());
+        assert!(parse_env_var("=value").is_err());
+        assert!(parse_env_var("1BAD=x").is_err());
+        assert!(parse_env_var("BAD NAME=x").is_err());
     }
 
 }
@@ -7,6 +7,9 @@
 //!   [8-byte instruction_id BE][2-byte (final<<15 | frag_num) BE][payload...]
 
 use anyhow::{bail, Result};
+use std::time::Duration;
+use tokio::time::Instant;
+
 /// Network transport overhead: timestamps (4 bytes).
 const TIMESTAMP_LEN: usize = 4;
 
@@ -19,8 +22,38 @@ pub const DEFAULT_MTU: usize = 1280;
 /// Overhead per encrypted packet: 8-byte nonce + 16-byte OCB tag.
 const CRYPTO_OVERHEAD: usize = 24;
 
-/// Maximum payload per fragment: MTU - crypto overhead - timestamp overhead - fragment header.
-pub const MAX_FRAG_PAYLOAD: usize = DEFAULT_MTU - CRYPTO_OVERHEAD - TIMESTAMP_LEN - FRAG_HEADER_LEN;
+/// Largest datagram ever tried over IPv4: a 1500-byte Ethernet frame less
+/// the IPv4 (20) and UDP (8) headers.
+pub const MAX_MTU_V4: usize = 1472;
+
+/// Largest datagram ever tried over IPv6 (40-byte IPv6 header).
+pub const MAX_MTU_V6: usize = 1452;
+
+/// Smallest datagram fallen back to: the IPv4 minimum reassembly size (576)
+/// less IPv4 and UDP headers.
+pub const MIN_MTU: usize = 548;
+
+/// Datagram sizes path MTU discovery steps between, in increasing order.
+const MTU_PLATEAUS: [usize; 7] = [MIN_MTU, 1024, 1200, DEFAULT_MTU, 1400, MAX_MTU_V6, MAX_MTU_V4];
+
+/// Oversized sends that must go unacknowledged, while the peer is still
+/// heard from, before the MTU is lowered.
+const BLACK_HOLE_SENDS: u32 = 3;
+
+/// How long to run at an MTU before probing the next size up.
+const PROBE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Probe interval ceiling after repeated failed probes.
+const PROBE_INTERVAL_MAX: Duration = Duration::from_secs(600);
+
+/// How many recent oversized sends are remembered for matching acks.
+const TRACKED_SENDS: usize = 8;
+
+/// Maximum payload per fragment for a datagram of `mtu` bytes:
+/// MTU - crypto overhead - timestamp overhead - fragment header.
+pub const fn max_frag_payload(mtu: usize) -> usize {
+    mtu - CRYPTO_OVERHEAD - TIMESTAMP_LEN - FRAG_HEADER_LEN
+}
 
 /// A Mosh network packet (after decryption, before fragmentation parsing).
 #[derive(Debug, Clone)]
@@ -174,90 +207,288 @@ impl Fragmenter {
     }
 }
 
-/// Reassembles fragments into complete instructions.
-pub struct FragmentAssembly {
-    current_id: Option<u64>,
+/// Instructions reassembled concurrently; the one that has waited longest
+/// for a fragment is evicted to make room.
+const ASSEMBLY_IN_FLIGHT: usize = 4;
+
+/// One instruction whose fragments are still arriving.
+struct PartialInstruction {
+    id: u64,
     fragments: Vec<Option<Fragment>>,
     fragments_arrived: usize,
     fragments_total: Option<usize>,
+    /// Assembly clock at the most recent fragment, for eviction by age.
+    last_seen: u64,
 }
 
-impl FragmentAssembly {
-    pub fn new() -> Self {
+impl PartialInstruction {
+    fn new(id: u64) -> Self {
         Self {
-            current_id: None,
+            id,
             fragments: Vec::new(),
             fragments_arrived: 0,
             fragments_total: None,
+            last_seen: 0,
+        }
+    }
+
+    /// Store a fragment; returns the reassembled bytes once all have arrived.
+    fn add(&mut self, fragment: Fragment) -> Option<Vec<u8>> {
+        let idx = fragment.fragment_num as usize;
+        if self.fragments.len() <= idx {
+            self.fragments.resize(idx + 1, None);
+        }
+        if fragment.is_final {
+            let total = idx + 1;
+            self.fragments_total = Some(total);
+        }
+        if let Some(existing) = &self.fragments[idx] {
+            assert!(
+                existing == &fragment,
+                "FragmentAssembly duplicate fragment mismatch"
+            );
+        } else {
+            self.fragments[idx] = Some(fragment);
+            self.fragments_arrived += 1;
+        }
+
+        let total = self.fragments_total?;
+        assert!(self.fragments_arrived <= total);
+        if self.fragments_arrived < total {
+            return None;
+        }
+        let mut out = Vec::new();
+        for frag in &self.fragments[..total] {
+            let frag = frag
+                .as_ref()
+                .expect("FragmentAssembly missing fragment despite complete count");
+            out.extend_from_slice(&frag.contents);
+        }
+        Some(out)
+    }
+}
+
+/// Reassembles fragments into complete instructions.
+///
+/// Upstream keeps a single instruction in progress, so fragments of two
+/// instructions interleaved by reordering destroy each other. Here up to
+/// `ASSEMBLY_IN_FLIGHT` instructions are assembled side by side. Instruction
+/// IDs only grow, and the latest still wins: once an instruction completes,
+/// older partial ones are dropped and their late fragments ignored.
+pub struct FragmentAssembly {
+    partials: Vec<PartialInstruction>,
+    /// ID of the newest instruction delivered so far.
+    newest_complete: Option<u64>,
+    clock: u64,
+}
+
+impl FragmentAssembly {
+    pub fn new() -> Self {
+        Self {
+            partials: Vec::new(),
+            newest_complete: None,
+            clock: 0,
         }
     }
 
     /// Add a fragment. If this completes an instruction, returns the reassembled bytes.
     pub fn add_fragment(&mut self, fragment: Fragment) -> Option<Vec<u8>> {
-        // Match upstream semantics: only one packet assembly in progress.
-        if self.current_id != Some(fragment.id) {
-            self.current_id = Some(fragment.id);
-            self.fragments.clear();
-            self.fragments.resize(fragment.fragment_num as usize + 1, None);
-            self.fragments[fragment.fragment_num as usize] = Some(fragment.clone());
-            self.fragments_arrived = 1;
-            self.fragments_total = None;
-        } else {
-            let idx = fragment.fragment_num as usize;
-            if self.fragments.len() <= idx {
-                self.fragments.resize(idx + 1, None);
-            }
-            if let Some(existing) = &self.fragments[idx] {
-                assert!(
-                    existing == &fragment,
-                    "FragmentAssembly duplicate fragment mismatch"
-                );
-            } else {
-                self.fragments[idx] = Some(fragment.clone());
-                self.fragments_arrived += 1;
+        if self.newest_complete.is_some_and(|newest| fragment.id < newest) {
+            log::debug!("dropping fragment of superseded instruction {}", fragment.id);
+            return None;
+        }
+        self.clock += 1;
+
+        let idx = match self.partials.iter().position(|p| p.id == fragment.id) {
+            Some(idx) => idx,
+            None => {
+                if self.partials.len() == ASSEMBLY_IN_FLIGHT {
+                    let oldest = self
+                        .partials
+                        .iter()
+                        .enumerate()
+                        .min_by_key(|(_, p)| p.last_seen)
+                        .map(|(i, _)| i)
+                        .expect("in-flight set is non-empty");
+                    log::debug!(
+                        "evicting partial instruction {}",
+                        self.partials[oldest].id
+                    );
+                    self.partials.swap_remove(oldest);
+                }
+                self.partials.push(PartialInstruction::new(fragment.id));
+                self.partials.len() - 1
             }
+        };
+
+        let partial = &mut self.partials[idx];
+        partial.last_seen = self.clock;
+        let id = partial.id;
+        let out = partial.add(fragment)?;
+
+        self.newest_complete = Some(id);
+        self.partials.retain(|p| p.id > id);
+        Some(out)
+    }
+}
+
+// ── Path MTU ───────────────────────────────────────────────────────────────
+
+/// Per-path MTU estimate (packetization-layer discovery, RFC 4821 style).
+///
+/// Mosh has no dedicated probe packets, so the estimate is driven by the
+/// instructions themselves. A send is "oversized" when it would have been
+/// smaller one plateau down. If `BLACK_HOLE_SENDS` of them go unacknowledged
+/// while other packets from the peer keep arriving, large datagrams are
+/// being dropped on the path and the MTU steps down. After running at a size
+/// for `PROBE_INTERVAL`, the next plateau up is tried; a probe that turns out
+/// to be a black hole doubles the interval before the next attempt.
+///
+/// All methods take the current time so the logic can be driven by tests.
+#[derive(Debug)]
+pub struct PathMtu {
+    mtu: usize,
+    ceiling: usize,
+    /// Recent oversized sends as (state number, datagram length).
+    oversized: Vec<(u64, usize)>,
+    /// Oversized sends since the last acknowledged one.
+    unacked: u32,
+    /// Whether anything arrived from the peer after the first of those.
+    heard: bool,
+    /// The current MTU was raised by a probe that hasn't been acknowledged yet.
+    probing: bool,
+    probe_interval: Duration,
+    next_probe: Instant,
+}
+
+impl PathMtu {
+    /// Start at `DEFAULT_MTU`, probing up to the Ethernet limit for the
+    /// address family.
+    pub fn new(ipv6: bool, now: Instant) -> Self {
+        Self {
+            mtu: DEFAULT_MTU,
+            ceiling: if ipv6 { MAX_MTU_V6 } else { MAX_MTU_V4 },
+            oversized: Vec::new(),
+            unacked: 0,
+            heard: false,
+            probing: false,
+            probe_interval: PROBE_INTERVAL,
+            next_probe: now + PROBE_INTERVAL,
         }
+    }
 
-        if fragment.is_final {
-            let total = fragment.fragment_num as usize + 1;
-            self.fragments_total = Some(total);
-            if self.fragments.len() < total {
-                self.fragments.resize(total, None);
-            }
+    /// Current datagram size limit in bytes.
+    pub fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    /// Fragment payload size for the current MTU.
+    pub fn max_frag_payload(&self) -> usize {
+        max_frag_payload(self.mtu())
+    }
+
+    /// Record that state `num` went out with its largest datagram `len` bytes long.
+    pub fn on_send(&mut self, num: u64, len: usize, now: Instant) {
+        self.oversized.retain(|&(n, _)| n != num);
+        if len <= self.step_down() {
+            return;
         }
+        if self.oversized.len() == TRACKED_SENDS {
+            self.oversized.remove(0);
+        }
+        self.oversized.push((num, len));
+        if self.unacked == 0 {
+            self.heard = false;
+        }
+        self.unacked += 1;
 
-        if let Some(total) = self.fragments_total {
-            assert!(self.fragments_arrived <= total);
-            if self.fragments_arrived == total {
-                let mut out = Vec::new();
-                for i in 0..total {
-                    let frag = self.fragments[i]
-                        .as_ref()
-                        .expect("FragmentAssembly missing fragment despite complete count");
-                    out.extend_from_slice(&frag.contents);
-                }
+        if self.unacked >= BLACK_HOLE_SENDS && self.heard {
+            self.lower(now);
+        }
+    }
 
-                self.current_id = None;
-                self.fragments.clear();
-                self.fragments_arrived = 0;
-                self.fragments_total = None;
-                return Some(out);
-            }
+    /// Record that a packet arrived from the peer.
+    pub fn on_receive(&mut self) {
+        if self.unacked > 0 {
+            self.heard = true;
+        }
+    }
+
+    /// Record the peer's acknowledgment of state `num`.
+    ///
+    /// Acks are cumulative, so this covers every oversized send up to `num`,
+    /// even if the peer never acked that exact state.
+    pub fn on_ack(&mut self, num: u64) {
+        let acked = self.oversized.iter().filter(|&&(n, _)| n <= num);
+        let Some(len) = acked.map(|&(_, len)| len).max() else {
+            return;
+        };
+        self.oversized.retain(|&(n, _)| n > num);
+        self.unacked = 0;
+        if self.probing && len > self.step_down() {
+            log::debug!("path MTU {} confirmed", self.mtu);
+            self.probing = false;
+            self.probe_interval = PROBE_INTERVAL;
+        }
+    }
+
+    /// Probe the next size up when it's time.
+    pub fn poll(&mut self, now: Instant) {
+        if self.probing || self.mtu >= self.ceiling || now < self.next_probe {
+            return;
         }
+        let up = MTU_PLATEAUS
+            .iter()
+            .copied()
+            .find(|&p| p > self.mtu)
+            .unwrap_or(self.ceiling)
+            .min(self.ceiling);
+        log::debug!("probing path MTU {} (was {})", up, self.mtu);
+        self.mtu = up;
+        self.probing = true;
+        self.reset_tracking();
+        self.next_probe = now + self.probe_interval;
+    }
+
+    /// The next plateau below the current MTU.
+    fn step_down(&self) -> usize {
+        MTU_PLATEAUS
+            .iter()
+            .rev()
+            .copied()
+            .find(|&p| p < self.mtu)
+            .unwrap_or(MIN_MTU)
+    }
 
-        None
+    fn lower(&mut self, now: Instant) {
+        let down = self.step_down();
+        if self.probing {
+            self.probe_interval = (self.probe_interval * 2).min(PROBE_INTERVAL_MAX);
+            self.probing = false;
+        }
+        if down != self.mtu {
+            log::info!("path MTU lowered to {} (was {})", down, self.mtu);
+            self.mtu = down;
+        }
+        self.reset_tracking();
+        self.next_probe = now + self.probe_interval;
     }
 
+    fn reset_tracking(&mut self) {
+        self.oversized.clear();
+        self.unacked = 0;
+        self.heard = false;
+    }
 }
 
-/// Generate a 16-bit timestamp from the current time (milliseconds mod 65536).
-pub fn current_timestamp() -> u16 {
-    use std::time::{SystemTime, UNIX_EPOCH};
-    let millis = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap_or_default()
-        .as_millis();
-    (millis % 65536) as u16
+/// Generate a 16-bit timestamp: milliseconds since `epoch`, mod 65536.
+///
+/// Each side stamps packets from its own clock and only compares the echoes
+/// of its own stamps, so any fixed epoch will do. A monotonic one keeps the
+/// RTT sample sane across wall-clock jumps and follows tokio's paused clock
+/// in tests.
+pub fn timestamp_since(epoch: Instant) -> u16 {
+    (epoch.elapsed().as_millis() % 65536) as u16
 }
 
 #[cfg(test)]
@@ -312,7 +543,7 @@ mod tests {
     fn test_fragmenter_single_fragment() {
         let mut fragmenter = Fragmenter::new();
         let data = vec![0u8; 100];
-        let frags = fragmenter.make_fragments(&data, MAX_FRAG_PAYLOAD);
+        let frags = fragmenter.make_fragments(&data, max_frag_payload(DEFAULT_MTU));
         assert_eq!(frags.len(), 1);
         assert!(frags[0].is_final);
         assert_eq!(frags[0].contents.len(), 100);
@@ -363,4 +594,205 @@ mod tests {
         }
         assert_eq!(result.unwrap(), data.to_vec());
     }
+
+    /// Fragments of instructions with consecutive IDs, as a sender would make them.
+    fn instructions(rng: &mut impl rand::Rng, count: usize) -> Vec<(u64, Vec<u8>, Vec<Fragment>)> {
+        let mut fragmenter = Fragmenter::new();
+        (0..count)
+            .map(|i| {
+                let len = rng.gen_range(1..200);
+                let mut data = vec![0u8; len];
+                rng.fill_bytes(&mut data);
+                data[0] = i as u8; // keep consecutive instructions distinct
+                let frags = fragmenter.make_fragments(&data, rng.gen_range(8..40));
+                (frags[0].id, data, frags)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_fragment_assembly_interleaved() {
+        let mut fragmenter = Fragmenter::new();
+        let a = fragmenter.make_fragments(&[1u8; 50], 20);
+        let b = fragmenter.make_fragments(&[2u8; 50], 20);
+
+        // A0 B0 A1 B1 A2 B2: the old single-slot assembly lost both.
+        let mut assembly = FragmentAssembly::new();
+        let mut out = Vec::new();
+        for (fa, fb) in a.into_iter().zip(b) {
+            out.extend(assembly.add_fragment(fa));
+            out.extend(assembly.add_fragment(fb));
+        }
+        assert_eq!(out, vec![vec![1u8; 50], vec![2u8; 50]]);
+    }
+
+    #[test]
+    fn test_fragment_assembly_latest_wins() {
+        let mut fragmenter = Fragmenter::new();
+        let a = fragmenter.make_fragments(&[1u8; 50], 20);
+        let b = fragmenter.make_fragments(&[2u8; 50], 20);
+
+        let mut assembly = FragmentAssembly::new();
+        assert_eq!(assembly.add_fragment(a[0].clone()), None);
+        let mut out = None;
+        for frag in b {
+            out = assembly.add_fragment(frag);
+        }
+        assert_eq!(out, Some(vec![2u8; 50]));
+
+        // The older instruction is superseded, even once complete.
+        for frag in a {
+            assert_eq!(assembly.add_fragment(frag), None);
+        }
+    }
+
+    #[test]
+    fn test_fragment_assembly_evicts_oldest() {
+        let mut fragmenter = Fragmenter::new();
+        let all: Vec<Vec<Fragment>> = (0..=ASSEMBLY_IN_FLIGHT as u8)
+            .map(|i| fragmenter.make_fragments(&[i; 30], 20))
+            .collect();
+
+        let mut assembly = FragmentAssembly::new();
+        // Start every instruction; the first is pushed out by the last.
+        for frags in &all {
+            assert_eq!(assembly.add_fragment(frags[0].clone()), None);
+        }
+        assert_eq!(assembly.add_fragment(all[0][1].clone()), None);
+        // The newest survived and still completes.
+        let last = ASSEMBLY_IN_FLIGHT;
+        assert_eq!(
+            assembly.add_fragment(all[last][1].clone()),
+            Some(vec![last as u8; 30])
+        );
+    }
+
+    #[test]
+    fn test_fragment_assembly_shuffled() {
+        use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+
+        let mut rng = StdRng::seed_from_u64(0x66726167);
+        for _ in 0..500 {
+            let count = rng.gen_range(1..=ASSEMBLY_IN_FLIGHT);
+            let sent = instructions(&mut rng, count);
+            let mut wire: Vec<Fragment> =
+                sent.iter().flat_map(|(_, _, frags)| frags.clone()).collect();
+            // Duplicates are common on real networks too.
+            for _ in 0..rng.gen_range(0..3) {
+                let dup = wire[rng.gen_range(0..wire.len())].clone();
+                wire.push(dup);
+            }
+            wire.shuffle(&mut rng);
+
+            let mut assembly = FragmentAssembly::new();
+            let delivered: Vec<Vec<u8>> =
+                wire.into_iter().filter_map(|f| assembly.add_fragment(f)).collect();
+
+            // Everything delivered is intact, in ID order (a duplicated
+            // fragment may deliver an instruction again, as upstream), and
+            // the newest instruction always gets through.
+            let ids: Vec<u64> = delivered
+                .iter()
+                .map(|d| sent.iter().find(|(_, data, _)| data == d).expect("corrupt instruction").0)
+                .collect();
+            assert!(ids.windows(2).all(|w| w[0] <= w[1]), "{:?}", ids);
+            assert_eq!(ids.last(), Some(&sent.last().unwrap().0));
+        }
+    }
+
+    #[test]
+    fn test_path_mtu_lowers_on_black_hole() {
+        let now = Instant::now();
+        let mut pmtu = PathMtu::new(false, now);
+        assert_eq!(pmtu.max_frag_payload(), max_frag_payload(DEFAULT_MTU));
+
+        // A total outage isn't a black hole: nothing heard, nothing lowered.
+        for num in 1..=5 {
+            pmtu.on_send(num, DEFAULT_MTU, now);
+        }
+        assert_eq!(pmtu.mtu(), DEFAULT_MTU);
+
+        // Small sends aren't tracked at all.
+        pmtu.on_send(6, 100, now);
+        pmtu.on_receive();
+        pmtu.on_send(7, DEFAULT_MTU, now);
+        assert_eq!(pmtu.mtu(), 1200);
+        pmtu.on_send(8, 1200, now);
+        pmtu.on_receive();
+        pmtu.on_send(8, 1200, now);
+        pmtu.on_send(8, 1200, now);
+        assert_eq!(pmtu.mtu(), 1024);
+
+        // Acknowledged sends reset the count.
+        pmtu.on_send(9, 1024, now);
+        pmtu.on_receive();
+        pmtu.on_send(9, 1024, now);
+        pmtu.on_ack(9);
+        pmtu.on_send(10, 1024, now);
+        pmtu.on_send(10, 1024, now);
+        assert_eq!(pmtu.mtu(), 1024);
+    }
+
+    #[test]
+    fn test_path_mtu_ack_past_oversized_state() {
+        let now = Instant::now();
+        let mut pmtu = PathMtu::new(false, now);
+        pmtu.on_receive();
+        pmtu.on_send(1, DEFAULT_MTU, now);
+        pmtu.on_receive();
+
+        // The peer skips straight to a later state; state 1 arrived too.
+        pmtu.on_ack(3);
+        pmtu.on_send(4, DEFAULT_MTU, now);
+        pmtu.on_receive();
+        pmtu.on_send(5, DEFAULT_MTU, now);
+        assert_eq!(pmtu.mtu(), DEFAULT_MTU);
+    }
+
+    #[test]
+    fn test_path_mtu_floor() {
+        let now = Instant::now();
+        let mut pmtu = PathMtu::new(true, now);
+        for num in 0..50 {
+            pmtu.on_receive();
+            pmtu.on_send(num, pmtu.mtu(), now);
+        }
+        assert_eq!(pmtu.mtu(), MIN_MTU);
+        assert!(pmtu.max_frag_payload() > 0);
+    }
+
+    #[test]
+    fn test_path_mtu_probes_up_and_backs_off() {
+        let start = Instant::now();
+        let mut pmtu = PathMtu::new(true, start);
+        pmtu.poll(start);
+        assert_eq!(pmtu.mtu(), DEFAULT_MTU);
+
+        // A confirmed probe stays.
+        let t = start + PROBE_INTERVAL;
+        pmtu.poll(t);
+        assert_eq!(pmtu.mtu(), 1400);
+        pmtu.on_send(1, 1400, t);
+        pmtu.on_ack(1);
+
+        // A failed probe falls back and waits twice as long.
+        let t = t + PROBE_INTERVAL;
+        pmtu.poll(t);
+        assert_eq!(pmtu.mtu(), MAX_MTU_V6);
+        for _ in 0..BLACK_HOLE_SENDS {
+            pmtu.on_receive();
+            pmtu.on_send(2, MAX_MTU_V6, t);
+        }
+        assert_eq!(pmtu.mtu(), 1400);
+        pmtu.poll(t + PROBE_INTERVAL);
+        assert_eq!(pmtu.mtu(), 1400);
+        pmtu.poll(t + PROBE_INTERVAL * 2);
+        assert_eq!(pmtu.mtu(), MAX_MTU_V6);
+
+        // Never beyond the address family's ceiling.
+        pmtu.on_send(3, MAX_MTU_V6, t);
+        pmtu.on_ack(3);
+        pmtu.poll(t + PROBE_INTERVAL * 10);
+        assert_eq!(pmtu.mtu(), MAX_MTU_V6);
+    }
 }
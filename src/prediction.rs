@@ -10,22 +10,17 @@ use crate::terminal::{Cell, Framebuffer};
 use std::time::{Duration, Instant};
 
 /// Prediction display mode.
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
 pub enum PredictionMode {
     /// Never predict.
     Never,
     /// Always display predictions.
     Always,
     /// Display predictions adaptively from timing heuristics.
+    #[default]
     Adaptive,
 }
 
-impl Default for PredictionMode {
-    fn default() -> Self {
-        PredictionMode::Adaptive
-    }
-}
-
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum Validity {
     Pending,
@@ -147,6 +142,24 @@ pub struct PredictionEngine {
     last_width: usize,
     last_height: usize,
     predict_overwrite: bool,
+    stats: PredictionStats,
+}
+
+/// How predictions have fared against the server's screen.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize)]
+pub struct PredictionStats {
+    /// Predicted cells the server confirmed.
+    pub confirmed: u64,
+    /// Predictions the server contradicted or that expired.
+    pub wrong: u64,
+}
+
+impl PredictionStats {
+    /// Share of judged predictions that were right, if any were judged.
+    pub fn hit_rate(&self) -> Option<f64> {
+        let judged = self.confirmed + self.wrong;
+        (judged > 0).then(|| self.confirmed as f64 / judged as f64)
+    }
 }
 
 impl PredictionEngine {
@@ -171,9 +184,14 @@ impl PredictionEngine {
             last_width: width,
             last_height: height,
             predict_overwrite: false,
+            stats: PredictionStats::default(),
         }
     }
 
+    pub fn stats(&self) -> PredictionStats {
+        self.stats
+    }
+
     pub fn resize(&mut self, width: usize, height: usize) {
         self.width = width;
         self.height = height;
@@ -189,9 +207,6 @@ impl PredictionEngine {
         self.become_tentative();
     }
 
-    /// Compatibility no-op; upstream infers cursor from the passed framebuffer.
-    pub fn set_cursor_from_server(&mut self, _row: usize, _col: usize) {}
-
     pub fn set_local_frame_sent(&mut self, frame_num: u64) {
         self.local_frame_sent = frame_num;
     }
@@ -210,11 +225,6 @@ impl PredictionEngine {
         self.send_interval_ms = send_interval_ms;
     }
 
-    /// Compatibility shim: call sites may still refer to server_ack().
-    pub fn server_ack(&mut self, echo_ack_num: u64) {
-        self.set_local_frame_late_acked(echo_ack_num);
-    }
-
     /// Handle a batch of input. Like upstream, large pastes disable predictions.
     pub fn new_user_input_batch(&mut self, data: &[u8], base_fb: &Framebuffer) {
         if data.len() > 100 {
@@ -723,6 +733,7 @@ impl PredictionEngine {
 
                     match validity {
                         Validity::IncorrectOrExpired => {
+                            self.stats.wrong += 1;
                             let cell = &row.overlay_cells[idx];
                             if cell.tentative(self.confirmed_epoch) {
                                 kill_epoch = Some(cell.tentative_until_epoch);
@@ -737,6 +748,7 @@ impl PredictionEngine {
                                 (cell.tentative_until_epoch, cell.prediction_time, cell.col)
                             };
 
+                            self.stats.confirmed += 1;
                             if tentative_until_epoch > self.confirmed_epoch {
                                 self.confirmed_epoch = tentative_until_epoch;
                             }
@@ -949,6 +961,32 @@ mod tests {
         assert!(p.has_predictions());
     }
 
+    #[test]
+    fn counts_confirmed_and_wrong_predictions() {
+        let mut p = PredictionEngine::new(PredictionMode::Always, 80, 24);
+        assert_eq!(p.stats().hit_rate(), None);
+        let fb = blank_fb();
+        p.set_local_frame_sent(0);
+        p.new_user_input_batch(b"x", &fb);
+
+        let mut echoed = fb.clone();
+        echoed.cells[0][0].character = 'x';
+        echoed.cursor_col = 1;
+        p.set_local_frame_late_acked(1);
+        p.cull(&echoed);
+        assert_eq!(p.stats(), PredictionStats { confirmed: 1, wrong: 0 });
+
+        p.set_local_frame_sent(1);
+        p.new_user_input_batch(b"y", &echoed);
+        let mut other = echoed.clone();
+        other.cells[0][1].character = 'z';
+        other.cursor_col = 2;
+        p.set_local_frame_late_acked(2);
+        p.cull(&other);
+        assert_eq!(p.stats(), PredictionStats { confirmed: 1, wrong: 1 });
+        assert_eq!(p.stats().hit_rate(), Some(0.5));
+    }
+
     #[test]
     fn predicts_backspace_by_erasing_previous_cell() {
         let mut p = PredictionEngine::new(PredictionMode::Always, 80, 24);
@@ -0,0 +1,364 @@
+//! Unix pseudo-terminals for `mosh-client server`.
+//!
+//! The shell runs on the slave side of a pty as a session leader; the server
+//! keeps the non-blocking master and drives it from tokio.
+
+use anyhow::{bail, Context, Result};
+use std::ffi::{CString, OsStr, OsString};
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio::io::unix::AsyncFd;
+
+/// How long the child gets to exit after `Pty::shutdown`'s SIGHUP before it
+/// is killed.
+const HANGUP_GRACE: Duration = Duration::from_millis(500);
+
+/// A child process on a pseudo-terminal.
+pub struct Pty {
+    master: AsyncFd<OwnedFd>,
+    pid: libc::pid_t,
+    /// Set once the child has been reaped, so its pid is never reused by us.
+    status: OnceLock<i32>,
+}
+
+impl Pty {
+    /// Run `program` with `argv` (including argv[0]) and exactly the
+    /// environment `env` on a new `width`x`height` pty.
+    ///
+    /// Everything the child needs is built before forking, so this is safe
+    /// to call from a multi-threaded process. Needs a tokio runtime.
+    pub fn spawn(
+        program: &Path,
+        argv: &[String],
+        env: &[(OsString, OsString)],
+        width: u16,
+        height: u16,
+    ) -> Result<Self> {
+        let program = c_string(program.as_os_str())?;
+        let argv = argv.iter().map(|a| c_string(a.as_ref())).collect::<Result<Vec<_>>>()?;
+        let envp = env
+            .iter()
+            .map(|(name, value)| {
+                let mut entry = name.clone();
+                entry.push("=");
+                entry.push(value);
+                c_string(&entry)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let mut argv_ptrs: Vec<*const libc::c_char> = argv.iter().map(|a| a.as_ptr()).collect();
+        argv_ptrs.push(std::ptr::null());
+        let mut envp_ptrs: Vec<*const libc::c_char> = envp.iter().map(|e| e.as_ptr()).collect();
+        envp_ptrs.push(std::ptr::null());
+
+        let winsize = winsize(width, height);
+        let mut master: libc::c_int = -1;
+        // SAFETY: the pointers outlive the call; the child only makes
+        // async-signal-safe calls before exec or _exit.
+        let pid = unsafe { libc::forkpty(&mut master, std::ptr::null_mut(), std::ptr::null(), &winsize) };
+        if pid < 0 {
+            return Err(io::Error::last_os_error()).context("forkpty failed");
+        }
+        if pid == 0 {
+            unsafe {
+                // Rust ignores SIGPIPE, and ignored signals survive exec.
+                libc::signal(libc::SIGPIPE, libc::SIG_DFL);
+                libc::execve(program.as_ptr(), argv_ptrs.as_ptr(), envp_ptrs.as_ptr());
+                libc::_exit(127);
+            }
+        }
+
+        // SAFETY: forkpty handed us this descriptor and nothing else owns it.
+        let master = unsafe { OwnedFd::from_raw_fd(master) };
+        set_nonblocking(&master)?;
+        Ok(Self {
+            master: AsyncFd::new(master).context("Failed to register pty with the runtime")?,
+            pid,
+            status: OnceLock::new(),
+        })
+    }
+
+    /// Read shell output. `Ok(0)` once the slave side is closed for good.
+    ///
+    /// Cancel safe: no output is lost if the future is dropped.
+    pub async fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let mut guard = self.master.readable().await?;
+            match guard.try_io(|fd| {
+                // SAFETY: `buf` is valid for `buf.len()` bytes.
+                let n = unsafe { libc::read(fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) };
+                if n < 0 {
+                    Err(io::Error::last_os_error())
+                } else {
+                    Ok(n as usize)
+                }
+            }) {
+                // Linux reports a hung-up slave as EIO rather than EOF.
+                Ok(Err(e)) if e.raw_os_error() == Some(libc::EIO) => return Ok(0),
+                Ok(result) => return result,
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    /// Send input to the shell.
+    pub async fn write_all(&self, mut data: &[u8]) -> io::Result<()> {
+        while !data.is_empty() {
+            let mut guard = self.master.writable().await?;
+            match guard.try_io(|fd| {
+                // SAFETY: `data` is valid for `data.len()` bytes.
+                let n = unsafe { libc::write(fd.as_raw_fd(), data.as_ptr().cast(), data.len()) };
+                if n < 0 {
+                    Err(io::Error::last_os_error())
+                } else {
+                    Ok(n as usize)
+                }
+            }) {
+                Ok(Ok(n)) => data = &data[n..],
+                Ok(Err(e)) => return Err(e),
+                Err(_would_block) => continue,
+            }
+        }
+        Ok(())
+    }
+
+    /// Change the window size; the shell gets SIGWINCH.
+    pub fn resize(&self, width: u16, height: u16) -> io::Result<()> {
+        let winsize = winsize(width, height);
+        // SAFETY: TIOCSWINSZ reads one `winsize`.
+        if unsafe { libc::ioctl(self.master.get_ref().as_raw_fd(), libc::TIOCSWINSZ, &winsize) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// The child's exit status, if it has exited.
+    pub fn try_wait(&self) -> Option<i32> {
+        self.wait(libc::WNOHANG)
+    }
+
+    /// Hang up on the child and reap it, killing it if it outlasts
+    /// `HANGUP_GRACE`, so no zombie is left behind.
+    pub async fn shutdown(&self) {
+        if self.try_wait().is_some() {
+            return;
+        }
+        self.signal(libc::SIGHUP);
+        let deadline = tokio::time::Instant::now() + HANGUP_GRACE;
+        while tokio::time::Instant::now() < deadline {
+            if self.try_wait().is_some() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        log::debug!("Shell ignored SIGHUP; killing it");
+        self.signal(libc::SIGKILL);
+        let pid = self.pid;
+        if let Ok(Some(code)) = tokio::task::spawn_blocking(move || waitpid(pid, 0)).await {
+            let _ = self.status.set(code);
+        }
+    }
+
+    /// Send `signal` to the child unless it has already been reaped.
+    fn signal(&self, signal: libc::c_int) {
+        if self.status.get().is_none() {
+            // SAFETY: signalling our own, not yet reaped, child.
+            unsafe { libc::kill(self.pid, signal) };
+        }
+    }
+
+    /// Reap the child with `waitpid(flags)`, remembering its status.
+    fn wait(&self, flags: libc::c_int) -> Option<i32> {
+        if let Some(&code) = self.status.get() {
+            return Some(code);
+        }
+        let code = waitpid(self.pid, flags)?;
+        Some(*self.status.get_or_init(|| code))
+    }
+}
+
+impl Drop for Pty {
+    /// Kill a child that `shutdown` didn't see off. Never blocks: a child
+    /// that hasn't died by the time of the final `WNOHANG` reap is left to
+    /// the process's exit.
+    fn drop(&mut self) {
+        if self.try_wait().is_some() {
+            return;
+        }
+        self.signal(libc::SIGKILL);
+        self.try_wait();
+    }
+}
+
+/// `waitpid(pid, flags)`, returning the exit code (128 + signal if killed),
+/// or `None` if `pid` hasn't exited.
+fn waitpid(pid: libc::pid_t, flags: libc::c_int) -> Option<i32> {
+    let mut status = 0;
+    // SAFETY: plain waitpid on our own child.
+    if unsafe { libc::waitpid(pid, &mut status, flags) } != pid {
+        return None;
+    }
+    Some(if libc::WIFEXITED(status) {
+        libc::WEXITSTATUS(status)
+    } else {
+        128 + libc::WTERMSIG(status)
+    })
+}
+
+/// The login shell for `mosh-client server` without a command: `$SHELL`,
+/// else `/bin/sh`, with a leading `-` in argv[0] as login(1) does.
+pub fn login_shell() -> (PathBuf, Vec<String>) {
+    let shell = std::env::var("SHELL")
+        .ok()
+        .filter(|s| !s.is_empty())
+        .unwrap_or_else(|| "/bin/sh".to_string());
+    let name = shell.rsplit('/').next().unwrap_or(&shell);
+    let argv0 = format!("-{}", name);
+    (PathBuf::from(shell), vec![argv0])
+}
+
+/// Find `program` the way a shell would: as given if it has a slash,
+/// otherwise in `$PATH`.
+pub fn find_program(program: &str) -> Result<PathBuf> {
+    if program.contains('/') {
+        return Ok(PathBuf::from(program));
+    }
+    let path = std::env::var_os("PATH").unwrap_or_else(|| "/usr/bin:/bin".into());
+    for dir in std::env::split_paths(&path) {
+        let candidate = dir.join(program);
+        if is_executable(&candidate) {
+            return Ok(candidate);
+        }
+    }
+    bail!("{}: command not found", program)
+}
+
+fn is_executable(path: &Path) -> bool {
+    match c_string(path.as_os_str()) {
+        Ok(c) => path.is_file() && unsafe { libc::access(c.as_ptr(), libc::X_OK) } == 0,
+        Err(_) => false,
+    }
+}
+
+fn c_string(s: &OsStr) -> Result<CString> {
+    CString::new(s.as_bytes()).with_context(|| format!("{:?} contains a NUL byte", s))
+}
+
+fn winsize(width: u16, height: u16) -> libc::winsize {
+    libc::winsize { ws_row: height, ws_col: width, ws_xpixel: 0, ws_ypixel: 0 }
+}
+
+fn set_nonblocking(fd: &OwnedFd) -> Result<()> {
+    // SAFETY: fcntl on a descriptor we own.
+    unsafe {
+        let flags = libc::fcntl(fd.as_raw_fd(), libc::F_GETFL);
+        if flags < 0 || libc::fcntl(fd.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 {
+            return Err(io::Error::last_os_error()).context("Failed to make pty non-blocking");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    async fn read_until(pty: &Pty, needle: &str) -> String {
+        let mut output = Vec::new();
+        let mut buf = [0u8; 1024];
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+        while !String::from_utf8_lossy(&output).contains(needle) {
+            let n = tokio::time::timeout_at(deadline, pty.read(&mut buf))
+                .await
+                .expect("pty output in time")
+                .unwrap();
+            assert_ne!(n, 0, "pty closed early: {:?}", String::from_utf8_lossy(&output));
+            output.extend_from_slice(&buf[..n]);
+        }
+        String::from_utf8_lossy(&output).into_owned()
+    }
+
+    #[tokio::test]
+    async fn test_shell_on_pty() {
+        let sh = find_program("sh").unwrap();
+        let argv = ["sh", "-c", "stty size; read line; echo \"got $line\""].map(String::from);
+        let env = [("PATH".into(), std::env::var_os("PATH").unwrap_or_default())];
+        let pty = Pty::spawn(&sh, &argv, &env, 100, 30).unwrap();
+
+        assert!(read_until(&pty, "30 100").await.contains("30 100"));
+        pty.write_all(b"hello\r").await.unwrap();
+        read_until(&pty, "got hello").await;
+
+        // The shell exits; reads end rather than fail.
+        let mut buf = [0u8; 1024];
+        while pty.read(&mut buf).await.unwrap() != 0 {}
+    }
+
+    #[tokio::test]
+    async fn test_non_unicode_environment_reaches_the_child() {
+        use std::os::unix::ffi::OsStringExt as _;
+        let sh = find_program("sh").unwrap();
+        let argv = ["sh", "-c", "printf '<%s>' \"$NAME\"; read line"].map(String::from);
+        let env = [("NAME".into(), OsString::from_vec(b"caf\xe9".to_vec()))];
+        let pty = Pty::spawn(&sh, &argv, &env, 80, 24).unwrap();
+        assert!(read_until(&pty, ">").await.contains("<caf\u{fffd}>"));
+    }
+
+    /// Whether `pid` is gone for good: exited and reaped.
+    fn reaped(pid: libc::pid_t) -> bool {
+        // SAFETY: signal 0 only checks that the process exists.
+        unsafe { libc::kill(pid, 0) != 0 && io::Error::last_os_error().raw_os_error() == Some(libc::ESRCH) }
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_reaps_the_child() {
+        let sh = find_program("sh").unwrap();
+        let hangs_up = ["sh", "-c", "echo ready; exec sleep 30"].map(String::from);
+        let pty = Pty::spawn(&sh, &hangs_up, &[], 80, 24).unwrap();
+        read_until(&pty, "ready").await;
+        let start = Instant::now();
+        pty.shutdown().await;
+        assert!(start.elapsed() < HANGUP_GRACE);
+        assert_eq!(pty.try_wait(), Some(128 + libc::SIGHUP));
+        let pid = pty.pid;
+        drop(pty);
+        assert!(reaped(pid));
+
+        // Ignored signals survive exec, so this one only goes with SIGKILL.
+        let ignores_hup = ["sh", "-c", "trap '' HUP; echo ready; exec sleep 30"].map(String::from);
+        let pty = Pty::spawn(&sh, &ignores_hup, &[], 80, 24).unwrap();
+        read_until(&pty, "ready").await;
+        let start = Instant::now();
+        pty.shutdown().await;
+        assert!(start.elapsed() >= HANGUP_GRACE);
+        assert_eq!(pty.try_wait(), Some(128 + libc::SIGKILL));
+    }
+
+    #[tokio::test]
+    async fn test_drop_kills_without_waiting() {
+        let sh = find_program("sh").unwrap();
+        let ignores_hup = ["sh", "-c", "trap '' HUP; echo ready; exec sleep 30"].map(String::from);
+        let pty = Pty::spawn(&sh, &ignores_hup, &[], 80, 24).unwrap();
+        read_until(&pty, "ready").await;
+        let pid = pty.pid;
+        let start = Instant::now();
+        drop(pty);
+        assert!(start.elapsed() < HANGUP_GRACE);
+        // Reaped by the drop, or left for us as a killed zombie.
+        assert!(matches!(waitpid(pid, 0), None | Some(137)));
+        assert!(reaped(pid));
+    }
+
+    #[test]
+    fn test_login_shell_and_lookup() {
+        let (_, argv) = login_shell();
+        assert!(argv[0].starts_with('-'));
+        assert!(find_program("sh").unwrap().is_absolute());
+        assert!(find_program("no-such-program-here").is_err());
+        assert_eq!(find_program("./x").unwrap(), PathBuf::from("./x"));
+    }
+}
@@ -0,0 +1,471 @@
+//! `--record`: a log of every transport instruction, for bug reports.
+//!
+//! A recording is JSON lines. The first is a `header` naming the format,
+//! its version and the initial window size; each later line is one
+//! decrypted, decompressed `TransportInstruction`, sent or received, with
+//! its state numbers, its diff in base64 and the milliseconds since
+//! recording started. Chaff is left out.
+//!
+//! `mosh-client replay FILE` feeds the received instructions back through
+//! `RemoteState::apply_string`, accepting and discarding states the way the
+//! transport does, and prints the screen they produce.
+
+use crate::display::repaint;
+use crate::jsonlines::JsonLines;
+use crate::status::ExitStatus;
+use crate::terminal::Framebuffer;
+use crate::transport::proto::transportinstruction::Instruction;
+use crate::transport::RemoteState;
+use anyhow::{bail, Context, Result};
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, Write as _};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use tokio::time::Instant;
+
+/// Written in every header; replay refuses anything else.
+pub const FORMAT_NAME: &str = "mosh-client-recording";
+/// Bumped whenever a reader of the previous version would misread a file.
+pub const FORMAT_VERSION: u32 = 1;
+
+// ── Format ─────────────────────────────────────────────────────────────────
+
+/// One line of a recording.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Record {
+    Header {
+        format: String,
+        version: u32,
+        width: usize,
+        height: usize,
+    },
+    Instruction(RecordedInstruction),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Flow {
+    Sent,
+    Received,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordedInstruction {
+    pub t_ms: u64,
+    pub flow: Flow,
+    pub old_num: u64,
+    pub new_num: u64,
+    pub ack_num: u64,
+    pub throwaway_num: u64,
+    #[serde(with = "base64_bytes")]
+    pub diff: Vec<u8>,
+}
+
+mod base64_bytes {
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine as _;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&STANDARD.encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
+        let text = String::deserialize(d)?;
+        STANDARD.decode(text).map_err(serde::de::Error::custom)
+    }
+}
+
+// ── Recorder ───────────────────────────────────────────────────────────────
+
+/// Writes a recording as the session runs.
+pub struct Recorder {
+    out: JsonLines,
+    start: Instant,
+}
+
+impl Recorder {
+    /// Create (or truncate) `path`. Nothing is written until `header`.
+    ///
+    /// The recording holds everything typed, passwords included, so on Unix
+    /// it is only readable by its owner, even if it already existed.
+    pub fn create(path: &Path) -> Result<Self> {
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let file = options
+            .open(path)
+            .with_context(|| format!("Failed to create recording {}", path.display()))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt as _;
+            file.set_permissions(std::fs::Permissions::from_mode(0o600))
+                .with_context(|| format!("Failed to restrict recording {}", path.display()))?;
+        }
+        Ok(Self { out: JsonLines::new(file, "recording"), start: Instant::now() })
+    }
+
+    /// Start the recording: the clock starts here.
+    pub fn header(&mut self, width: usize, height: usize) {
+        self.start = Instant::now();
+        self.out.write(&Record::Header {
+            format: FORMAT_NAME.to_string(),
+            version: FORMAT_VERSION,
+            width,
+            height,
+        });
+    }
+
+    pub fn instruction(&mut self, flow: Flow, ti: &Instruction) {
+        self.out.write(&Record::Instruction(RecordedInstruction {
+            t_ms: self.start.elapsed().as_millis() as u64,
+            flow,
+            old_num: ti.old_num.unwrap_or_default(),
+            new_num: ti.new_num.unwrap_or_default(),
+            ack_num: ti.ack_num.unwrap_or_default(),
+            throwaway_num: ti.throwaway_num.unwrap_or_default(),
+            diff: ti.diff.clone().unwrap_or_default(),
+        }));
+    }
+}
+
+// ── Replay ─────────────────────────────────────────────────────────────────
+
+/// A recording read back: the initial size and every instruction.
+#[derive(Debug)]
+pub struct Recording {
+    pub width: usize,
+    pub height: usize,
+    pub instructions: Vec<RecordedInstruction>,
+}
+
+impl Recording {
+    pub fn read(path: &Path) -> Result<Self> {
+        let file = std::fs::File::open(path)
+            .with_context(|| format!("Failed to open recording {}", path.display()))?;
+        Self::parse(BufReader::new(file))
+    }
+
+    pub fn parse(input: impl BufRead) -> Result<Self> {
+        let mut lines = input.lines().enumerate().filter(|(_, l)| !matches!(l, Ok(l) if l.trim().is_empty()));
+        let (width, height) = match lines.next() {
+            Some((_, line)) => match serde_json::from_str(&line.context("Failed to read recording")?) {
+                Ok(Record::Header { format, version, width, height }) => {
+                    if format != FORMAT_NAME {
+                        bail!("not a mosh-client recording (format '{}')", format);
+                    }
+                    if version != FORMAT_VERSION {
+                        bail!(
+                            "recording format version {} is not supported (this build reads version {})",
+                            version,
+                            FORMAT_VERSION
+                        );
+                    }
+                    (width, height)
+                }
+                _ => bail!("not a mosh-client recording (no header on line 1)"),
+            },
+            None => bail!("recording is empty"),
+        };
+
+        let mut instructions = Vec::new();
+        for (i, line) in lines {
+            let line = line.context("Failed to read recording")?;
+            match serde_json::from_str(&line).with_context(|| format!("line {} of the recording", i + 1))? {
+                Record::Instruction(ri) => instructions.push(ri),
+                Record::Header { .. } => bail!("line {} of the recording: unexpected header", i + 1),
+            }
+        }
+        Ok(Self { width, height, instructions })
+    }
+}
+
+/// The client's receiver queue, rebuilt from recorded instructions.
+pub struct Replayer {
+    states: Vec<(u64, RemoteState)>,
+}
+
+impl Replayer {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self { states: vec![(0, RemoteState::new(width, height))] }
+    }
+
+    /// Take a received instruction as `Transport` would. Returns whether it
+    /// made a new state; duplicates and states whose base is gone don't.
+    pub fn apply(&mut self, ri: &RecordedInstruction) -> Result<bool> {
+        if self.states.iter().any(|(num, _)| *num == ri.new_num) {
+            return Ok(false);
+        }
+        let Some((_, base)) = self.states.iter().find(|(num, _)| *num == ri.old_num) else {
+            return Ok(false);
+        };
+        let mut state = base.clone();
+        self.states.retain(|(num, _)| *num >= ri.throwaway_num);
+        state
+            .apply_string(&ri.diff)
+            .with_context(|| format!("state {} (from {})", ri.new_num, ri.old_num))?;
+        let at = self.states.partition_point(|(num, _)| *num < ri.new_num);
+        self.states.insert(at, (ri.new_num, state));
+        Ok(true)
+    }
+
+    /// The newest state number and its screen.
+    pub fn latest(&self) -> (u64, &Framebuffer) {
+        let (num, state) = self.states.last().expect("replayer queue is never empty");
+        (*num, state.framebuffer())
+    }
+}
+
+/// Replay a `--record` recording and print the screen it produces.
+#[derive(Parser, Debug)]
+pub struct ReplayCli {
+    /// Recording written by --record.
+    #[arg(value_name = "FILE")]
+    file: PathBuf,
+
+    /// Stop once this state number has been applied.
+    #[arg(long, value_name = "NUM")]
+    until: Option<u64>,
+
+    /// Print the screen after every new state, not just at the end.
+    #[arg(long)]
+    frames: bool,
+
+    /// Print escape sequences that redraw each screen instead of plain text.
+    #[arg(long)]
+    ansi: bool,
+}
+
+/// Entry point for `mosh-client replay ...`.
+pub fn main(cli: ReplayCli) -> ExitCode {
+    let mut out = std::io::stdout().lock();
+    match replay(&cli, &mut out) {
+        Ok(()) => ExitCode::from(ExitStatus::Success.code()),
+        Err(err) => {
+            let _ = out.flush();
+            eprintln!("mosh-client replay: {:#}", err);
+            ExitCode::from(ExitStatus::of(&err).code())
+        }
+    }
+}
+
+fn replay(cli: &ReplayCli, out: &mut impl std::io::Write) -> Result<()> {
+    let recording = Recording::read(&cli.file)?;
+    let mut replayer = Replayer::new(recording.width, recording.height);
+    for ri in recording.instructions.iter().filter(|ri| ri.flow == Flow::Received) {
+        if !replayer.apply(ri)? {
+            continue;
+        }
+        if cli.frames {
+            let (num, fb) = replayer.latest();
+            writeln!(out, "── state {} at {} ms ──", num, ri.t_ms)?;
+            print_screen(out, fb, cli.ansi)?;
+        }
+        if cli.until.is_some_and(|until| ri.new_num >= until) {
+            break;
+        }
+    }
+    if !cli.frames {
+        print_screen(out, replayer.latest().1, cli.ansi)?;
+    }
+    Ok(())
+}
+
+fn print_screen(out: &mut impl std::io::Write, fb: &Framebuffer, ansi: bool) -> Result<()> {
+    if ansi {
+        out.write_all(&repaint(fb))?;
+        out.write_all(b"\x1b[0m\r\n")?;
+    } else {
+        for row in &fb.cells {
+            let text: String = row.iter().map(|c| c.character).collect();
+            writeln!(out, "{}", text.trim_end())?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scratch::ScratchFile;
+    use crate::transport::proto::hostinput;
+    use prost::Message;
+
+    fn host_bytes(data: &[u8]) -> Vec<u8> {
+        hostinput::HostMessage {
+            instruction: vec![hostinput::Instruction {
+                hostbytes: Some(hostinput::HostBytes { hoststring: Some(data.to_vec()) }),
+                ..Default::default()
+            }],
+        }
+        .encode_to_vec()
+    }
+
+    fn received(old_num: u64, new_num: u64, throwaway_num: u64, data: &[u8]) -> RecordedInstruction {
+        RecordedInstruction {
+            t_ms: new_num * 10,
+            flow: Flow::Received,
+            old_num,
+            new_num,
+            ack_num: 0,
+            throwaway_num,
+            diff: host_bytes(data),
+        }
+    }
+
+    fn row(fb: &Framebuffer, r: usize) -> String {
+        fb.cells[r].iter().map(|c| c.character).collect::<String>().trim_end().to_string()
+    }
+
+    #[test]
+    fn test_record_round_trip() {
+        let file = ScratchFile::new("record.jsonl");
+        let path = file.path();
+        let mut recorder = Recorder::create(path).unwrap();
+        recorder.header(80, 24);
+        let ti = Instruction {
+            protocol_version: Some(2),
+            old_num: Some(0),
+            new_num: Some(1),
+            ack_num: Some(3),
+            throwaway_num: Some(0),
+            diff: Some(host_bytes(b"hi")),
+            chaff: Some(vec![1, 2, 3]),
+        };
+        recorder.instruction(Flow::Received, &ti);
+        recorder.instruction(Flow::Sent, &Instruction { diff: None, ..ti.clone() });
+        drop(recorder);
+
+        let text = std::fs::read_to_string(path).unwrap();
+        let first: serde_json::Value = serde_json::from_str(text.lines().next().unwrap()).unwrap();
+        assert_eq!(first["type"], "header");
+        assert_eq!(first["version"], FORMAT_VERSION);
+        assert!(!text.contains("chaff"));
+
+        let recording = Recording::parse(text.as_bytes()).unwrap();
+        assert_eq!((recording.width, recording.height), (80, 24));
+        assert_eq!(recording.instructions.len(), 2);
+        let ri = &recording.instructions[0];
+        assert_eq!((ri.flow, ri.old_num, ri.new_num, ri.ack_num), (Flow::Received, 0, 1, 3));
+        assert_eq!(ri.diff, host_bytes(b"hi"));
+        assert_eq!(recording.instructions[1].flow, Flow::Sent);
+        assert!(recording.instructions[1].diff.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_recording_is_private() {
+        use std::os::unix::fs::PermissionsExt as _;
+        let file = ScratchFile::new("private.jsonl");
+        let path = file.path();
+        std::fs::write(path, "old").unwrap();
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        let recorder = Recorder::create(path).unwrap();
+        drop(recorder);
+        let meta = std::fs::metadata(path).unwrap();
+        assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+        assert_eq!(meta.len(), 0);
+    }
+
+    #[test]
+    fn test_parse_rejects_other_versions() {
+        let future = format!(
+            "{{\"type\":\"header\",\"format\":\"{}\",\"version\":{},\"width\":80,\"height\":24}}\n",
+            FORMAT_NAME,
+            FORMAT_VERSION + 1
+        );
+        let err = Recording::parse(future.as_bytes()).unwrap_err();
+        assert!(format!("{:#}", err).contains("not supported"), "{:#}", err);
+        assert!(Recording::parse(&b"{\"type\":\"header\"}\n"[..]).is_err());
+        assert!(Recording::parse(&b""[..]).is_err());
+    }
+
+    #[test]
+    fn test_replayer_follows_the_receiver_queue() {
+        let mut replayer = Replayer::new(20, 5);
+        assert!(replayer.apply(&received(0, 1, 0, b"one\r\n")).unwrap());
+        // Out of order: 3 is based on 2, which hasn't arrived.
+        assert!(!replayer.apply(&received(2, 3, 0, b"three")).unwrap());
+        assert!(replayer.apply(&received(1, 2, 1, b"two\r\n")).unwrap());
+        // A retransmission of 2 is a duplicate.
+        assert!(!replayer.apply(&received(1, 2, 1, b"two\r\n")).unwrap());
+        // State 1 was thrown away, so nothing can build on it any more.
+        assert!(replayer.apply(&received(2, 3, 2, b"three")).unwrap());
+        assert!(!replayer.apply(&received(1, 4, 2, b"stale")).unwrap());
+
+        let (num, fb) = replayer.latest();
+        assert_eq!(num, 3);
+        assert_eq!([row(fb, 0), row(fb, 1), row(fb, 2)], ["one", "two", "three"]);
+    }
+
+    #[tokio::test]
+    async fn test_replay_reproduces_a_session() {
+        use crate::crypto::{Base64Key, Direction};
+        use crate::display::same_frame;
+        use crate::testserver::{exchange, settle, HostSource, TestServer};
+        use crate::transport::Transport;
+
+        let file = ScratchFile::new("session.jsonl");
+        let path = file.path();
+        let key = Base64Key::from_str("AAAAAAAAAAAAAAAAAAAAAA").unwrap();
+        let mut server = TestServer::bind(&key, HostSource::Echo, 80, 24).await.unwrap();
+        let mut client = Transport::new(&key, server.local_addr(), Direction::ToServer, 80, 24)
+            .await
+            .unwrap();
+        client.record_to(Recorder::create(path).unwrap());
+        client.push_resize(60, 10);
+        for i in 0..30 {
+            client.push_user_input(format!("line {}\r", i).as_bytes());
+            for _ in 0..5 {
+                exchange(&mut client, &mut server).await;
+            }
+        }
+        settle(&mut client, &mut server).await;
+
+        let recording = Recording::read(path).unwrap();
+        assert!(recording.instructions.iter().any(|ri| ri.flow == Flow::Sent));
+        let mut replayer = Replayer::new(recording.width, recording.height);
+        for ri in recording.instructions.iter().filter(|ri| ri.flow == Flow::Received) {
+            replayer.apply(ri).unwrap();
+        }
+        assert!(same_frame(replayer.latest().1, client.latest_remote_framebuffer()));
+        assert_eq!(row(replayer.latest().1, 8), "line 29");
+    }
+
+    #[test]
+    fn test_replay_prints_screens() {
+        let file = ScratchFile::new("replay.jsonl");
+        let path = file.path();
+        let mut recorder = Recorder::create(path).unwrap();
+        recorder.header(20, 3);
+        for (n, text) in ["a\r\n", "b\r\n", "c"].iter().enumerate() {
+            let n = n as u64;
+            let ti = Instruction {
+                old_num: Some(n),
+                new_num: Some(n + 1),
+                throwaway_num: Some(n),
+                diff: Some(host_bytes(text.as_bytes())),
+                ..Default::default()
+            };
+            recorder.instruction(Flow::Received, &ti);
+        }
+        drop(recorder);
+
+        let run = |args: &[&str]| {
+            let mut argv = vec!["mosh-client replay".to_string(), path.display().to_string()];
+            argv.extend(args.iter().map(|a| a.to_string()));
+            let mut out = Vec::new();
+            replay(&ReplayCli::try_parse_from(argv).unwrap(), &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(run(&[]), "a\nb\nc\n");
+        assert_eq!(run(&["--until", "2"]), "a\nb\n\n");
+        let frames = run(&["--frames"]);
+        assert_eq!(frames.matches("── state").count(), 3);
+        assert!(frames.contains("── state 2 at "));
+        assert!(run(&["--ansi"]).starts_with("\x1b[r"));
+    }
+}
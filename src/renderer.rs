@@ -74,11 +74,6 @@ impl Renderer {
         self.force_redraw = true;
     }
 
-    /// Force a full redraw on the next render call.
-    pub fn force_redraw(&mut self) {
-        self.force_redraw = true;
-    }
-
     /// Render the framebuffer to the terminal, only updating changed cells.
     pub fn render(&mut self, fb: &Framebuffer) -> io::Result<()> {
         let mut stdout = io::stdout();
@@ -270,6 +265,8 @@ impl Renderer {
 pub struct NotificationBar {
     message: String,
     visible: bool,
+    /// Extra rows under the message, e.g. the stats overlay.
+    detail: Vec<String>,
 }
 
 impl NotificationBar {
@@ -277,6 +274,7 @@ impl NotificationBar {
         Self {
             message: String::new(),
             visible: false,
+            detail: Vec::new(),
         }
     }
 
@@ -285,32 +283,49 @@ impl NotificationBar {
         self.visible = !msg.is_empty();
     }
 
+    /// Clear the message; detail rows stay until `set_detail` replaces them.
     pub fn clear(&mut self) {
         self.message.clear();
         self.visible = false;
     }
 
+    pub fn set_detail(&mut self, lines: Vec<String>) {
+        self.detail = lines;
+    }
+
     pub fn apply(&self, fb: &mut Framebuffer) {
-        if !self.visible || fb.height == 0 || fb.width == 0 {
+        if fb.height == 0 || fb.width == 0 {
             return;
         }
 
-        if fb.cursor_row == 0 {
+        let message = self.visible.then_some(self.message.as_str());
+        let rows: Vec<(&str, bool)> = message
+            .map(|m| (m, true))
+            .into_iter()
+            .chain(self.detail.iter().map(|d| (d.as_str(), false)))
+            .take(fb.height)
+            .collect();
+        if rows.is_empty() {
+            return;
+        }
+
+        if fb.cursor_row < rows.len() {
             fb.cursor_visible = false;
         }
 
-        let msg_chars: Vec<char> = self.message.chars().take(fb.width).collect();
-
-        for col in 0..fb.width {
-            let cell = &mut fb.cells[0][col];
-            cell.fg = Color::Indexed(7);
-            cell.bg = Color::Indexed(4);
-            cell.attrs = Attributes { bold: true, ..Attributes::default() };
-            cell.character = if col < msg_chars.len() {
-                msg_chars[col]
-            } else {
-                ' '
-            };
+        for (row, (text, bold)) in rows.into_iter().enumerate() {
+            let msg_chars: Vec<char> = text.chars().take(fb.width).collect();
+            for col in 0..fb.width {
+                let cell = &mut fb.cells[row][col];
+                cell.fg = Color::Indexed(7);
+                cell.bg = Color::Indexed(4);
+                cell.attrs = Attributes { bold, ..Attributes::default() };
+                cell.character = if col < msg_chars.len() {
+                    msg_chars[col]
+                } else {
+                    ' '
+                };
+            }
         }
     }
 }
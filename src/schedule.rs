@@ -0,0 +1,132 @@
+//! Wake-up scheduling for the session loops.
+//!
+//! The client's loop sleeps until a packet arrives, the terminal has an
+//! event, or the earliest of its deadlines passes. Each part that has
+//! something to do later (the transport's next send or ack, a pending frame,
+//! the stall notice) adds its deadline to a `Deadlines`, so an idle session
+//! only wakes for the transport's 3-second heartbeat. The server's loop
+//! waits on its socket, the pty and its own deadlines the same way.
+
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// The earliest of a set of optional deadlines.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Deadlines {
+    earliest: Option<Instant>,
+}
+
+impl Deadlines {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, deadline: Option<Instant>) {
+        if let Some(deadline) = deadline {
+            self.earliest = Some(self.earliest.map_or(deadline, |e| e.min(deadline)));
+        }
+    }
+
+    pub fn earliest(&self) -> Option<Instant> {
+        self.earliest
+    }
+
+    /// Sleep until the earliest deadline; never returns if there is none.
+    pub async fn sleep(&self) {
+        match self.earliest() {
+            Some(deadline) => tokio::time::sleep_until(deadline).await,
+            None => std::future::pending().await,
+        }
+    }
+}
+
+/// Decides when to draw: a change after a quiet spell is drawn at once,
+/// and a burst of changes at most once per `interval`.
+#[derive(Debug)]
+pub struct RenderScheduler {
+    interval: Duration,
+    last: Option<Instant>,
+    dirty: bool,
+}
+
+impl RenderScheduler {
+    pub fn new(interval: Duration) -> Self {
+        Self { interval, last: None, dirty: true }
+    }
+
+    /// Something on screen changed.
+    pub fn mark_dirty(&mut self) {
+        self.dirty = true;
+    }
+
+    /// When the next frame should be drawn, if one is needed.
+    pub fn deadline(&self) -> Option<Instant> {
+        if !self.dirty {
+            return None;
+        }
+        Some(self.last.map_or_else(Instant::now, |last| last + self.interval))
+    }
+
+    pub fn due(&self, now: Instant) -> bool {
+        self.dirty && self.last.is_none_or(|last| now >= last + self.interval)
+    }
+
+    pub fn rendered(&mut self, now: Instant) {
+        self.last = Some(now);
+        self.dirty = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deadlines_pick_the_earliest() {
+        let now = Instant::now();
+        let mut deadlines = Deadlines::new();
+        assert_eq!(deadlines.earliest(), None);
+        deadlines.add(None);
+        deadlines.add(Some(now + Duration::from_millis(30)));
+        deadlines.add(Some(now + Duration::from_millis(10)));
+        deadlines.add(None);
+        deadlines.add(Some(now + Duration::from_millis(20)));
+        assert_eq!(deadlines.earliest(), Some(now + Duration::from_millis(10)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sleep_without_deadlines_waits_forever() {
+        let never = Deadlines::new();
+        assert!(tokio::time::timeout(Duration::from_secs(3600), never.sleep()).await.is_err());
+
+        let mut soon = Deadlines::new();
+        soon.add(Some(Instant::now() + Duration::from_millis(5)));
+        let start = Instant::now();
+        soon.sleep().await;
+        assert_eq!(start.elapsed(), Duration::from_millis(5));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_render_scheduler_paces_bursts() {
+        let interval = Duration::from_millis(16);
+        let mut render = RenderScheduler::new(interval);
+        // The first frame is drawn straight away.
+        assert!(render.due(Instant::now()));
+        render.rendered(Instant::now());
+        assert_eq!(render.deadline(), None);
+
+        // A change right after a frame waits out the interval...
+        tokio::time::advance(Duration::from_millis(4)).await;
+        render.mark_dirty();
+        assert!(!render.due(Instant::now()));
+        assert_eq!(render.deadline(), Some(Instant::now() + Duration::from_millis(12)));
+        tokio::time::advance(Duration::from_millis(12)).await;
+        assert!(render.due(Instant::now()));
+        render.rendered(Instant::now());
+
+        // ...but one after a quiet spell doesn't.
+        tokio::time::advance(Duration::from_secs(1)).await;
+        render.mark_dirty();
+        assert!(render.due(Instant::now()));
+    }
+}
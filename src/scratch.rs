@@ -0,0 +1,26 @@
+//! Scratch files for tests: a unique path in the temp directory, removed
+//! when the guard is dropped, even if the test fails.
+
+use std::path::{Path, PathBuf};
+
+pub struct ScratchFile(PathBuf);
+
+impl ScratchFile {
+    /// A path for `name` (e.g. "record.jsonl") that no other test process
+    /// uses. Nothing is created; a leftover from an earlier run is removed.
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("mosh-client-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        Self(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for ScratchFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
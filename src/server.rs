@@ -0,0 +1,898 @@
+//! `mosh-client server`: the host end of a mosh session.
+//!
+//! Started over SSH the same way as mosh-server, and answering the same way:
+//! it binds a UDP port, prints `MOSH CONNECT <port> <key>`, detaches, and
+//! runs a shell on a pty. `ServerTransport` speaks SSP as
+//! `Direction::ToClient`, keeping the shell's screen in a `Terminal` and
+//! sending clients the escape sequences that bring theirs up to date.
+
+use crate::status::ExitStatus;
+use anyhow::{bail, Result};
+use clap::Parser;
+use std::net::IpAddr;
+use std::process::ExitCode;
+
+// The transport is only driven by `serve`, and by tests on every platform.
+#[cfg(any(unix, test))]
+use {
+    crate::crypto::{make_nonce, parse_nonce, Base64Key, Direction, Session},
+    crate::display::{new_frame, same_frame},
+    crate::network::{
+        max_frag_payload, timestamp_since, Fragment, FragmentAssembly, Fragmenter, Packet, DEFAULT_MTU,
+    },
+    crate::terminal::{Framebuffer, Terminal},
+    crate::transport::proto::{hostinput, transportinstruction::Instruction},
+    crate::transport::{zlib_compress, zlib_decompress, Link, MOSH_PROTOCOL_VERSION},
+    crate::userstream::{UserEvent, UserStream},
+    anyhow::Context,
+    prost::Message,
+    std::collections::{BTreeMap, VecDeque},
+    std::net::SocketAddr,
+    std::time::Duration,
+    tokio::net::UdpSocket,
+    tokio::time::Instant,
+};
+#[cfg(unix)]
+use {crate::schedule::Deadlines, std::ffi::OsString, std::net::Ipv4Addr};
+
+/// Minimum gap between new states.
+#[cfg(any(unix, test))]
+const SEND_INTERVAL: Duration = Duration::from_millis(20);
+/// Resend unacknowledged states this often.
+#[cfg(any(unix, test))]
+const RETRY_INTERVAL: Duration = Duration::from_millis(200);
+/// Heartbeat when nothing else is sent.
+#[cfg(any(unix, test))]
+const ACK_INTERVAL: Duration = Duration::from_millis(3000);
+/// How long keystrokes get to show up in the shell's output before the
+/// client is told they were echoed, as mosh-server does.
+#[cfg(any(unix, test))]
+const ECHO_TIMEOUT: Duration = Duration::from_millis(50);
+/// Give up if no client shows up within this long of starting.
+#[cfg(unix)]
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long to wait for the client to acknowledge that the shell exited.
+#[cfg(unix)]
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Ports tried when `-p` isn't given, as mosh-server does.
+#[cfg(unix)]
+const DEFAULT_PORTS: (u16, u16) = (60001, 60999);
+
+/// Host end of a mosh session, started over SSH by a mosh client.
+#[derive(Parser, Debug)]
+pub struct ServerCli {
+    /// Accepted for mosh-server compatibility: every run starts a new session.
+    #[arg(value_name = "new", value_parser = ["new"])]
+    new: Option<String>,
+
+    /// Bind to the server address of the SSH connection that started us.
+    #[arg(short = 's')]
+    bind_ssh_address: bool,
+
+    /// Number of colours the client's terminal has (picks TERM).
+    #[arg(short = 'c', value_name = "COLORS")]
+    colors: Option<u32>,
+
+    /// Local address to bind.
+    #[arg(short = 'i', value_name = "ADDR")]
+    ip: Option<IpAddr>,
+
+    /// UDP port or range to listen on (default: 60001:60999).
+    #[arg(short = 'p', value_name = "PORT[:PORT2]", value_parser = crate::parse_port_spec)]
+    port: Option<String>,
+
+    /// Set an environment variable for the shell (repeatable).
+    #[arg(short = 'l', value_name = "NAME=VALUE", value_parser = crate::parse_env_var)]
+    env: Vec<(String, String)>,
+
+    /// Log protocol details to stderr; implies --foreground.
+    #[arg(short = 'v')]
+    verbose: bool,
+
+    /// End the session after this many seconds without hearing from the
+    /// client (default: $MOSH_SERVER_NETWORK_TMOUT, else never).
+    #[arg(long, value_name = "SECS")]
+    idle_timeout: Option<u64>,
+
+    /// Stay attached to the terminal instead of detaching.
+    #[arg(long)]
+    foreground: bool,
+
+    /// Command to run instead of the login shell.
+    #[arg(last = true, value_name = "COMMAND")]
+    command: Vec<String>,
+}
+
+#[cfg(unix)]
+impl ServerCli {
+    fn idle_timeout(&self) -> Result<Option<Duration>> {
+        let secs = match self.idle_timeout {
+            Some(secs) => Some(secs),
+            None => match std::env::var("MOSH_SERVER_NETWORK_TMOUT") {
+                Ok(value) if !value.is_empty() => Some(
+                    value
+                        .parse()
+                        .with_context(|| format!("invalid MOSH_SERVER_NETWORK_TMOUT '{}'", value))?,
+                ),
+                _ => None,
+            },
+        };
+        Ok(secs.filter(|&s| s > 0).map(Duration::from_secs))
+    }
+
+    /// The address to bind, from `-i` or `-s`.
+    fn bind_ip(&self) -> Result<IpAddr> {
+        if let Some(ip) = self.ip {
+            return Ok(ip);
+        }
+        if self.bind_ssh_address {
+            let conn = std::env::var("SSH_CONNECTION").context("-s given but SSH_CONNECTION is not set")?;
+            return ssh_server_address(&conn);
+        }
+        Ok(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+    }
+
+    fn term(&self) -> &'static str {
+        match self.colors {
+            Some(c) if c >= 256 => "xterm-256color",
+            _ => "xterm",
+        }
+    }
+}
+
+/// The server address in `SSH_CONNECTION` ("client port server port").
+#[cfg(unix)]
+fn ssh_server_address(conn: &str) -> Result<IpAddr> {
+    let field = conn
+        .split_whitespace()
+        .nth(2)
+        .with_context(|| format!("malformed SSH_CONNECTION '{}'", conn))?;
+    // A v4 client on a dual-stack socket shows up as ::ffff:a.b.c.d
+    let field = field.strip_prefix("::ffff:").filter(|v4| v4.contains('.')).unwrap_or(field);
+    field
+        .parse()
+        .with_context(|| format!("invalid server address '{}' in SSH_CONNECTION", field))
+}
+
+/// Bind the first free port in `low..=high`.
+#[cfg(unix)]
+fn bind_port(ip: IpAddr, (low, high): (u16, u16)) -> Result<std::net::UdpSocket> {
+    let mut last_err = None;
+    for port in low..=high {
+        match std::net::UdpSocket::bind((ip, port)) {
+            Ok(socket) => return Ok(socket),
+            Err(e) => last_err = Some(e),
+        }
+    }
+    let err = last_err.expect("port ranges are never empty");
+    Err(err).with_context(|| format!("Failed to bind {} on ports {}:{}", ip, low, high))
+}
+
+/// Entry point for `mosh-client server ...`.
+pub fn main(cli: ServerCli) -> ExitCode {
+    let log_level = if cli.verbose { "debug" } else { "warn" };
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(log_level))
+        .format_timestamp_millis()
+        .init();
+
+    match run(cli) {
+        Ok(()) => ExitCode::from(ExitStatus::Success.code()),
+        Err(err) => {
+            eprintln!("mosh-client server: {:#}", err);
+            ExitCode::from(ExitStatus::of(&err).code())
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn run(_cli: ServerCli) -> Result<()> {
+    bail!("server mode needs a Unix host with pseudo-terminals")
+}
+
+#[cfg(unix)]
+fn run(cli: ServerCli) -> Result<()> {
+    let ports = match &cli.port {
+        Some(spec) => crate::ssh::parse_port_range(spec)?,
+        None => DEFAULT_PORTS,
+    };
+    let idle_timeout = cli.idle_timeout()?;
+    let socket = bind_port(cli.bind_ip()?, ports)?;
+    let port = socket.local_addr()?.port();
+    let key = Base64Key::generate();
+    let (width, height) = crossterm::terminal::size().unwrap_or((80, 24));
+
+    let (program, argv) = match cli.command.split_first() {
+        Some((program, _)) => (crate::pty::find_program(program)?, cli.command.clone()),
+        None => crate::pty::login_shell(),
+    };
+    // Inherited values pass through as they are, valid Unicode or not
+    let mut env: Vec<(OsString, OsString)> = std::env::vars_os()
+        .filter(|(name, _)| name != "TERM" && !cli.env.iter().any(|(n, _)| name == n.as_str()))
+        .collect();
+    env.extend(cli.env.iter().map(|(name, value)| (name.into(), value.into())));
+    env.push(("TERM".into(), cli.term().into()));
+
+    {
+        use std::io::Write;
+        let mut stdout = std::io::stdout();
+        write!(stdout, "\r\nMOSH CONNECT {} {}\r\n", port, key.to_base64().as_str())?;
+        stdout.flush()?;
+    }
+
+    if !(cli.foreground || cli.verbose) && detach()? {
+        return Ok(());
+    }
+
+    // A single-threaded runtime, so the pty's fork happens with no other
+    // threads around.
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .context("Failed to start the async runtime")?;
+    runtime.block_on(async {
+        let transport = ServerTransport::new(&key, socket, width as usize, height as usize)?;
+        let pty = crate::pty::Pty::spawn(&program, &argv, &env, width, height)?;
+        serve(transport, pty, idle_timeout).await
+    })
+}
+
+/// Fork into the background. Returns true in the parent, which should exit.
+#[cfg(unix)]
+fn detach() -> Result<bool> {
+    // SAFETY: no other threads exist yet; the child carries on as normal.
+    match unsafe { libc::fork() } {
+        -1 => Err(std::io::Error::last_os_error()).context("fork failed"),
+        0 => {
+            // SAFETY: plain syscalls on descriptors we own.
+            unsafe {
+                libc::setsid();
+                let null = libc::open(c"/dev/null".as_ptr(), libc::O_RDWR);
+                if null >= 0 {
+                    for fd in 0..=2 {
+                        libc::dup2(null, fd);
+                    }
+                    if null > 2 {
+                        libc::close(null);
+                    }
+                }
+            }
+            Ok(false)
+        }
+        pid => {
+            eprintln!("\r\nmosh-client server detached, pid = {}\r", pid);
+            Ok(true)
+        }
+    }
+}
+
+/// Relay between the client and the shell until one of them ends, then hang
+/// up on the shell.
+#[cfg(unix)]
+async fn serve(mut transport: ServerTransport, pty: crate::pty::Pty, idle_timeout: Option<Duration>) -> Result<()> {
+    let result = relay(&mut transport, &pty, idle_timeout).await;
+    pty.shutdown().await;
+    result
+}
+
+#[cfg(unix)]
+async fn relay(transport: &mut ServerTransport, pty: &crate::pty::Pty, idle_timeout: Option<Duration>) -> Result<()> {
+    log::debug!("Serving on {}", transport.local_addr());
+    let started = Instant::now();
+    let mut buf = vec![0u8; 16_384];
+    let mut shell_exited: Option<Instant> = None;
+
+    loop {
+        let mut wake = Deadlines::new();
+        wake.add(transport.next_wakeup());
+        wake.add(shell_exited.map(|at| at + CLOSE_TIMEOUT));
+        match transport.last_heard() {
+            None => wake.add(Some(started + CONNECT_TIMEOUT)),
+            Some(heard) => wake.add(idle_timeout.map(|t| heard + t)),
+        }
+
+        tokio::select! {
+            r = transport.readable() => r?,
+            n = pty.read(&mut buf), if shell_exited.is_none() => match n {
+                Ok(0) | Err(_) => {
+                    log::debug!("Shell exited ({:?}); closing session", pty.try_wait());
+                    shell_exited = Some(Instant::now());
+                    transport.close();
+                }
+                Ok(n) => transport.host_output(&buf[..n]),
+            },
+            _ = wake.sleep() => {}
+        }
+
+        transport.drain_recv()?;
+        let mut input = Vec::new();
+        for event in transport.take_user_events() {
+            match event {
+                UserEvent::Keystroke(byte) => input.push(byte),
+                UserEvent::Resize { width, height } => {
+                    if !input.is_empty() {
+                        pty.write_all(&std::mem::take(&mut input)).await?;
+                    }
+                    pty.resize(width.clamp(1, u16::MAX as i32) as u16, height.clamp(1, u16::MAX as i32) as u16)?;
+                }
+            }
+        }
+        if !input.is_empty() && shell_exited.is_none() {
+            pty.write_all(&input).await?;
+        }
+        transport.tick().await?;
+
+        if transport.client_shut_down() && transport.client_shutdown_acknowledged() {
+            log::debug!("Client ended the session");
+            return Ok(());
+        }
+        if let Some(at) = shell_exited {
+            if transport.shutdown_acknowledged() || transport.last_heard().is_none() {
+                return Ok(());
+            }
+            if at.elapsed() >= CLOSE_TIMEOUT {
+                bail!("client didn't acknowledge the end of the session");
+            }
+        }
+        match transport.last_heard() {
+            None if started.elapsed() >= CONNECT_TIMEOUT => {
+                bail!("no client connected within {} seconds", CONNECT_TIMEOUT.as_secs());
+            }
+            Some(heard) if idle_timeout.is_some_and(|t| heard.elapsed() >= t) => {
+                bail!("no contact with the client for {} seconds", heard.elapsed().as_secs());
+            }
+            _ => {}
+        }
+    }
+}
+
+// ── Transport ──────────────────────────────────────────────────────
+
+/// A state we sent: the screen and echo ack it carries.
+#[cfg(any(unix, test))]
+struct SentState {
+    num: u64,
+    fb: Framebuffer,
+    echo_ack: u64,
+    /// `ServerTransport::screen_writes` when `fb` was taken.
+    screen_writes: u64,
+}
+
+/// The server side of SSP: sends screen states, receives user input.
+#[cfg(any(unix, test))]
+pub struct ServerTransport {
+    link: Link,
+    /// Where the newest client packet came from; replies go here, so a
+    /// client that roams is followed.
+    peer: Option<SocketAddr>,
+    session: Session,
+    fragmenter: Fragmenter,
+    assembly: FragmentAssembly,
+    next_seq: u64,
+    /// One past the newest client sequence number seen.
+    expected_seq: u64,
+    recv_buf: Vec<u8>,
+    epoch: Instant,
+    last_client_timestamp: u16,
+    last_heard: Option<Instant>,
+
+    // ── Sender ─────────────────────────────────────────────────────
+    terminal: Terminal,
+    /// Bumped on every change to `terminal`, so a state taken since can be
+    /// known current without comparing screens.
+    screen_writes: u64,
+    echo_ack: u64,
+    /// Client states with user input, and when we passed it to the host.
+    pending_echo: VecDeque<(u64, Instant)>,
+    /// Front = acknowledged by the client. Back = last sent.
+    sent: Vec<SentState>,
+    last_send: Option<Instant>,
+    ack_pending: bool,
+    shutdown: bool,
+    /// Whether we've acknowledged the client's shutdown.
+    client_shutdown_acked: bool,
+
+    // ── Receiver ───────────────────────────────────────────────────
+    client_states: BTreeMap<u64, UserStream>,
+    /// Newest client state applied.
+    client_num: u64,
+    /// User events already handed to the host.
+    processed: usize,
+    /// Every client instruction that arrived intact, in arrival order.
+    #[cfg(test)]
+    received: Vec<Instruction>,
+}
+
+#[cfg(any(unix, test))]
+impl ServerTransport {
+    /// Serve on an already bound socket. Needs a tokio runtime.
+    pub fn new(key: &Base64Key, socket: std::net::UdpSocket, width: usize, height: usize) -> Result<Self> {
+        socket.set_nonblocking(true)?;
+        let socket = UdpSocket::from_std(socket).context("Failed to register UDP socket")?;
+        Self::with_link(key, Link::Udp(socket), width, height)
+    }
+
+    /// Serve over a simulated network (see `linksim`).
+    #[cfg(test)]
+    pub(crate) fn with_sim_link(
+        key: &Base64Key,
+        endpoint: crate::linksim::SimEndpoint,
+        width: usize,
+        height: usize,
+    ) -> Result<Self> {
+        Self::with_link(key, Link::Sim(endpoint), width, height)
+    }
+
+    fn with_link(key: &Base64Key, link: Link, width: usize, height: usize) -> Result<Self> {
+        let terminal = Terminal::new(width, height);
+        Ok(Self {
+            link,
+            peer: None,
+            session: Session::new(key)?,
+            fragmenter: Fragmenter::new(),
+            assembly: FragmentAssembly::new(),
+            next_seq: 0,
+            expected_seq: 0,
+            recv_buf: vec![0u8; 65_536],
+            epoch: Instant::now(),
+            last_client_timestamp: u16::MAX,
+            last_heard: None,
+            sent: vec![SentState {
+                num: 0,
+                fb: terminal.fb.clone(),
+                echo_ack: 0,
+                screen_writes: 0,
+            }],
+            screen_writes: 0,
+            terminal,
+            echo_ack: 0,
+            pending_echo: VecDeque::new(),
+            last_send: None,
+            ack_pending: false,
+            shutdown: false,
+            client_shutdown_acked: false,
+            client_states: BTreeMap::from([(0, UserStream::new())]),
+            client_num: 0,
+            processed: 0,
+            #[cfg(test)]
+            received: Vec::new(),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.link.local_addr().expect("bound socket has an address")
+    }
+
+    /// The host's screen, as clients will see it.
+    #[cfg(test)]
+    pub(crate) fn terminal(&self) -> &Terminal {
+        &self.terminal
+    }
+
+    /// Everything the client has typed so far.
+    #[cfg(test)]
+    pub(crate) fn client_input(&self) -> &UserStream {
+        &self.client_states[&self.client_num]
+    }
+
+    /// The number of the client state `client_input` comes from.
+    #[cfg(test)]
+    pub(crate) fn client_state_num(&self) -> u64 {
+        self.client_num
+    }
+
+    /// Client instructions received so far, in arrival order.
+    #[cfg(test)]
+    pub(crate) fn received(&self) -> &[Instruction] {
+        &self.received
+    }
+
+    /// When we last heard from a client, if ever.
+    #[cfg(unix)]
+    pub fn last_heard(&self) -> Option<Instant> {
+        self.last_heard
+    }
+
+    /// Whether the client has started shutting the session down.
+    pub fn client_shut_down(&self) -> bool {
+        self.client_num == u64::MAX
+    }
+
+    /// Whether we've told the client we saw its shutdown.
+    #[cfg(unix)]
+    pub fn client_shutdown_acknowledged(&self) -> bool {
+        self.client_shutdown_acked
+    }
+
+    /// Output from the host, drawn on the server's screen.
+    pub fn host_output(&mut self, bytes: &[u8]) {
+        self.terminal.process(bytes);
+        self.screen_writes += 1;
+    }
+
+    /// User input the host hasn't seen yet. Resizes also resize the screen.
+    pub fn take_user_events(&mut self) -> Vec<UserEvent> {
+        let input = &self.client_states[&self.client_num];
+        let fresh = input.events()[self.processed..].to_vec();
+        self.processed = input.len();
+        if fresh.is_empty() {
+            return fresh;
+        }
+        for event in &fresh {
+            if let UserEvent::Resize { width, height } = *event {
+                self.terminal.resize(width.max(1) as usize, height.max(1) as usize);
+                self.screen_writes += 1;
+            }
+        }
+        if self.client_num != u64::MAX {
+            self.pending_echo.push_back((self.client_num, Instant::now()));
+        }
+        fresh
+    }
+
+    /// End the session from the server side.
+    pub fn close(&mut self) {
+        self.shutdown = true;
+    }
+
+    /// Whether the client acknowledged our shutdown.
+    pub fn shutdown_acknowledged(&self) -> bool {
+        self.sent[0].num == u64::MAX
+    }
+
+    /// Wait until a datagram may be waiting.
+    pub async fn readable(&self) -> Result<()> {
+        self.link.readable().await?;
+        Ok(())
+    }
+
+    /// Handle every datagram that has arrived. Ones that aren't a valid
+    /// packet from a client are dropped.
+    pub fn drain_recv(&mut self) -> Result<()> {
+        let mut buf = std::mem::take(&mut self.recv_buf);
+        let result = self.drain_recv_into(&mut buf);
+        self.recv_buf = buf;
+        result
+    }
+
+    fn drain_recv_into(&mut self, buf: &mut [u8]) -> Result<()> {
+        loop {
+            match self.link.try_recv_from(buf) {
+                Ok((n, from)) => {
+                    if let Err(e) = self.receive(&buf[..n], from) {
+                        log::debug!("Dropped datagram from {}: {:#}", from, e);
+                    }
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                // Windows reports ICMP errors for earlier sends here.
+                Err(e) if e.kind() == std::io::ErrorKind::ConnectionReset => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Send if anything is due.
+    pub async fn tick(&mut self) -> Result<()> {
+        let now = Instant::now();
+        while let Some(&(num, at)) = self.pending_echo.front() {
+            if now.duration_since(at) < ECHO_TIMEOUT {
+                break;
+            }
+            self.echo_ack = num;
+            self.pending_echo.pop_front();
+        }
+        if self.next_send(now).is_some_and(|at| at <= now) {
+            self.send().await?;
+        }
+        Ok(())
+    }
+
+    /// When `tick` next has something to do: a send, or passing on an echo ack.
+    pub fn next_wakeup(&self) -> Option<Instant> {
+        let echo = self.pending_echo.front().map(|&(_, at)| at + ECHO_TIMEOUT);
+        match (self.next_send(Instant::now()), echo) {
+            (Some(send), Some(echo)) => Some(send.min(echo)),
+            (send, echo) => send.or(echo),
+        }
+    }
+
+    fn receive(&mut self, datagram: &[u8], from: SocketAddr) -> Result<()> {
+        let (nonce, plaintext) = self.session.decrypt(datagram)?;
+        let mut wire = [0u8; 8];
+        wire.copy_from_slice(&nonce[4..12]);
+        let (direction, seq) = parse_nonce(&wire);
+        if direction != Direction::ToServer {
+            bail!("packet is not from a client");
+        }
+        let packet = Packet::from_bytes(&plaintext)?;
+        if seq >= self.expected_seq {
+            self.expected_seq = seq + 1;
+            if self.peer != Some(from) {
+                log::debug!("Client is now at {}", from);
+                self.peer = Some(from);
+            }
+        }
+        self.last_client_timestamp = packet.timestamp;
+        self.last_heard = Some(Instant::now());
+        if packet.payload.is_empty() {
+            return Ok(());
+        }
+        let fragment = Fragment::from_bytes(&packet.payload)?;
+        let Some(compressed) = self.assembly.add_fragment(fragment) else {
+            return Ok(());
+        };
+        let bytes = zlib_decompress(&compressed)?;
+        let ti = Instruction::decode(bytes.as_slice()).context("Failed to decode TransportInstruction")?;
+        let version = ti.protocol_version.unwrap_or_default();
+        if version != MOSH_PROTOCOL_VERSION {
+            bail!("peer={} local={}", version, MOSH_PROTOCOL_VERSION);
+        }
+        #[cfg(test)]
+        self.received.push(ti.clone());
+
+        let ack = ti.ack_num.unwrap_or_default();
+        if self.sent.iter().any(|s| s.num == ack) {
+            self.sent.retain(|s| s.num >= ack);
+        }
+
+        let (old, new) = (ti.old_num.unwrap_or_default(), ti.new_num.unwrap_or_default());
+        if self.client_states.contains_key(&new) {
+            self.ack_pending = true;
+            return Ok(());
+        }
+        let Some(base) = self.client_states.get(&old) else {
+            return Ok(());
+        };
+        let mut state = base.clone();
+        state.apply_string(ti.diff.as_deref().unwrap_or_default());
+        self.client_states.insert(new, state);
+        self.client_states.retain(|&n, _| n >= ti.throwaway_num.unwrap_or_default());
+        if new > self.client_num {
+            self.client_num = new;
+        }
+        self.ack_pending = true;
+        Ok(())
+    }
+
+    fn is_current(&self, state: &SentState) -> bool {
+        state.echo_ack == self.echo_ack
+            && (state.screen_writes == self.screen_writes || same_frame(&state.fb, &self.terminal.fb))
+    }
+
+    /// When the next packet is due, `now` if it's overdue; `None` if
+    /// nothing will be sent, as before a client shows up.
+    fn next_send(&self, now: Instant) -> Option<Instant> {
+        self.peer?;
+        let after = |interval| self.last_send.map_or(now, |t| t + interval);
+        let last = self.sent.last().expect("sent states are never empty");
+        if self.shutdown_acknowledged() {
+            return self.ack_pending.then_some(now);
+        }
+        if self.ack_pending || (self.shutdown && last.num != u64::MAX) {
+            return Some(now);
+        }
+        let mut next = after(ACK_INTERVAL);
+        if !self.is_current(last) {
+            next = next.min(after(SEND_INTERVAL));
+        }
+        if self.shutdown || !self.is_current(&self.sent[0]) {
+            next = next.min(after(RETRY_INTERVAL));
+        }
+        Some(next)
+    }
+
+    async fn send(&mut self) -> Result<()> {
+        let Some(peer) = self.peer else {
+            return Ok(());
+        };
+        let last = self.sent.last().expect("sent states are never empty");
+        let new_num = if self.shutdown || last.num == u64::MAX {
+            u64::MAX
+        } else if !self.is_current(last) {
+            last.num + 1
+        } else {
+            last.num
+        };
+        if new_num != last.num {
+            self.sent.push(SentState {
+                num: new_num,
+                fb: self.terminal.fb.clone(),
+                echo_ack: self.echo_ack,
+                screen_writes: self.screen_writes,
+            });
+        }
+
+        let base = &self.sent[0];
+        let instruction = Instruction {
+            protocol_version: Some(MOSH_PROTOCOL_VERSION),
+            old_num: Some(base.num),
+            new_num: Some(new_num),
+            ack_num: Some(self.client_num),
+            throwaway_num: Some(base.num),
+            diff: Some(self.diff_from(base)),
+            chaff: None,
+        };
+
+        let compressed = zlib_compress(&instruction.encode_to_vec())?;
+        for fragment in self
+            .fragmenter
+            .make_fragments(&compressed, max_frag_payload(DEFAULT_MTU))
+        {
+            let packet = Packet {
+                timestamp: timestamp_since(self.epoch),
+                timestamp_reply: self.last_client_timestamp,
+                payload: fragment.to_bytes(),
+            };
+            let nonce = make_nonce(Direction::ToClient, self.next_seq);
+            self.next_seq += 1;
+            let datagram = self.session.encrypt(&nonce, &packet.to_bytes())?;
+            self.link.send_to(&datagram, peer).await?;
+        }
+        self.last_send = Some(Instant::now());
+        self.ack_pending = false;
+        if self.client_num == u64::MAX {
+            self.client_shutdown_acked = true;
+        }
+        Ok(())
+    }
+
+    /// The `HostMessage` taking a client from `base` to the current state.
+    fn diff_from(&self, base: &SentState) -> Vec<u8> {
+        let fb = &self.terminal.fb;
+        let mut instruction = Vec::new();
+        if (base.fb.width, base.fb.height) != (fb.width, fb.height) {
+            instruction.push(hostinput::Instruction {
+                hostbytes: None,
+                resize: Some(hostinput::ResizeMessage {
+                    width: Some(fb.width as i32),
+                    height: Some(fb.height as i32),
+                }),
+                echoack: None,
+            });
+        }
+        if !same_frame(&base.fb, fb) {
+            instruction.push(hostinput::Instruction {
+                hostbytes: Some(hostinput::HostBytes { hoststring: Some(new_frame(&base.fb, fb)) }),
+                resize: None,
+                echoack: None,
+            });
+        }
+        if base.echo_ack != self.echo_ack {
+            instruction.push(hostinput::Instruction {
+                hostbytes: None,
+                resize: None,
+                echoack: Some(hostinput::EchoAck { echo_ack_num: Some(self.echo_ack) }),
+            });
+        }
+        if instruction.is_empty() {
+            return Vec::new();
+        }
+        hostinput::HostMessage { instruction }.encode_to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bind_loopback() -> std::net::UdpSocket {
+        std::net::UdpSocket::bind("127.0.0.1:0").unwrap()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_server_cli() {
+        let cli = ServerCli::try_parse_from([
+            "server", "new", "-s", "-c", "256", "-p", "60010:60020", "-l", "LANG=C.UTF-8", "--", "vim", "-R",
+        ])
+        .unwrap();
+        assert!(cli.bind_ssh_address);
+        assert_eq!(cli.term(), "xterm-256color");
+        assert_eq!(cli.port.as_deref(), Some("60010:60020"));
+        assert_eq!(cli.env, vec![("LANG".to_string(), "C.UTF-8".to_string())]);
+        assert_eq!(cli.command, vec!["vim", "-R"]);
+
+        let bare = ServerCli::try_parse_from(["server"]).unwrap();
+        assert_eq!(bare.term(), "xterm");
+        assert!(bare.command.is_empty());
+
+        assert!(ServerCli::try_parse_from(["server", "old"]).is_err());
+        assert!(ServerCli::try_parse_from(["server", "-p", "0"]).is_err());
+        assert!(ServerCli::try_parse_from(["server", "-l", "NOEQUALS"]).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_ssh_server_address() {
+        assert_eq!(
+            ssh_server_address("192.0.2.7 51234 198.51.100.2 22").unwrap(),
+            "198.51.100.2".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            ssh_server_address("::ffff:192.0.2.7 51234 ::ffff:198.51.100.2 22").unwrap(),
+            "198.51.100.2".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            ssh_server_address("2001:db8::7 51234 2001:db8::2 22").unwrap(),
+            "2001:db8::2".parse::<IpAddr>().unwrap()
+        );
+        assert!(ssh_server_address("192.0.2.7 51234").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_bind_port_skips_busy_ports() {
+        let busy = bind_loopback();
+        let port = busy.local_addr().unwrap().port();
+        assert!(bind_port(IpAddr::V4(Ipv4Addr::LOCALHOST), (port, port)).is_err());
+        if port < u16::MAX {
+            // The next port may be taken by someone else; only check when it isn't.
+            if let Ok(socket) = bind_port(IpAddr::V4(Ipv4Addr::LOCALHOST), (port, port + 1)) {
+                assert_eq!(socket.local_addr().unwrap().port(), port + 1);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_next_wakeup_follows_the_timers() {
+        use crate::transport::Transport;
+
+        let key = Base64Key::from_str("AAAAAAAAAAAAAAAAAAAAAA").unwrap();
+        let mut server = ServerTransport::new(&key, bind_loopback(), 80, 24).unwrap();
+        // Nothing to do until a client shows up.
+        assert_eq!(server.next_wakeup(), None);
+
+        let mut client = Transport::new(&key, server.local_addr(), Direction::ToServer, 80, 24).await.unwrap();
+        client.push_user_input(b"x");
+        client.tick().await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), server.readable()).await.unwrap().unwrap();
+        server.drain_recv().unwrap();
+        // The client's state needs acknowledging right away.
+        assert!(server.next_wakeup().unwrap() <= Instant::now());
+
+        assert_eq!(server.take_user_events(), [UserEvent::Keystroke(b'x')]);
+        let typed = Instant::now();
+        server.tick().await.unwrap();
+        // Next up: the echo ack, before the retry and the heartbeat.
+        let next = server.next_wakeup().unwrap();
+        assert!(next > Instant::now() && next <= typed + ECHO_TIMEOUT, "{:?}", next - typed);
+
+        // New output goes out one send interval after the last packet.
+        let sent = server.last_send.unwrap();
+        server.host_output(b"x");
+        assert_eq!(server.next_wakeup(), Some(sent + SEND_INTERVAL));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_session_with_shell() {
+        use crate::pty::{find_program, Pty};
+        use crate::transport::Transport;
+
+        let key = Base64Key::from_str("AAAAAAAAAAAAAAAAAAAAAA").unwrap();
+        let transport = ServerTransport::new(&key, bind_loopback(), 80, 24).unwrap();
+        let addr = transport.local_addr();
+        let argv = ["sh", "-c", "read line; echo \"got $line\""].map(String::from);
+        let pty = Pty::spawn(&find_program("sh").unwrap(), &argv, &[], 80, 24).unwrap();
+        let server = tokio::spawn(serve(transport, pty, None));
+
+        let mut client = Transport::new(&key, addr, Direction::ToServer, 80, 24).await.unwrap();
+        client.push_resize(80, 24);
+        client.push_user_input(b"hello\r");
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !server.is_finished() {
+            assert!(Instant::now() < deadline, "session didn't end in time");
+            client.drain_recv().unwrap();
+            client.tick().await.unwrap();
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        server.await.unwrap().unwrap();
+
+        // The shell's last words made it into the final state.
+        let fb = client.latest_remote_framebuffer();
+        let row = |r: usize| fb.cells[r].iter().map(|c| c.character).collect::<String>();
+        assert_eq!(row(0).trim_end(), "hello");
+        assert_eq!(row(1).trim_end(), "got hello");
+        assert!(client.counterparty_shutdown_ack_sent());
+    }
+}
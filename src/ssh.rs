@@ -2,155 +2,312 @@
 //!
 //! Authentication order (mirrors OpenSSH):
 //! 1. Explicit identity file (-i flag)
-//! 2. SSH agent (Windows OpenSSH agent pipe → Pageant → SSH_AUTH_SOCK)
+//! 2. SSH agent (Windows OpenSSH agent pipe → Pageant; SSH_AUTH_SOCK on Unix)
 //! 3. Default key files (~/.ssh/id_ed25519, id_rsa, id_ecdsa)
-//! 4. Interactive password prompt (stdin)
+//! 4. Keyboard-interactive challenges (OTP / 2FA prompts)
+//! 5. Interactive password prompt (stdin, or an askpass program)
 //!
-//! Server key verification uses ~/.ssh/known_hosts (standard OpenSSH location).
+//! Key files are paired with an OpenSSH user certificate when one is given
+//! explicitly or found next to the key as `<key>-cert.pub`; certificates
+//! held by the agent are offered like its keys.
+//!
+//! Server key verification uses ~/.ssh/known_hosts plus the system-wide
+//! ssh_known_hosts (standard OpenSSH locations), honoring hashed entries and
+//! `@revoked` / `@cert-authority` markers. Host certificates are asked for
+//! when a `@cert-authority` line matches the host, and accepted when that CA
+//! signed them. Unknown hosts are handled according to
+//! `StrictHostKeyChecking`; without a terminal, `ask` fails instead of prompting.
+//!
+//! If mosh-server isn't on the remote PATH, common install directories and a
+//! login shell are tried, and an optional local binary is uploaded as a last resort.
 
+use crate::agent::{self, AgentSource, DynAgentClient};
+use crate::crypto::Base64Key;
+use crate::status::ExitStatus;
 use anyhow::{bail, Context, Result};
-use russh::keys::key;
+use hmac::{Hmac, Mac};
+use russh::keys::agent::AgentIdentity;
+use russh::keys::ssh_key::certificate::CertType;
+use russh::keys::{
+    Algorithm, Certificate, HashAlg, PrivateKey, PrivateKeyWithHashAlg, PublicKey, PublicKeyOrCertificate,
+};
 use russh::*;
+use sha1::Sha1;
+use std::borrow::Cow;
 use std::io::Write as _;
-use std::path::PathBuf;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
-
-// Windows OpenSSH agent named pipe path.
-const OPENSSH_AGENT_PIPE: &str = r"\\.\pipe\openssh-ssh-agent";
+use std::time::Duration;
+use zeroize::Zeroizing;
 
 /// Result of SSH bootstrap: port and encryption key.
+/// The key is already decoded, and is redacted from `Debug` output.
 #[derive(Debug)]
 pub struct MoshSession {
     pub port: u16,
-    pub key: String,
+    pub key: Base64Key,
     pub remote_ip: String,
 }
 
+/// How to treat host keys that aren't already trusted (OpenSSH `StrictHostKeyChecking`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StrictHostKeyChecking {
+    /// Refuse hosts whose key isn't already in a known_hosts file.
+    Yes,
+    /// Record keys for new hosts without asking; refuse changed keys.
+    AcceptNew,
+    /// Record keys for new hosts and let changed keys through with a warning.
+    /// Password and keyboard-interactive auth are disabled after a key change.
+    No,
+    /// Ask on the terminal before recording a new host key.
+    #[default]
+    Ask,
+}
+
+impl std::str::FromStr for StrictHostKeyChecking {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "yes" => Ok(Self::Yes),
+            "accept-new" => Ok(Self::AcceptNew),
+            "no" | "off" => Ok(Self::No),
+            "ask" => Ok(Self::Ask),
+            other => bail!(
+                "invalid StrictHostKeyChecking value '{}' (expected yes, accept-new, no or ask)",
+                other
+            ),
+        }
+    }
+}
+
+impl std::fmt::Display for StrictHostKeyChecking {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Yes => "yes",
+            Self::AcceptNew => "accept-new",
+            Self::No => "no",
+            Self::Ask => "ask",
+        })
+    }
+}
+
 /// SSH client handler with known_hosts verification.
 struct SshClient {
     host: String,
     port: u16,
-    /// Set after check_server_key to indicate the key was new and should be learned.
-    server_key_new: bool,
+    strict_host_key_checking: StrictHostKeyChecking,
+    /// Trusted keys are read from here, and new keys are recorded here.
+    user_known_hosts: PathBuf,
+    /// System-wide known hosts, consulted read-only.
+    global_known_hosts: Option<PathBuf>,
+    hash_known_hosts: bool,
+    /// Set when a changed host key was let through (`StrictHostKeyChecking=no`).
+    key_mismatch: Arc<AtomicBool>,
+    /// Accept agent channels opened by the server.
+    forward_agent: bool,
+    /// Shared with the bootstrap's deadlines, so the host key prompt pauses them.
+    prompts: Arc<PromptActivity>,
 }
 
-#[async_trait::async_trait]
 impl client::Handler for SshClient {
     type Error = russh::Error;
 
     async fn check_server_key(
         &mut self,
-        server_public_key: &key::PublicKey,
+        server_key: &PublicKeyOrCertificate,
     ) -> Result<bool, Self::Error> {
-        let known_hosts_path = ssh_dir().join("known_hosts");
+        Ok(match server_key {
+            PublicKeyOrCertificate::PublicKey { key, .. } => self.verify_host_key(key),
+            PublicKeyOrCertificate::Certificate(cert) => self.verify_host_certificate(cert),
+        })
+    }
 
-        // If known_hosts file exists, check it
-        if known_hosts_path.exists() {
-            match russh_keys::check_known_hosts_path(
-                &self.host,
-                self.port,
-                server_public_key,
-                &known_hosts_path,
-            ) {
-                Ok(true) => {
-                    // Key matches — trusted
-                    return Ok(true);
+    async fn server_channel_open_agent_forward(
+        &mut self,
+        channel: Channel<client::Msg>,
+        reply: client::ChannelOpenHandle,
+        _session: &mut client::Session,
+    ) -> Result<(), Self::Error> {
+        if !self.forward_agent {
+            // Dropping the handle refuses the channel
+            log::warn!("SSH: server opened an agent channel that was not requested");
+            return Ok(());
+        }
+        reply.accept().await;
+        tokio::spawn(async move {
+            if let Err(e) = agent::proxy(channel.into_stream()).await {
+                log::debug!("SSH: agent forwarding failed: {}", e);
+            }
+        });
+        Ok(())
+    }
+}
+
+impl SshClient {
+    /// Known-hosts files to consult, user file first.
+    fn known_hosts_files(&self) -> impl Iterator<Item = &Path> {
+        std::iter::once(self.user_known_hosts.as_path()).chain(self.global_known_hosts.as_deref())
+    }
+
+    /// Keys of the `@cert-authority` entries that apply to this host.
+    fn cert_authorities(&self) -> Vec<PublicKey> {
+        self.known_hosts_files()
+            .flat_map(|path| marked_host_keys(path, &self.host, self.port).cert_authorities)
+            .collect()
+    }
+
+    /// Refuse `key` if a known_hosts file marks it `@revoked`.
+    fn is_revoked(&self, key: &PublicKey, what: &str) -> bool {
+        for path in self.known_hosts_files() {
+            let marked = marked_host_keys(path, &self.host, self.port);
+            if marked.revoked.iter().any(|k| k.key_data() == key.key_data()) {
+                eprintln!("@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@");
+                eprintln!("@       WARNING: REVOKED HOST KEY DETECTED!               @");
+                eprintln!("@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@");
+                eprintln!(
+                    "The {} {} for '{}' is marked as revoked in {:?}.",
+                    key.algorithm(),
+                    what,
+                    self.host,
+                    path
+                );
+                eprintln!("Host key verification failed.");
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Accept a host certificate signed by a `@cert-authority` for this host.
+    ///
+    /// A certificate no trusted CA vouches for isn't fatal: as in OpenSSH,
+    /// the key it certifies then goes through the plain known_hosts checks.
+    fn verify_host_certificate(&mut self, cert: &Certificate) -> bool {
+        let key = PublicKey::new(cert.public_key().clone(), "");
+        let ca = PublicKey::new(cert.signature_key().clone(), "");
+        if self.is_revoked(&key, "host key") || self.is_revoked(&ca, "certificate authority") {
+            return false;
+        }
+        match check_host_certificate(cert, &self.cert_authorities(), &self.host, unix_time()) {
+            Ok(()) => {
+                log::debug!(
+                    "SSH: host certificate {:?} signed by {}",
+                    cert.key_id(),
+                    ca.fingerprint(HashAlg::Sha256)
+                );
+                true
+            }
+            Err(e) => {
+                eprintln!("Warning: host certificate for '{}' not accepted: {}", self.host, e);
+                self.verify_host_key(&key)
+            }
+        }
+    }
+
+    fn verify_host_key(&mut self, server_public_key: &PublicKey) -> bool {
+        if self.is_revoked(server_public_key, "host key") {
+            return false;
+        }
+
+        let mut changed = None;
+        for path in self.known_hosts_files() {
+            match keys::check_known_hosts_path(&self.host, self.port, server_public_key, path) {
+                // Key matches — trusted
+                Ok(true) => return true,
+                Ok(false) => {}
+                Err(keys::Error::KeyChanged { line }) => {
+                    changed.get_or_insert((path.to_path_buf(), line));
                 }
-                Ok(false) => {
-                    // Host not in known_hosts — ask user to accept
-                    let fingerprint = server_public_key.fingerprint();
+                Err(e) => eprintln!("Warning: error reading {:?}: {}", path, e),
+            }
+        }
+
+        if let Some((path, line)) = changed {
+            eprintln!("@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@");
+            eprintln!("@    WARNING: REMOTE HOST IDENTIFICATION HAS CHANGED!     @");
+            eprintln!("@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@");
+            eprintln!("IT IS POSSIBLE THAT SOMEONE IS DOING SOMETHING NASTY!");
+            eprintln!(
+                "The {} host key for '{}' has changed (line {} of {:?}).",
+                server_public_key.algorithm(),
+                self.host,
+                line,
+                path
+            );
+            if self.strict_host_key_checking == StrictHostKeyChecking::No {
+                eprintln!(
+                    "StrictHostKeyChecking is off: continuing, but password and \
+                     keyboard-interactive authentication are disabled."
+                );
+                self.key_mismatch.store(true, Ordering::SeqCst);
+                return true;
+            }
+            eprintln!("Host key verification failed.");
+            return false;
+        }
+
+        // Host not in known_hosts
+        match self.strict_host_key_checking {
+            StrictHostKeyChecking::Yes => {
+                eprintln!(
+                    "No {} host key is known for '{}:{}' and you have requested strict checking.",
+                    server_public_key.algorithm(),
+                    self.host,
+                    self.port
+                );
+                eprintln!("Host key verification failed.");
+                return false;
+            }
+            StrictHostKeyChecking::AcceptNew | StrictHostKeyChecking::No => {}
+            StrictHostKeyChecking::Ask => {
+                if !atty_stdin() {
                     eprintln!(
-                        "The authenticity of host '{}:{}' can't be established.",
+                        "The authenticity of host '{}:{}' can't be established, and stdin \
+                         is not a terminal to confirm it.",
                         self.host, self.port
                     );
                     eprintln!(
-                        "{} key fingerprint is {}.",
-                        server_public_key.name(),
-                        fingerprint
-                    );
-
-                    if confirm_prompt("Are you sure you want to continue connecting (yes/no)? ") {
-                        // Learn the key
-                        self.server_key_new = true;
-                        if let Err(e) = russh_keys::known_hosts::learn_known_hosts_path(
-                            &self.host,
-                            self.port,
-                            server_public_key,
-                            &known_hosts_path,
-                        ) {
-                            eprintln!("Warning: failed to save host key: {}", e);
-                        } else {
-                            eprintln!(
-                                "Warning: Permanently added '{}:{}' to the list of known hosts.",
-                                self.host, self.port
-                            );
-                        }
-                        return Ok(true);
-                    } else {
-                        eprintln!("Host key verification failed.");
-                        return Ok(false);
-                    }
-                }
-                Err(russh_keys::Error::KeyChanged { line }) => {
-                    eprintln!("@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@");
-                    eprintln!("@    WARNING: REMOTE HOST IDENTIFICATION HAS CHANGED!     @");
-                    eprintln!("@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@");
-                    eprintln!("IT IS POSSIBLE THAT SOMEONE IS DOING SOMETHING NASTY!");
-                    eprintln!(
-                        "The {} host key for '{}' has changed (line {} of {:?}).",
-                        server_public_key.name(),
-                        self.host,
-                        line,
-                        known_hosts_path
+                        "Add the host to {:?} or pass --strict-host-key-checking=accept-new.",
+                        self.user_known_hosts
                     );
                     eprintln!("Host key verification failed.");
-                    return Ok(false);
+                    return false;
                 }
-                Err(e) => {
-                    eprintln!("Warning: error reading known_hosts: {}", e);
-                    // Fall through to the "no file" path below
+                eprintln!(
+                    "The authenticity of host '{}:{}' can't be established.",
+                    self.host, self.port
+                );
+                eprintln!(
+                    "{} key fingerprint is {}.",
+                    server_public_key.algorithm(),
+                    server_public_key.fingerprint(HashAlg::Sha256)
+                );
+                if !confirm_prompt(&self.prompts, "Are you sure you want to continue connecting (yes/no)? ") {
+                    eprintln!("Host key verification failed.");
+                    return false;
                 }
             }
         }
 
-        // No known_hosts file — first connection, ask user
-        let fingerprint = server_public_key.fingerprint();
-        eprintln!(
-            "The authenticity of host '{}:{}' can't be established.",
-            self.host, self.port
-        );
-        eprintln!(
-            "{} key fingerprint is {}.",
-            server_public_key.name(),
-            fingerprint
-        );
-
-        if confirm_prompt("Are you sure you want to continue connecting (yes/no)? ") {
-            // Ensure ~/.ssh directory exists
-            let ssh_dir = ssh_dir();
-            if !ssh_dir.exists() {
-                let _ = std::fs::create_dir_all(&ssh_dir);
-            }
-
-            self.server_key_new = true;
-            if let Err(e) = russh_keys::known_hosts::learn_known_hosts_path(
-                &self.host,
+        match learn_host_key(
+            &self.user_known_hosts,
+            &self.host,
+            self.port,
+            server_public_key,
+            self.hash_known_hosts,
+        ) {
+            Ok(()) => eprintln!(
+                "Warning: Permanently added '{}:{}' ({}) to the list of known hosts.",
+                self.host,
                 self.port,
-                server_public_key,
-                &known_hosts_path,
-            ) {
-                eprintln!("Warning: failed to save host key: {}", e);
-            } else {
-                eprintln!(
-                    "Warning: Permanently added '{}:{}' to the list of known hosts.",
-                    self.host, self.port
-                );
-            }
-            Ok(true)
-        } else {
-            eprintln!("Host key verification failed.");
-            Ok(false)
+                server_public_key.algorithm()
+            ),
+            Err(e) => eprintln!("Warning: failed to save host key: {}", e),
         }
+        true
     }
 }
 
@@ -160,10 +317,37 @@ pub struct SshConfig {
     pub host: String,
     pub port: u16,
     pub username: String,
-    pub password: Option<String>,
+    pub password: Option<Zeroizing<String>>,
     pub identity_file: Option<PathBuf>,
+    /// OpenSSH user certificate for the identity file (default: `<identity>-cert.pub`).
+    pub certificate_file: Option<PathBuf>,
+    pub strict_host_key_checking: StrictHostKeyChecking,
+    /// Known hosts file to read and update (default: `~/.ssh/known_hosts`).
+    pub user_known_hosts_file: PathBuf,
+    /// Read-only system-wide known hosts file (default: platform `ssh_known_hosts`).
+    pub global_known_hosts_file: Option<PathBuf>,
+    /// Record new host names hashed (`|1|salt|hash`) instead of in plain text.
+    pub hash_known_hosts: bool,
     pub mosh_server_command: String,
     pub mosh_server_args: Vec<String>,
+    /// UDP port or inclusive range for mosh-server to bind (`-p PORT[:PORT2]`).
+    pub udp_port_range: Option<(u16, u16)>,
+    /// Environment for mosh-server's shell, passed as `-l NAME=VALUE`.
+    pub server_env: Vec<(String, String)>,
+    /// Request a PTY on the bootstrap channel (like `ssh -t`).
+    pub request_pty: bool,
+    /// Forward the local SSH agent to the bootstrap session (like `ssh -A`).
+    pub forward_agent: bool,
+    /// Command for mosh-server to run instead of the login shell (after `--`).
+    pub remote_command: Vec<String>,
+    /// Local static mosh-server binary to upload when none is installed remotely.
+    pub server_binary: Option<PathBuf>,
+    /// Limit for TCP connect plus SSH handshake (OpenSSH `ConnectTimeout`).
+    pub connect_timeout: Duration,
+    /// Limit for authentication, not counting time spent at prompts.
+    pub auth_timeout: Duration,
+    /// Limit for mosh-server to report its port and key.
+    pub server_start_timeout: Duration,
 }
 
 impl SshConfig {
@@ -174,15 +358,27 @@ impl SshConfig {
             username: username.to_string(),
             password: None,
             identity_file: None,
+            certificate_file: None,
+            strict_host_key_checking: StrictHostKeyChecking::default(),
+            user_known_hosts_file: ssh_dir().join("known_hosts"),
+            global_known_hosts_file: default_global_known_hosts(),
+            hash_known_hosts: false,
             mosh_server_command: "mosh-server".to_string(),
             mosh_server_args: vec![
                 "new".to_string(),
                 "-s".to_string(),
                 "-c".to_string(),
                 "256".to_string(),
-                "-l".to_string(),
-                "LANG=en_US.UTF-8".to_string(),
             ],
+            udp_port_range: None,
+            server_env: local_locale_env(std::env::vars_os()),
+            request_pty: false,
+            forward_agent: false,
+            remote_command: Vec::new(),
+            server_binary: None,
+            connect_timeout: Duration::from_secs(30),
+            auth_timeout: Duration::from_secs(60),
+            server_start_timeout: Duration::from_secs(30),
         }
     }
 
@@ -194,7 +390,7 @@ impl SshConfig {
 
     /// Set password for authentication.
     pub fn with_password(mut self, password: &str) -> Self {
-        self.password = Some(password.to_string());
+        self.password = Some(Zeroizing::new(password.to_string()));
         self
     }
 
@@ -203,127 +399,589 @@ impl SshConfig {
         self.identity_file = Some(path);
         self
     }
+
+    /// Set the OpenSSH certificate to present with the identity file.
+    pub fn with_certificate_file(mut self, path: PathBuf) -> Self {
+        self.certificate_file = Some(path);
+        self
+    }
+
+    /// Set the policy for unknown and changed host keys (default: ask).
+    pub fn with_strict_host_key_checking(mut self, mode: StrictHostKeyChecking) -> Self {
+        self.strict_host_key_checking = mode;
+        self
+    }
+
+    /// Set the user known hosts file.
+    pub fn with_user_known_hosts_file(mut self, path: PathBuf) -> Self {
+        self.user_known_hosts_file = path;
+        self
+    }
+
+    /// Set the global known hosts file.
+    pub fn with_global_known_hosts_file(mut self, path: PathBuf) -> Self {
+        self.global_known_hosts_file = Some(path);
+        self
+    }
+
+    /// Restrict mosh-server to a UDP port or inclusive port range.
+    pub fn with_udp_port_range(mut self, low: u16, high: u16) -> Self {
+        self.udp_port_range = Some((low, high));
+        self
+    }
+
+    /// Set an environment variable for mosh-server, replacing any earlier value.
+    pub fn with_env(mut self, name: &str, value: &str) -> Self {
+        self.server_env.retain(|(n, _)| n != name);
+        self.server_env.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Request a PTY for the mosh-server start-up command.
+    pub fn with_pty(mut self, request_pty: bool) -> Self {
+        self.request_pty = request_pty;
+        self
+    }
+
+    /// Forward the local SSH agent while mosh-server starts.
+    pub fn with_agent_forwarding(mut self, forward_agent: bool) -> Self {
+        self.forward_agent = forward_agent;
+        self
+    }
+
+    /// Run `command` inside the mosh session instead of the login shell.
+    pub fn with_remote_command(mut self, command: Vec<String>) -> Self {
+        self.remote_command = command;
+        self
+    }
+
+    /// Upload `path` and run it if mosh-server can't be found remotely.
+    pub fn with_server_binary(mut self, path: PathBuf) -> Self {
+        self.server_binary = Some(path);
+        self
+    }
+
+    /// Set the connect (TCP + handshake) timeout.
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Set the authentication timeout.
+    pub fn with_auth_timeout(mut self, timeout: Duration) -> Self {
+        self.auth_timeout = timeout;
+        self
+    }
+
+    /// Set the mosh-server start-up timeout.
+    pub fn with_server_start_timeout(mut self, timeout: Duration) -> Self {
+        self.server_start_timeout = timeout;
+        self
+    }
+
+    /// Hash host names when recording new keys.
+    pub fn with_hash_known_hosts(mut self, hash: bool) -> Self {
+        self.hash_known_hosts = hash;
+        self
+    }
 }
 
 /// Connect via SSH and start mosh-server, returning the connection details.
 pub async fn bootstrap(config: &SshConfig) -> Result<MoshSession> {
-    let ssh_config = russh::client::Config::default();
+    let key_mismatch = Arc::new(AtomicBool::new(false));
+    let prompts = Arc::new(PromptActivity::default());
     let sh = SshClient {
         host: config.host.clone(),
         port: config.port,
-        server_key_new: false,
+        strict_host_key_checking: config.strict_host_key_checking,
+        user_known_hosts: config.user_known_hosts_file.clone(),
+        global_known_hosts: config.global_known_hosts_file.clone(),
+        hash_known_hosts: config.hash_known_hosts,
+        key_mismatch: key_mismatch.clone(),
+        forward_agent: config.forward_agent,
+        prompts: prompts.clone(),
     };
 
+    let mut ssh_config = russh::client::Config::default();
+    // Like OpenSSH, only ask for a host certificate when a CA could vouch for it
+    if !sh.cert_authorities().is_empty() {
+        ssh_config.preferred.host_key_certificates = ssh_config.preferred.key.clone();
+    }
+
     eprintln!(
         "SSH: connecting to {}@{}:{}",
         config.username, config.host, config.port
     );
 
-    let mut session = russh::client::connect(
-        Arc::new(ssh_config),
-        (config.host.as_str(), config.port),
-        sh,
+    let mut session = with_deadline(
+        &prompts,
+        "SSH connection",
+        config.connect_timeout,
+        async {
+            let stream =
+                tokio::net::TcpStream::connect((config.host.as_str(), config.port)).await?;
+            Ok(client::connect_stream(Arc::new(ssh_config), stream, sh).await?)
+        },
     )
     .await
-    .context("SSH connection failed")?;
+    .map_err(|e| {
+        // russh reports a refused host key as UnknownKey
+        let refused = matches!(e.downcast_ref::<russh::Error>(), Some(russh::Error::UnknownKey));
+        e.context(if refused { ExitStatus::HostKey } else { ExitStatus::SshConnect })
+    })?;
 
     // ── Authentication ──────────────────────────────────────────────────
 
-    let authenticated = authenticate(&mut session, config).await?;
+    let allow_password = !key_mismatch.load(Ordering::SeqCst);
+    let authenticated = with_deadline(
+        &prompts,
+        "SSH authentication",
+        config.auth_timeout,
+        authenticate(&mut session, config, allow_password, &prompts),
+    )
+    .await
+    .context(ExitStatus::SshAuth)?;
 
     if !authenticated {
-        bail!(
-            "SSH authentication failed for {}@{}",
+        return Err(anyhow::anyhow!(
+            "no authentication method was accepted for {}@{}",
             config.username,
             config.host
-        );
+        )
+        .context(ExitStatus::SshAuth));
     }
 
     eprintln!("SSH: authenticated successfully");
 
     // ── Execute mosh-server ─────────────────────────────────────────────
 
-    let server_cmd = format!(
-        "{} {}",
-        config.mosh_server_command,
-        config.mosh_server_args.join(" ")
-    );
+    let reply = with_deadline(
+        &prompts,
+        "mosh-server start-up",
+        config.server_start_timeout,
+        start_mosh_server(&session, config),
+    )
+    .await
+    .context(ExitStatus::ServerStart)?;
+
+    // Disconnect SSH
+    let _ = session
+        .disconnect(Disconnect::ByApplication, "mosh session started", "en")
+        .await;
+
+    if let Some(ref version) = reply.version {
+        log::debug!("SSH: remote mosh-server version {}", version);
+    }
+    for warning in &reply.warnings {
+        eprintln!("mosh-server: {}", warning);
+    }
+
+    Ok(MoshSession {
+        port: reply.port,
+        key: reply.key,
+        remote_ip: reply
+            .ip
+            .map(|ip| ip.to_string())
+            .unwrap_or_else(|| config.host.clone()),
+    })
+}
+
+// ── Deadlines ───────────────────────────────────────────────────────────────
+
+/// Prompt bookkeeping for one bootstrap, so deadlines can tell that time
+/// went to the user rather than the server.
+#[derive(Default)]
+struct PromptActivity {
+    /// Bumped whenever a prompt starts or finishes.
+    changes: AtomicUsize,
+    /// Number of prompts currently waiting for the user.
+    open: AtomicUsize,
+}
+
+impl PromptActivity {
+    /// Mark a prompt as in progress until the returned guard is dropped.
+    fn prompt(&self) -> PromptGuard<'_> {
+        self.open.fetch_add(1, Ordering::SeqCst);
+        self.changes.fetch_add(1, Ordering::SeqCst);
+        PromptGuard(self)
+    }
+
+    fn changes(&self) -> usize {
+        self.changes.load(Ordering::SeqCst)
+    }
+
+    /// Whether a prompt was open at any point since `changes()` returned `before`.
+    fn prompted_since(&self, before: usize) -> bool {
+        self.open.load(Ordering::SeqCst) > 0 || self.changes() != before
+    }
+}
+
+/// Marks a prompt as in progress for as long as it lives.
+struct PromptGuard<'a>(&'a PromptActivity);
+
+impl Drop for PromptGuard<'_> {
+    fn drop(&mut self) {
+        self.0.open.fetch_sub(1, Ordering::SeqCst);
+        self.0.changes.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// Run one bootstrap phase, failing if it makes no progress for `limit`.
+///
+/// Time spent at interactive prompts doesn't count: if a prompt was shown
+/// while the clock ran, the phase gets a fresh `limit`.
+async fn with_deadline<T>(
+    prompts: &PromptActivity,
+    phase: &str,
+    limit: Duration,
+    fut: impl std::future::Future<Output = Result<T>>,
+) -> Result<T> {
+    let mut fut = std::pin::pin!(fut);
+    loop {
+        let before = prompts.changes();
+        match tokio::time::timeout(limit, &mut fut).await {
+            Ok(result) => return result,
+            Err(_) if prompts.prompted_since(before) => continue,
+            Err(_) => bail!("{} timed out after {:?}", phase, limit),
+        }
+    }
+}
+
+/// Turn terminal echo back on, in case the process is interrupted at a
+/// password prompt.
+#[cfg(windows)]
+pub fn restore_terminal_echo() {
+    use std::os::windows::io::AsRawHandle;
+    use windows_sys::Win32::System::Console::*;
+    let handle = std::io::stdin().as_raw_handle();
+    let mut mode: u32 = 0;
+    unsafe {
+        if GetConsoleMode(handle, &mut mode) != 0 {
+            SetConsoleMode(handle, mode | ENABLE_ECHO_INPUT | ENABLE_LINE_INPUT);
+        }
+    }
+}
+
+/// Turn terminal echo back on, in case the process is interrupted at a
+/// password prompt.
+#[cfg(unix)]
+pub fn restore_terminal_echo() {
+    use std::os::unix::io::AsRawFd;
+    let fd = std::io::stdin().as_raw_fd();
+    let mut attrs: libc::termios = unsafe { std::mem::zeroed() };
+    unsafe {
+        if libc::tcgetattr(fd, &mut attrs) == 0 {
+            attrs.c_lflag |= libc::ECHO;
+            libc::tcsetattr(fd, libc::TCSANOW, &attrs);
+        }
+    }
+}
+
+// ── Starting mosh-server ────────────────────────────────────────────────────
+
+/// Directories searched for mosh-server when it isn't on the non-login PATH.
+const SERVER_SEARCH_DIRS: &[&str] = &[
+    "$HOME/.local/bin",
+    "/usr/local/bin",
+    "/opt/homebrew/bin",
+    "/opt/local/bin",
+    "/snap/bin",
+    "/var/lib/snapd/snap/bin",
+    "$HOME/.nix-profile/bin",
+    "/run/current-system/sw/bin",
+];
+
+/// Extra requests to make on an exec channel before running the command.
+#[derive(Default, Clone, Copy)]
+struct ChannelSetup {
+    pty: bool,
+    forward_agent: bool,
+}
+
+/// Everything a remote command produced.
+struct RemoteOutput {
+    /// Holds the session key after a mosh-server run, so it's wiped on drop.
+    stdout: Zeroizing<Vec<u8>>,
+    stderr: Vec<u8>,
+    exit_status: Option<u32>,
+}
+
+impl RemoteOutput {
+    /// Whether the remote shell couldn't find `name`: exit status 127 and
+    /// the shell's complaint about that very command, so a mosh-server that
+    /// fails for its own reasons isn't mistaken for a missing one.
+    fn command_not_found(&self, name: &str) -> bool {
+        if self.exit_status != Some(127) {
+            return false;
+        }
+        // With a PTY, the complaint arrives on stdout
+        [&self.stderr[..], &self.stdout[..]].iter().any(|stream| {
+            String::from_utf8_lossy(stream)
+                .lines()
+                .any(|line| complains_missing(line.trim_end(), name))
+        })
+    }
+
+    /// First non-empty stdout line, if the command succeeded.
+    fn first_line(&self) -> Option<String> {
+        if self.exit_status != Some(0) {
+            return None;
+        }
+        String::from_utf8_lossy(&self.stdout)
+            .lines()
+            .map(str::trim)
+            .find(|line| !line.is_empty())
+            .map(str::to_string)
+    }
+
+    /// Extract the connection details from a mosh-server run.
+    fn mosh_connect(&self) -> Result<ServerReply> {
+        let stdout = String::from_utf8_lossy(&self.stdout);
+        let stderr = String::from_utf8_lossy(&self.stderr);
+        if let Some(status) = self.exit_status.filter(|&s| s != 0) {
+            // With a PTY, the server's complaints arrive on stdout
+            bail!(
+                "mosh-server exited with status {}: {}{}",
+                status,
+                stderr.trim_end(),
+                strip_escapes(stdout.trim_end())
+            );
+        }
+        parse_server_reply(&stdout, &stderr)
+            .context("Failed to parse MOSH CONNECT response from mosh-server")
+    }
+}
+
+/// Whether `line` is a shell saying it can't find `name`, as in
+/// "sh: 1: NAME: not found", "bash: NAME: command not found",
+/// "zsh:1: command not found: NAME" or "fish: Unknown command: NAME".
+fn complains_missing(line: &str, name: &str) -> bool {
+    const MESSAGES: &[&str] = &[
+        "not found",
+        "command not found",
+        "Command not found",
+        "No such file or directory",
+        "Unknown command",
+    ];
+    if let Some((_, rest)) = line.split_once(&format!("{}: ", name)) {
+        return MESSAGES.iter().any(|m| rest.starts_with(m));
+    }
+    match line.strip_suffix(name).and_then(|l| l.strip_suffix(": ")) {
+        Some(before) => MESSAGES.iter().any(|m| before.ends_with(m)),
+        None => false,
+    }
+}
 
-    log::info!("SSH: executing: {}", server_cmd);
+/// Run `command` on a fresh exec channel, feeding it `stdin` if given.
+async fn run_remote<H: client::Handler>(
+    session: &client::Handle<H>,
+    command: &str,
+    setup: ChannelSetup,
+    stdin: Option<&[u8]>,
+) -> Result<RemoteOutput> {
+    log::info!("SSH: executing: {}", command);
 
     let mut channel = session
         .channel_open_session()
         .await
         .context("Failed to open SSH channel")?;
 
+    if setup.forward_agent {
+        channel
+            .agent_forward(false)
+            .await
+            .context("Failed to request agent forwarding")?;
+    }
+
+    if setup.pty {
+        let (cols, rows) = crossterm::terminal::size().unwrap_or((80, 24));
+        let term = std::env::var("TERM").unwrap_or_else(|_| "xterm-256color".to_string());
+        channel
+            .request_pty(false, &term, cols as u32, rows as u32, 0, 0, &[])
+            .await
+            .context("Failed to request PTY")?;
+    }
+
     channel
-        .exec(true, server_cmd.as_str())
+        .exec(true, command)
         .await
-        .context("Failed to execute mosh-server command")?;
+        .context("Failed to execute remote command")?;
 
-    // Collect output
-    let mut stdout_data = Vec::new();
-    let mut stderr_data = Vec::new();
+    if let Some(data) = stdin {
+        channel.data(data).await.context("Failed to send data")?;
+        channel.eof().await.context("Failed to send data")?;
+    }
 
-    loop {
-        let Some(msg) = channel.wait().await else {
-            break;
-        };
+    let mut output = RemoteOutput {
+        stdout: Zeroizing::new(Vec::new()),
+        stderr: Vec::new(),
+        exit_status: None,
+    };
+    let mut eof = false;
 
+    // The exit status may arrive on either side of EOF; wait for both
+    while let Some(msg) = channel.wait().await {
         match msg {
-            ChannelMsg::Data { ref data } => {
-                stdout_data.extend_from_slice(data);
-            }
-            ChannelMsg::ExtendedData { ref data, ext } => {
-                if ext == 1 {
-                    stderr_data.extend_from_slice(data);
-                }
-            }
-            ChannelMsg::ExitStatus { exit_status } => {
-                if exit_status != 0 {
-                    let stderr_str = String::from_utf8_lossy(&stderr_data);
-                    bail!(
-                        "mosh-server exited with status {}: {}",
-                        exit_status,
-                        stderr_str
-                    );
-                }
+            ChannelMsg::Data { ref data } => output.stdout.extend_from_slice(data),
+            ChannelMsg::ExtendedData { ref data, ext: 1 } => {
+                output.stderr.extend_from_slice(data)
             }
-            ChannelMsg::Eof => break,
+            ChannelMsg::ExitStatus { exit_status } => output.exit_status = Some(exit_status),
+            ChannelMsg::Eof => eof = true,
             _ => {}
         }
+        if eof && output.exit_status.is_some() {
+            break;
+        }
     }
+    Ok(output)
+}
 
-    // Parse the MOSH CONNECT line from stdout
-    let stdout_str = String::from_utf8_lossy(&stdout_data);
-    let session_info = parse_mosh_connect(&stdout_str).context(
-        "Failed to parse MOSH CONNECT response from mosh-server. \
-         Is mosh-server installed on the remote host?",
-    )?;
+/// Start mosh-server, searching for it (and optionally uploading it) if the
+/// configured command isn't found.
+///
+/// Order: the command as given, then `SERVER_SEARCH_DIRS`, then a login shell
+/// (for PATH set in `.profile`), then the `server_binary` upload.
+async fn start_mosh_server<H: client::Handler>(
+    session: &client::Handle<H>,
+    config: &SshConfig,
+) -> Result<ServerReply> {
+    let setup = ChannelSetup {
+        pty: config.request_pty,
+        forward_agent: config.forward_agent,
+    };
+    let name = config.mosh_server_command.as_str();
 
-    // Disconnect SSH
-    let _ = session
-        .disconnect(Disconnect::ByApplication, "mosh session started", "en")
-        .await;
+    let output = run_remote(session, &mosh_server_command_line(config, name), setup, None).await?;
+    if !output.command_not_found(name) {
+        return output.mosh_connect();
+    }
 
-    Ok(MoshSession {
-        port: session_info.0,
-        key: session_info.1,
-        remote_ip: config.host.clone(),
+    if is_bare_command(name) {
+        eprintln!("SSH: {} is not on the remote PATH, searching common locations", name);
+        let probe = run_remote(session, &discovery_script(name), ChannelSetup::default(), None).await?;
+        if let Some(path) = probe.first_line() {
+            eprintln!("SSH: found {}", path);
+            let command = mosh_server_command_line(config, &shell_quote(&path));
+            return run_remote(session, &command, setup, None).await?.mosh_connect();
+        }
+
+        eprintln!("SSH: retrying {} in a login shell", name);
+        let command = login_shell_command(&mosh_server_command_line(config, name));
+        let output = run_remote(session, &command, setup, None).await?;
+        if !output.command_not_found(name) {
+            return output.mosh_connect();
+        }
+    }
+
+    if let Some(ref binary) = config.server_binary {
+        let path = upload_server_binary(session, binary).await?;
+        let command = mosh_server_command_line(config, &shell_quote(&path));
+        return run_remote(session, &command, setup, None).await?.mosh_connect();
+    }
+
+    bail!(
+        "{} was not found on the remote host (searched PATH, common install \
+         locations and a login shell). Install mosh there, or use --server PATH \
+         or --upload-server FILE.",
+        name
+    )
+}
+
+/// Copy a local mosh-server binary into the remote cache directory, unless an
+/// identical one is already there. Returns the remote path.
+async fn upload_server_binary<H: client::Handler>(
+    session: &client::Handle<H>,
+    binary: &Path,
+) -> Result<String> {
+    let data = std::fs::read(binary)
+        .with_context(|| format!("failed to read server binary '{}'", binary.display()))?;
+    let digest: String = {
+        use sha1::Digest as _;
+        Sha1::digest(&data)[..8].iter().map(|b| format!("{:02x}", b)).collect()
+    };
+    let target = format!(
+        "\"${{XDG_CACHE_HOME:-$HOME/.cache}}\"/mosh-client/mosh-server-{}",
+        digest
+    );
+
+    let cached = run_remote(
+        session,
+        &format!("t={}; [ -x \"$t\" ] && printf '%s\\n' \"$t\"", target),
+        ChannelSetup::default(),
+        None,
+    )
+    .await?;
+    if let Some(path) = cached.first_line() {
+        log::debug!("SSH: using cached server binary {}", path);
+        return Ok(path);
+    }
+
+    eprintln!(
+        "SSH: uploading {} ({} bytes) to the remote host",
+        binary.display(),
+        data.len()
+    );
+    let output = run_remote(session, &upload_script(&target), ChannelSetup::default(), Some(&data))
+        .await?;
+    output.first_line().with_context(|| {
+        format!(
+            "failed to upload server binary: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
     })
 }
 
+/// Whether `command` is a bare program name that a PATH search could find.
+fn is_bare_command(command: &str) -> bool {
+    !command.is_empty()
+        && command
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "._-".contains(c))
+}
+
+/// Shell snippet that prints the first `SERVER_SEARCH_DIRS` entry holding `name`.
+fn discovery_script(name: &str) -> String {
+    format!(
+        "for d in {}; do if [ -x \"$d/{}\" ]; then printf '%s\\n' \"$d/{}\"; exit 0; fi; done; exit 1",
+        SERVER_SEARCH_DIRS
+            .iter()
+            .map(|d| format!("\"{}\"", d))
+            .collect::<Vec<_>>()
+            .join(" "),
+        name,
+        name
+    )
+}
+
+/// Run `command` through the user's login shell, so profile PATH changes apply.
+fn login_shell_command(command: &str) -> String {
+    format!("exec \"${{SHELL:-/bin/sh}}\" -lc {}", shell_quote(command))
+}
+
+/// Shell snippet that stores stdin at `target` (atomically) and prints the path.
+fn upload_script(target: &str) -> String {
+    format!(
+        "set -e; t={}; mkdir -p \"$(dirname \"$t\")\"; cat > \"$t.$$\"; \
+         chmod 700 \"$t.$$\"; mv \"$t.$$\" \"$t\"; printf '%s\\n' \"$t\"",
+        target
+    )
+}
+
 // ── Authentication strategies ───────────────────────────────────────────────
 
 /// Try all authentication methods in order. Returns true on success.
-async fn authenticate(
-    session: &mut client::Handle<SshClient>,
+async fn authenticate<H: client::Handler>(
+    session: &mut client::Handle<H>,
     config: &SshConfig,
+    allow_password: bool,
+    prompts: &PromptActivity,
 ) -> Result<bool> {
     // 1. Explicit identity file (if -i was given)
     if let Some(ref identity_path) = config.identity_file {
         eprintln!("SSH: trying identity file {:?}", identity_path);
-        match try_key_file(session, &config.username, identity_path).await {
+        let cert_path = config.certificate_file.as_deref();
+        match try_key_file(session, &config.username, identity_path, cert_path, prompts).await {
             Ok(true) => return Ok(true),
             Ok(false) => eprintln!("SSH: key file rejected by server"),
             Err(e) => eprintln!("SSH: failed to load key file: {}", e),
@@ -331,11 +989,12 @@ async fn authenticate(
     }
 
     // 2. Explicit password (if --password was given)
-    if let Some(ref password) = config.password {
+    if let Some(password) = config.password.as_ref().filter(|_| allow_password) {
         eprintln!("SSH: trying password authentication");
         match session
             .authenticate_password(&config.username, password.as_str())
             .await
+            .map(|r| r.success())
         {
             Ok(true) => return Ok(true),
             Ok(false) => eprintln!("SSH: password rejected by server"),
@@ -357,7 +1016,7 @@ async fn authenticate(
         let key_path = ssh_dir.join(name);
         if key_path.exists() {
             eprintln!("SSH: trying key {}", key_path.display());
-            match try_key_file(session, &config.username, &key_path).await {
+            match try_key_file(session, &config.username, &key_path, None, prompts).await {
                 Ok(true) => return Ok(true),
                 Ok(false) => eprintln!("SSH: key {} rejected by server", name),
                 Err(e) => eprintln!("SSH: failed to load {}: {}", name, e),
@@ -365,15 +1024,30 @@ async fn authenticate(
         }
     }
 
-    // 5. Interactive password prompt (only if stdin is a terminal)
-    if atty_stdin() && config.password.is_none() {
-        for attempt in 1..=3 {
-            let prompt = format!("{}@{}'s password: ", config.username, config.host);
-            match read_password(&prompt) {
-                Some(password) if !password.is_empty() => {
+    // Never send secrets to a host whose key didn't match known_hosts
+    if !allow_password {
+        return Ok(false);
+    }
+
+    // 5. Keyboard-interactive (TOTP, Duo, PAM conversations; needs a terminal)
+    if atty_stdin() {
+        match try_keyboard_interactive(session, &config.username, &mut ConsoleResponder { prompts }).await {
+            Ok(true) => return Ok(true),
+            Ok(false) => {}
+            Err(e) => eprintln!("SSH: keyboard-interactive auth error: {}", e),
+        }
+    }
+
+    // 6. Interactive password prompt (terminal or askpass program)
+    if config.password.is_none() && (atty_stdin() || askpass_program().is_some()) {
+        for attempt in 1..=3 {
+            let prompt = format!("{}@{}'s password: ", config.username, config.host);
+            match ask_secret(prompts, &prompt) {
+                Some(password) if !password.is_empty() => {
                     match session
                         .authenticate_password(&config.username, password.as_str())
                         .await
+                        .map(|r| r.success())
                     {
                         Ok(true) => return Ok(true),
                         Ok(false) => {
@@ -395,14 +1069,17 @@ async fn authenticate(
     Ok(false)
 }
 
-/// Try authenticating with a key file.
-async fn try_key_file(
-    session: &mut client::Handle<SshClient>,
+/// Try authenticating with a key file, presenting its certificate first if one
+/// is available (`cert_path`, or the `<key>-cert.pub` companion file).
+async fn try_key_file<H: client::Handler>(
+    session: &mut client::Handle<H>,
     username: &str,
-    path: &std::path::Path,
+    path: &Path,
+    cert_path: Option<&Path>,
+    prompts: &PromptActivity,
 ) -> Result<bool> {
     // Try loading without passphrase first
-    let key_pair = match russh_keys::load_secret_key(path, None) {
+    let key_pair = match keys::load_secret_key(path, None) {
         Ok(kp) => kp,
         Err(initial_err) => {
             let err_str = initial_err.to_string();
@@ -416,9 +1093,9 @@ async fn try_key_file(
             }
 
             // Key is encrypted — prompt for passphrase (3 attempts, like OpenSSH)
-            if !atty_stdin() {
+            if !atty_stdin() && askpass_program().is_none() {
                 bail!(
-                    "Key '{}' is encrypted and no terminal for passphrase prompt",
+                    "Key '{}' is encrypted and no terminal or askpass program for passphrase prompt",
                     path.display()
                 );
             }
@@ -426,9 +1103,9 @@ async fn try_key_file(
             let mut loaded = None;
             for attempt in 1..=3 {
                 let prompt = format!("Enter passphrase for key '{}': ", path.display());
-                match read_password(&prompt) {
+                match ask_secret(prompts, &prompt) {
                     Some(passphrase) if !passphrase.is_empty() => {
-                        match russh_keys::load_secret_key(path, Some(&passphrase)) {
+                        match keys::load_secret_key(path, Some(&passphrase)) {
                             Ok(kp) => {
                                 loaded = Some(kp);
                                 break;
@@ -458,65 +1135,128 @@ async fn try_key_file(
         }
     };
 
+    let key_pair = Arc::new(key_pair);
+
+    let cert_path = cert_path
+        .map(Path::to_path_buf)
+        .or_else(|| companion_certificate(path));
+    if let Some(cert_path) = cert_path {
+        match load_user_certificate(&cert_path, &key_pair) {
+            Ok(cert) => {
+                eprintln!("SSH: offering certificate {}", cert_path.display());
+                if session
+                    .authenticate_openssh_cert(username, key_pair.clone(), cert)
+                    .await?
+                    .success()
+                {
+                    return Ok(true);
+                }
+                eprintln!("SSH: certificate rejected by server, trying bare key");
+            }
+            Err(e) => eprintln!("SSH: skipping certificate {}: {}", cert_path.display(), e),
+        }
+    }
+
+    let hash_alg = rsa_hash(session, key_pair.algorithm()).await;
     let result = session
-        .authenticate_publickey(username, Arc::new(key_pair))
+        .authenticate_publickey(username, PrivateKeyWithHashAlg::new(key_pair, hash_alg))
         .await?;
-    Ok(result)
+    Ok(result.success())
 }
 
-/// Try authenticating via SSH agent (Windows OpenSSH pipe, Pageant, or SSH_AUTH_SOCK).
-async fn try_ssh_agent(
-    session: &mut client::Handle<SshClient>,
-    username: &str,
-) -> Result<bool> {
-    // Try Windows OpenSSH agent (named pipe)
-    match try_openssh_agent().await {
-        Ok(agent) => {
-            eprintln!("SSH: trying Windows OpenSSH agent");
-            return try_agent_auth(session, username, agent).await;
-        }
-        Err(e) => {
-            log::debug!("SSH: Windows OpenSSH agent not available ({})", e);
-        }
+/// The signature hash to use with a key of type `algorithm`: the strongest
+/// RSA hash the server accepts, or `None` for key types with a fixed hash.
+async fn rsa_hash<H: client::Handler>(
+    session: &client::Handle<H>,
+    algorithm: Algorithm,
+) -> Option<HashAlg> {
+    if !algorithm.is_rsa() {
+        return None;
+    }
+    // Servers that don't list their signature algorithms still take SHA-512
+    match session.best_supported_rsa_hash().await {
+        Ok(Some(hash)) => hash,
+        _ => Some(HashAlg::Sha512),
+    }
+}
+
+/// OpenSSH's companion certificate path for a key file: `<key>-cert.pub`.
+fn companion_certificate(key_path: &Path) -> Option<PathBuf> {
+    let mut name = key_path.file_name()?.to_os_string();
+    name.push("-cert.pub");
+    let cert = key_path.with_file_name(name);
+    cert.exists().then_some(cert)
+}
+
+/// Load a user certificate and check it can be used with `key_pair` right now.
+fn load_user_certificate(path: &Path, key_pair: &PrivateKey) -> Result<Certificate> {
+    let cert = keys::load_openssh_certificate(path)
+        .with_context(|| format!("failed to parse certificate '{}'", path.display()))?;
+
+    if cert.cert_type() != CertType::User {
+        bail!("not a user certificate");
+    }
+
+    let now = unix_time();
+    if now < cert.valid_after() {
+        bail!("certificate is not yet valid");
+    }
+    if now >= cert.valid_before() {
+        bail!("certificate has expired");
     }
 
-    // Try Pageant (PuTTY agent).
-    // The `pageant` crate panics (unwrap) if Pageant isn't running, so we
-    // MUST check that the Pageant window exists before calling connect_pageant().
-    if is_pageant_running() {
-        eprintln!("SSH: trying Pageant agent");
-        let agent = russh_keys::agent::client::AgentClient::connect_pageant().await;
-        match try_agent_auth(session, username, agent).await {
+    if cert.public_key() != key_pair.public_key().key_data() {
+        bail!("certificate does not match the private key");
+    }
+
+    Ok(cert)
+}
+
+/// Seconds since the Unix epoch, the clock certificates are checked against.
+fn unix_time() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Try authenticating via each SSH agent available on this platform
+/// (Windows OpenSSH pipe and Pageant, or SSH_AUTH_SOCK on Unix).
+async fn try_ssh_agent<H: client::Handler>(
+    session: &mut client::Handle<H>,
+    username: &str,
+) -> Result<bool> {
+    for source in agent::sources() {
+        match try_agent_source(session, username, &source).await {
             Ok(true) => return Ok(true),
             Ok(false) => {}
-            Err(e) => log::debug!("Pageant auth failed: {}", e),
+            Err(e) => log::debug!("SSH: {} not usable ({})", source, e),
         }
     }
-
-    // SSH_AUTH_SOCK is not available on native Windows (Unix sockets only).
-    // The Windows OpenSSH agent and Pageant paths above cover Windows agents.
-
     Ok(false)
 }
 
-/// Try to connect to the Windows OpenSSH agent named pipe.
-async fn try_openssh_agent(
-) -> Result<russh_keys::agent::client::AgentClient<tokio::net::windows::named_pipe::NamedPipeClient>>
-{
-    let agent =
-        russh_keys::agent::client::AgentClient::connect_named_pipe(OPENSSH_AGENT_PIPE).await?;
-    Ok(agent)
+/// Connect to one agent and try the keys it holds.
+async fn try_agent_source<H: client::Handler>(
+    session: &mut client::Handle<H>,
+    username: &str,
+    source: &AgentSource,
+) -> Result<bool> {
+    let agent = source.connect().await?;
+    eprintln!("SSH: trying {}", source);
+    try_agent_auth(session, username, agent).await
 }
 
-/// Authenticate using an SSH agent by trying each key the agent offers.
-async fn try_agent_auth<
-    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
->(
-    session: &mut client::Handle<SshClient>,
+/// Authenticate using an SSH agent by trying each key and certificate the
+/// agent offers, in the agent's order.
+async fn try_agent_auth<H: client::Handler>(
+    session: &mut client::Handle<H>,
     username: &str,
-    mut agent: russh_keys::agent::client::AgentClient<S>,
+    agent: DynAgentClient,
 ) -> Result<bool> {
-    let identities = agent.request_identities().await?;
+    let (mut agent, identities) = agent::list_identities(agent)
+        .await
+        .context("failed to list agent identities")?;
     if identities.is_empty() {
         return Ok(false);
     }
@@ -524,14 +1264,29 @@ async fn try_agent_auth<
     eprintln!("SSH: agent has {} key(s)", identities.len());
 
     for identity in identities {
-        log::debug!("SSH: trying agent key: {}", identity.name());
-        let (returned_agent, auth_result) = session
-            .authenticate_future(username, identity, agent)
-            .await;
-        agent = returned_agent;
-        match auth_result {
-            Ok(true) => return Ok(true),
-            Ok(false) => continue,
+        let result = match identity {
+            AgentIdentity::PublicKey { key, comment } => {
+                log::debug!("SSH: trying agent key {} {:?}", key.algorithm(), comment);
+                let hash_alg = rsa_hash(session, key.algorithm()).await;
+                session
+                    .authenticate_publickey_with(username, key, hash_alg, &mut agent)
+                    .await
+            }
+            AgentIdentity::Certificate { certificate, comment } => {
+                log::debug!(
+                    "SSH: trying agent certificate {} {:?}",
+                    certificate.algorithm(),
+                    comment
+                );
+                let hash_alg = rsa_hash(session, certificate.algorithm()).await;
+                session
+                    .authenticate_certificate_with(username, certificate, hash_alg, &mut agent)
+                    .await
+            }
+        };
+        match result {
+            Ok(r) if r.success() => return Ok(true),
+            Ok(_) => continue,
             Err(e) => {
                 log::debug!("SSH: agent key failed: {}", e);
                 continue;
@@ -542,10 +1297,120 @@ async fn try_agent_auth<
     Ok(false)
 }
 
+/// Supplies answers to keyboard-interactive (RFC 4256) info requests.
+trait KbdInteractiveResponder {
+    /// Answer one info request with exactly one response per prompt.
+    /// Returning `None` abandons the exchange.
+    fn respond(
+        &mut self,
+        name: &str,
+        instructions: &str,
+        prompts: &[client::Prompt],
+    ) -> Option<Vec<Zeroizing<String>>>;
+}
+
+/// Renders server challenges on the console and reads answers from stdin.
+struct ConsoleResponder<'a> {
+    prompts: &'a PromptActivity,
+}
+
+impl KbdInteractiveResponder for ConsoleResponder<'_> {
+    fn respond(
+        &mut self,
+        name: &str,
+        instructions: &str,
+        prompts: &[client::Prompt],
+    ) -> Option<Vec<Zeroizing<String>>> {
+        // Same layout as OpenSSH: name and instructions first, then each prompt.
+        if !name.is_empty() {
+            eprintln!("{}", name);
+        }
+        if !instructions.is_empty() {
+            eprintln!("{}", instructions);
+        }
+
+        let mut responses = Vec::with_capacity(prompts.len());
+        for p in prompts {
+            let answer = if p.echo {
+                Zeroizing::new(read_line(self.prompts, &p.prompt)?)
+            } else {
+                read_password(self.prompts, &p.prompt)?
+            };
+            responses.push(answer);
+        }
+        Some(responses)
+    }
+}
+
+/// Run keyboard-interactive exchanges (up to 3 attempts, like OpenSSH's
+/// NumberOfPasswordPrompts). Returns true on success.
+async fn try_keyboard_interactive<H: client::Handler>(
+    session: &mut client::Handle<H>,
+    username: &str,
+    responder: &mut dyn KbdInteractiveResponder,
+) -> Result<bool> {
+    use client::KeyboardInteractiveAuthResponse as Reply;
+
+    for attempt in 1..=3 {
+        let mut prompted = false;
+        let mut reply = session
+            .authenticate_keyboard_interactive_start(username, None)
+            .await?;
+
+        loop {
+            match reply {
+                Reply::Success => return Ok(true),
+                Reply::Failure { .. } => break,
+                Reply::InfoRequest {
+                    name,
+                    instructions,
+                    prompts,
+                } => {
+                    log::debug!(
+                        "SSH: keyboard-interactive request '{}' with {} prompt(s)",
+                        name,
+                        prompts.len()
+                    );
+                    prompted |= !prompts.is_empty();
+                    let Some(mut responses) = responder.respond(&name, &instructions, &prompts)
+                    else {
+                        return Ok(false);
+                    };
+                    if responses.len() != prompts.len() {
+                        bail!(
+                            "keyboard-interactive: {} response(s) for {} prompt(s)",
+                            responses.len(),
+                            prompts.len()
+                        );
+                    }
+                    // russh wants plain strings: move each answer straight into
+                    // the request, so no unwiped copy is left behind here.
+                    let answers = responses.iter_mut().map(|r| std::mem::take(&mut **r)).collect();
+                    drop(responses);
+                    reply = session
+                        .authenticate_keyboard_interactive_respond(answers)
+                        .await?;
+                }
+            }
+        }
+
+        // A server without keyboard-interactive fails before asking anything;
+        // there is nothing to retry in that case.
+        if !prompted {
+            return Ok(false);
+        }
+        if attempt < 3 {
+            eprintln!("Permission denied, please try again.");
+        }
+    }
+
+    Ok(false)
+}
+
 // ── Helpers ─────────────────────────────────────────────────────────────────
 
-/// Translate cryptic russh_keys errors into human-readable messages.
-fn friendly_key_error(e: &russh_keys::Error) -> String {
+/// Translate cryptic key loading errors into human-readable messages.
+fn friendly_key_error(e: &keys::Error) -> String {
     let raw = e.to_string();
     if raw.contains("Unpad") || raw.contains("unpad") || raw.contains("padding") {
         format!(
@@ -566,38 +1431,434 @@ fn friendly_key_error(e: &russh_keys::Error) -> String {
     }
 }
 
-/// Parse the "MOSH CONNECT <port> <key>" line from mosh-server output.
-fn parse_mosh_connect(output: &str) -> Result<(u16, String)> {
-    for line in output.lines() {
+/// Host keys from known_hosts lines carrying a `@revoked` or `@cert-authority` marker.
+#[derive(Default)]
+struct MarkedHostKeys {
+    revoked: Vec<PublicKey>,
+    cert_authorities: Vec<PublicKey>,
+}
+
+/// Collect marked known_hosts entries that apply to `host:port`.
+///
+/// Plain entries are left to `keys::check_known_hosts_path`, which
+/// ignores marker lines entirely.
+fn marked_host_keys(path: &Path, host: &str, port: u16) -> MarkedHostKeys {
+    let mut marked = MarkedHostKeys::default();
+    let Ok(contents) = std::fs::read_to_string(path) else {
+        return marked;
+    };
+    let host_port = if port == 22 {
+        host.to_string()
+    } else {
+        format!("[{}]:{}", host, port)
+    };
+
+    for line in contents.lines() {
+        let mut fields = line.split_whitespace();
+        let (Some(marker), Some(patterns), Some(_algo), Some(blob)) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            continue;
+        };
+        let list = match marker {
+            "@revoked" => &mut marked.revoked,
+            "@cert-authority" => &mut marked.cert_authorities,
+            _ => continue,
+        };
+        if !host_patterns_match(patterns, &host_port) {
+            continue;
+        }
+        match keys::parse_public_key_base64(blob) {
+            Ok(k) => list.push(k),
+            Err(e) => log::debug!("known_hosts: skipping {} entry: {}", marker, e),
+        }
+    }
+    marked
+}
+
+/// Check that `cert` is a host certificate for `host`, valid at `now`, and
+/// signed by one of `authorities`.
+fn check_host_certificate(
+    cert: &Certificate,
+    authorities: &[PublicKey],
+    host: &str,
+    now: u64,
+) -> Result<()> {
+    if cert.cert_type() != CertType::Host {
+        bail!("not a host certificate");
+    }
+    let Some(ca) = authorities
+        .iter()
+        .find(|ca| ca.key_data() == cert.signature_key())
+    else {
+        bail!("signed by an unknown certificate authority");
+    };
+    if now < cert.valid_after() {
+        bail!("certificate is not yet valid");
+    }
+    if now >= cert.valid_before() {
+        bail!("certificate has expired");
+    }
+    cert.validate_at(now, [&ca.fingerprint(HashAlg::Sha256)])
+        .context("bad certificate authority signature")?;
+    let principals = cert.valid_principals();
+    if !principals.is_empty() && !principals.iter().any(|p| p.eq_ignore_ascii_case(host)) {
+        bail!("not valid for this host name");
+    }
+    Ok(())
+}
+
+/// Match a comma-separated known_hosts pattern list (`*`, `?`, `!negation`).
+pub fn host_patterns_match(patterns: &str, host: &str) -> bool {
+    let mut matched = false;
+    for pattern in patterns.split(',') {
+        if pattern.starts_with("|1|") {
+            if hashed_host_matches(pattern, host) {
+                matched = true;
+            }
+        } else if let Some(negated) = pattern.strip_prefix('!') {
+            if wildcard_match(negated, host) {
+                return false;
+            }
+        } else if wildcard_match(pattern, host) {
+            matched = true;
+        }
+    }
+    matched
+}
+
+/// Glob match supporting `*` (any run) and `?` (one character), case-insensitive.
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let p: Vec<char> = pattern.to_lowercase().chars().collect();
+    let t: Vec<char> = text.to_lowercase().chars().collect();
+    let (mut pi, mut ti) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+    while ti < t.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == t[ti]) {
+            pi += 1;
+            ti += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            backtrack = Some((pi, ti));
+            pi += 1;
+        } else if let Some((star, matched)) = backtrack {
+            pi = star + 1;
+            ti = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|&c| c == '*')
+}
+
+/// Check a hashed known_hosts entry (`|1|base64(salt)|base64(HMAC-SHA1(salt, host))`).
+fn hashed_host_matches(entry: &str, host: &str) -> bool {
+    use base64::Engine as _;
+    let mut parts = entry.split('|').skip(2);
+    let (Some(salt), Some(hash)) = (parts.next(), parts.next()) else {
+        return false;
+    };
+    let engine = base64::engine::general_purpose::STANDARD;
+    let (Ok(salt), Ok(hash)) = (engine.decode(salt), engine.decode(hash)) else {
+        return false;
+    };
+    let Ok(mac) = Hmac::<Sha1>::new_from_slice(&salt) else {
+        return false;
+    };
+    mac.chain_update(host.as_bytes()).verify_slice(&hash).is_ok()
+}
+
+/// Hash a known_hosts host name the way `ssh-keygen -H` does, with a fresh salt.
+fn hash_host_name(host: &str) -> String {
+    use base64::Engine as _;
+    use rand::RngCore as _;
+    let mut salt = [0u8; 20];
+    rand::rngs::OsRng.fill_bytes(&mut salt);
+    let mac = Hmac::<Sha1>::new_from_slice(&salt)
+        .expect("HMAC accepts any key length")
+        .chain_update(host.as_bytes())
+        .finalize()
+        .into_bytes();
+    let engine = base64::engine::general_purpose::STANDARD;
+    format!("|1|{}|{}", engine.encode(salt), engine.encode(mac))
+}
+
+/// Append a host key to a known_hosts file, optionally hashing the host name.
+fn learn_host_key(
+    path: &Path,
+    host: &str,
+    port: u16,
+    server_public_key: &PublicKey,
+    hash: bool,
+) -> Result<()> {
+    use russh::keys::PublicKeyBase64 as _;
+    use std::io::{Read as _, Seek as _, SeekFrom};
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut file = std::fs::OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(path)?;
+
+    // Don't glue our entry onto a last line that lacks its newline
+    let mut last = [0u8; 1];
+    let needs_newline = file.seek(SeekFrom::End(-1)).is_ok()
+        && file.read_exact(&mut last).is_ok()
+        && last[0] != b'\n';
+
+    let host_port = if port == 22 {
+        host.to_string()
+    } else {
+        format!("[{}]:{}", host, port)
+    };
+    let name = if hash {
+        hash_host_name(&host_port)
+    } else {
+        host_port
+    };
+    let entry = format!(
+        "{}{} {} {}\n",
+        if needs_newline { "\n" } else { "" },
+        name,
+        server_public_key.algorithm(),
+        server_public_key.public_key_base64()
+    );
+    file.write_all(entry.as_bytes())?;
+    Ok(())
+}
+
+/// System-wide known hosts file, if the platform has a conventional one.
+#[cfg(windows)]
+fn default_global_known_hosts() -> Option<PathBuf> {
+    std::env::var_os("PROGRAMDATA").map(|p| PathBuf::from(p).join("ssh").join("ssh_known_hosts"))
+}
+
+/// System-wide known hosts file, if the platform has a conventional one.
+#[cfg(unix)]
+fn default_global_known_hosts() -> Option<PathBuf> {
+    Some(PathBuf::from("/etc/ssh/ssh_known_hosts"))
+}
+
+/// Locale variables to forward to mosh-server, like upstream mosh's `-l` handling.
+///
+/// Falls back to `LANG=en_US.UTF-8` when no locale is set locally (the usual
+/// case on Windows), since mosh-server refuses to start without a UTF-8 locale.
+/// Variables that aren't valid Unicode are skipped.
+fn local_locale_env(
+    vars: impl Iterator<Item = (std::ffi::OsString, std::ffi::OsString)>,
+) -> Vec<(String, String)> {
+    let mut env: Vec<(String, String)> = vars
+        .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+        .filter(|(name, value)| {
+            !value.is_empty() && (name == "LANG" || name == "LANGUAGE" || name.starts_with("LC_"))
+        })
+        .collect();
+    if env.is_empty() {
+        env.push(("LANG".to_string(), "en_US.UTF-8".to_string()));
+    }
+    env.sort();
+    env
+}
+
+/// Parse a mosh-server port specification, `PORT` or `PORT:PORT2`.
+pub fn parse_port_range(spec: &str) -> Result<(u16, u16)> {
+    let parse = |s: &str| -> Result<u16> {
+        match s.parse::<u16>() {
+            Ok(port) if port != 0 => Ok(port),
+            _ => bail!("invalid port '{}' in '{}'", s, spec),
+        }
+    };
+    let (low, high) = match spec.split_once(':') {
+        Some((low, high)) => (parse(low)?, parse(high)?),
+        None => {
+            let port = parse(spec)?;
+            (port, port)
+        }
+    };
+    if low > high {
+        bail!("invalid port range '{}': {} is greater than {}", spec, low, high);
+    }
+    Ok((low, high))
+}
+
+/// Quote a word for a POSIX shell, leaving it bare when that's safe.
+///
+/// `=` is only safe after the first character: zsh expands a leading `=cmd`
+/// to the path of `cmd`.
+fn shell_quote(word: &str) -> String {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "@%+=:,./_-".contains(c);
+    if !word.is_empty() && !word.starts_with('=') && word.chars().all(is_safe) {
+        word.to_string()
+    } else {
+        format!("'{}'", word.replace('\'', r"'\''"))
+    }
+}
+
+/// Build the remote command that starts mosh-server as `server`.
+///
+/// The server command itself is sent as written (it may be a path with `~`
+/// or a wrapper like `sudo -u x mosh-server`); every argument is quoted.
+fn mosh_server_command_line(config: &SshConfig, server: &str) -> String {
+    let mut args = config.mosh_server_args.clone();
+    if let Some((low, high)) = config.udp_port_range {
+        args.push("-p".to_string());
+        args.push(if low == high {
+            low.to_string()
+        } else {
+            format!("{}:{}", low, high)
+        });
+    }
+    for (name, value) in &config.server_env {
+        args.push("-l".to_string());
+        args.push(format!("{}={}", name, value));
+    }
+    if !config.remote_command.is_empty() {
+        args.push("--".to_string());
+        args.extend(config.remote_command.iter().cloned());
+    }
+
+    let mut command = server.to_string();
+    for arg in &args {
+        command.push(' ');
+        command.push_str(&shell_quote(arg));
+    }
+    command
+}
+
+/// What mosh-server reported when it started.
+#[derive(Debug)]
+struct ServerReply {
+    port: u16,
+    key: Base64Key,
+    /// Address from a `MOSH IP` line, if the remote side printed one.
+    ip: Option<IpAddr>,
+    /// Version from the `mosh-server (mosh X.Y.Z)` banner.
+    version: Option<String>,
+    /// Locale and other warnings worth showing to the user.
+    warnings: Vec<String>,
+}
+
+/// Parse mosh-server's start-up output.
+///
+/// Login shells, MOTDs and rc files can print anything around the lines we
+/// want, and with a PTY stderr arrives on stdout with `\r\n` endings and
+/// colour codes, so every line of both streams is cleaned up and scanned.
+/// The first `MOSH CONNECT` line whose port and key are valid wins.
+fn parse_server_reply(stdout: &str, stderr: &str) -> Result<ServerReply> {
+    let mut connect = None;
+    let mut rejected = None;
+    let mut ip = None;
+    let mut version = None;
+    let mut warnings = Vec::new();
+
+    for raw in stdout.lines().chain(stderr.lines()) {
+        let line = strip_escapes(raw);
         let line = line.trim();
-        if line.starts_with("MOSH CONNECT ") {
-            let parts: Vec<&str> = line.split_whitespace().collect();
-            if parts.len() >= 4 {
-                let port: u16 = parts[2]
-                    .parse()
-                    .context("Invalid port in MOSH CONNECT")?;
-                let key = parts[3].to_string();
-                return Ok((port, key));
+
+        if let Some(rest) = line.strip_prefix("MOSH CONNECT ") {
+            if connect.is_none() {
+                match parse_connect_fields(rest) {
+                    Ok(fields) => connect = Some(fields),
+                    Err(e) => rejected = rejected.or(Some(e)),
+                }
             }
+        } else if let Some(rest) = line.strip_prefix("MOSH IP ") {
+            match rest.trim().parse::<IpAddr>() {
+                Ok(addr) => ip = ip.or(Some(addr)),
+                Err(_) => log::debug!("Ignoring malformed MOSH IP line: {}", line),
+            }
+        } else if let Some(rest) = line.strip_prefix("mosh-server (mosh ") {
+            version = rest.split(')').next().map(str::to_string);
+        } else if is_server_warning(line) && !warnings.iter().any(|w| w == line) {
+            warnings.push(line.to_string());
         }
     }
-    bail!(
-        "No 'MOSH CONNECT' line found in mosh-server output:\n{}",
-        output
-    )
+
+    match connect {
+        Some((port, key)) => Ok(ServerReply {
+            port,
+            key,
+            ip,
+            version,
+            warnings,
+        }),
+        None => match rejected {
+            Some(e) => Err(e),
+            None if !warnings.is_empty() => {
+                bail!("No 'MOSH CONNECT' line from mosh-server:\n{}", warnings.join("\n"))
+            }
+            None => bail!(
+                "No 'MOSH CONNECT' line found in mosh-server output:\n{}{}",
+                stdout,
+                stderr
+            ),
+        },
+    }
 }
 
-/// Check if PuTTY's Pageant is running by looking for its window.
-fn is_pageant_running() -> bool {
-    unsafe {
-        let class_name = b"Pageant\0";
-        let window_name = b"Pageant\0";
-        let hwnd = windows_sys::Win32::UI::WindowsAndMessaging::FindWindowA(
-            class_name.as_ptr(),
-            window_name.as_ptr(),
-        );
-        !hwnd.is_null()
+/// Parse the `<port> <key>` part of a `MOSH CONNECT` line.
+pub fn parse_connect_fields(rest: &str) -> Result<(u16, Base64Key)> {
+    let mut fields = rest.split_whitespace();
+    let (Some(port), Some(key), None) = (fields.next(), fields.next(), fields.next()) else {
+        bail!("Malformed MOSH CONNECT line: expected a port and a key");
+    };
+    let port: u16 = port.parse().context("Invalid port in MOSH CONNECT")?;
+    if port == 0 {
+        bail!("Invalid port in MOSH CONNECT: 0");
+    }
+    let key = Base64Key::from_str(key).context("Invalid key in MOSH CONNECT")?;
+    Ok((port, key))
+}
+
+/// Whether a line of mosh-server output is a warning the user should see.
+fn is_server_warning(line: &str) -> bool {
+    let lower = line.to_ascii_lowercase();
+    lower.contains("locale")
+        || lower.contains("utf-8")
+        || lower.starts_with("warning")
+}
+
+/// Remove ANSI escape sequences (CSI, OSC and two-byte escapes) from a line.
+/// Lines without escapes (the usual `MOSH CONNECT`) are borrowed, not copied.
+fn strip_escapes(line: &str) -> Cow<'_, str> {
+    if !line.contains('\x1b') {
+        return Cow::Borrowed(line);
     }
+    let mut out = String::with_capacity(line.len());
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            // CSI: parameters, then a final byte in @..~
+            Some('[') => {
+                for c in chars.by_ref() {
+                    if ('@'..='~').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            // OSC: runs until BEL or ST (ESC \)
+            Some(']') => {
+                while let Some(c) = chars.next() {
+                    if c == '\x07' {
+                        break;
+                    }
+                    if c == '\x1b' && chars.peek() == Some(&'\\') {
+                        chars.next();
+                        break;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Cow::Owned(out)
 }
 
 /// Get the user's ~/.ssh directory (using the correct Windows path).
@@ -608,16 +1869,17 @@ fn ssh_dir() -> PathBuf {
 }
 
 /// Get the user's home directory.
-fn home_dir() -> Option<PathBuf> {
+pub fn home_dir() -> Option<PathBuf> {
     std::env::var_os("USERPROFILE")
         .or_else(|| std::env::var_os("HOME"))
         .map(PathBuf::from)
 }
 
 /// Check if stdin is a terminal (for interactive prompts).
+#[cfg(windows)]
 fn atty_stdin() -> bool {
     use std::os::windows::io::AsRawHandle;
-    let handle = std::io::stdin().as_raw_handle() as *mut core::ffi::c_void;
+    let handle = std::io::stdin().as_raw_handle();
     // GetConsoleMode succeeds only for console handles
     let mut mode: u32 = 0;
     unsafe {
@@ -625,12 +1887,20 @@ fn atty_stdin() -> bool {
     }
 }
 
+/// Check if stdin is a terminal (for interactive prompts).
+#[cfg(unix)]
+fn atty_stdin() -> bool {
+    use std::io::IsTerminal;
+    std::io::stdin().is_terminal()
+}
+
 /// Prompt the user for a yes/no confirmation. Returns true if "yes".
-fn confirm_prompt(prompt: &str) -> bool {
+fn confirm_prompt(prompts: &PromptActivity, prompt: &str) -> bool {
     if !atty_stdin() {
         // Non-interactive: reject by default (safe)
         return false;
     }
+    let _prompt = prompts.prompt();
     eprint!("{}", prompt);
     let _ = std::io::stderr().flush();
     let mut input = String::new();
@@ -642,15 +1912,121 @@ fn confirm_prompt(prompt: &str) -> bool {
     }
 }
 
+/// Strip a trailing `\n` or `\r\n` in place, so secrets aren't copied.
+fn trim_line_ending(line: &mut String) {
+    let len = line.trim_end_matches(&['\r', '\n'][..]).len();
+    line.truncate(len);
+}
+
+// ── Secret sources ─────────────────────────────────────────────────────────
+
+/// Ask for a password or passphrase: through the askpass program if one
+/// applies, otherwise on the terminal with echo off.
+fn ask_secret(prompts: &PromptActivity, prompt: &str) -> Option<Zeroizing<String>> {
+    match askpass_program() {
+        Some(program) => run_askpass(prompts, &program, prompt),
+        None => read_password(prompts, prompt),
+    }
+}
+
+/// The askpass program to use for secret prompts, if any.
+fn askpass_program() -> Option<PathBuf> {
+    select_askpass(|name| std::env::var(name).ok(), atty_stdin())
+}
+
+/// `MOSH_ASKPASS` always applies. `SSH_ASKPASS` follows OpenSSH: it is used
+/// without a terminal, or always when `SSH_ASKPASS_REQUIRE` is `force` or
+/// `prefer`, and never when it is `never`.
+fn select_askpass(var: impl Fn(&str) -> Option<String>, tty: bool) -> Option<PathBuf> {
+    if let Some(program) = var("MOSH_ASKPASS").filter(|p| !p.is_empty()) {
+        return Some(PathBuf::from(program));
+    }
+    let program = var("SSH_ASKPASS").filter(|p| !p.is_empty())?;
+    let wanted = match var("SSH_ASKPASS_REQUIRE").as_deref() {
+        Some("force") | Some("prefer") => true,
+        Some("never") => false,
+        _ => !tty,
+    };
+    wanted.then(|| PathBuf::from(program))
+}
+
+/// Run an askpass program with `prompt` as its argument and read the secret
+/// from the first line of its output. A non-zero exit means "cancelled".
+fn run_askpass(prompts: &PromptActivity, program: &Path, prompt: &str) -> Option<Zeroizing<String>> {
+    let _prompt = prompts.prompt();
+    let output = match std::process::Command::new(program)
+        .arg(prompt)
+        .stdin(std::process::Stdio::null())
+        .stderr(std::process::Stdio::inherit())
+        .output()
+    {
+        Ok(output) => output,
+        Err(e) => {
+            eprintln!("SSH: failed to run askpass program {}: {}", program.display(), e);
+            return None;
+        }
+    };
+    let stdout = Zeroizing::new(output.stdout);
+    if !output.status.success() {
+        return None;
+    }
+    let line = stdout.split(|&b| b == b'\n').next().unwrap_or_default();
+    let mut secret = Zeroizing::new(String::from_utf8_lossy(line).into_owned());
+    trim_line_ending(&mut secret);
+    Some(secret)
+}
+
+/// Read a password from the first line of `path`, the `--password-file` source.
+pub fn read_password_file(path: &Path) -> Result<Zeroizing<String>> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        if let Ok(meta) = std::fs::metadata(path) {
+            if meta.mode() & 0o077 != 0 {
+                eprintln!(
+                    "Warning: password file {} is accessible by other users",
+                    path.display()
+                );
+            }
+        }
+    }
+    let contents = Zeroizing::new(
+        std::fs::read(path)
+            .with_context(|| format!("Failed to read password file {}", path.display()))?,
+    );
+    let line = contents.split(|&b| b == b'\n').next().unwrap_or_default();
+    let mut password = Zeroizing::new(
+        std::str::from_utf8(line)
+            .with_context(|| format!("Password file {} is not UTF-8", path.display()))?
+            .to_string(),
+    );
+    trim_line_ending(&mut password);
+    Ok(password)
+}
+
+/// Prompt for and read a single line from the terminal with echo enabled.
+fn read_line(prompts: &PromptActivity, prompt: &str) -> Option<String> {
+    let _prompt = prompts.prompt();
+    eprint!("{}", prompt);
+    let _ = std::io::stderr().flush();
+    let mut line = String::new();
+    match std::io::stdin().read_line(&mut line) {
+        Ok(0) | Err(_) => None,
+        Ok(_) => Some(line.trim_end_matches(&['\r', '\n'][..]).to_string()),
+    }
+}
+
 /// Read a password from the terminal with echo disabled.
-fn read_password(prompt: &str) -> Option<String> {
+#[cfg(windows)]
+fn read_password(prompts: &PromptActivity, prompt: &str) -> Option<Zeroizing<String>> {
     use std::os::windows::io::AsRawHandle;
     use windows_sys::Win32::System::Console::*;
 
+    let _prompt = prompts.prompt();
     eprint!("{}", prompt);
     let _ = std::io::stderr().flush();
 
-    let stdin_handle = std::io::stdin().as_raw_handle() as *mut core::ffi::c_void;
+    let stdin_handle = std::io::stdin().as_raw_handle();
 
     // Save current console mode
     let mut old_mode: u32 = 0;
@@ -664,7 +2040,7 @@ fn read_password(prompt: &str) -> Option<String> {
         SetConsoleMode(stdin_handle, new_mode);
     }
 
-    let mut password = String::new();
+    let mut password = Zeroizing::new(String::with_capacity(256));
     let result = std::io::stdin().read_line(&mut password);
 
     // Restore console mode
@@ -674,7 +2050,53 @@ fn read_password(prompt: &str) -> Option<String> {
     eprintln!(); // Print newline after hidden input
 
     match result {
-        Ok(_) => Some(password.trim_end_matches(&['\r', '\n'][..]).to_string()),
+        Ok(_) => {
+            trim_line_ending(&mut password);
+            Some(password)
+        }
+        Err(_) => None,
+    }
+}
+
+/// Read a password from the terminal with echo disabled.
+#[cfg(unix)]
+fn read_password(prompts: &PromptActivity, prompt: &str) -> Option<Zeroizing<String>> {
+    use std::os::unix::io::AsRawFd;
+
+    let _prompt = prompts.prompt();
+    eprint!("{}", prompt);
+    let _ = std::io::stderr().flush();
+
+    let fd = std::io::stdin().as_raw_fd();
+
+    // Save current terminal attributes
+    let mut old_attrs: libc::termios = unsafe { std::mem::zeroed() };
+    unsafe {
+        if libc::tcgetattr(fd, &mut old_attrs) != 0 {
+            // Not a terminal — can't disable echo
+            return None;
+        }
+        // Disable echo, keep canonical (line) input
+        let mut new_attrs = old_attrs;
+        new_attrs.c_lflag &= !libc::ECHO;
+        new_attrs.c_lflag |= libc::ICANON;
+        libc::tcsetattr(fd, libc::TCSANOW, &new_attrs);
+    }
+
+    let mut password = Zeroizing::new(String::with_capacity(256));
+    let result = std::io::stdin().read_line(&mut password);
+
+    // Restore terminal attributes
+    unsafe {
+        libc::tcsetattr(fd, libc::TCSANOW, &old_attrs);
+    }
+    eprintln!(); // Print newline after hidden input
+
+    match result {
+        Ok(_) => {
+            trim_line_ending(&mut password);
+            Some(password)
+        }
         Err(_) => None,
     }
 }
@@ -682,32 +2104,1345 @@ fn read_password(prompt: &str) -> Option<String> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use russh::keys::ssh_key;
 
     #[test]
     fn test_parse_mosh_connect() {
-        let output = "\n\nMOSH CONNECT 60001 AbCdEfGhIjKlMnOpQrStUv\n\n";
-        let (port, key) = parse_mosh_connect(output).unwrap();
-        assert_eq!(port, 60001);
-        assert_eq!(key, "AbCdEfGhIjKlMnOpQrStUv");
+        let output = "\n\nMOSH CONNECT 60001 AbCdEfGhIjKlMnOpQrStUg\n\n";
+        let reply = parse_server_reply(output, "").unwrap();
+        assert_eq!(reply.port, 60001);
+        assert_eq!(
+            reply.key.as_bytes(),
+            Base64Key::from_str("AbCdEfGhIjKlMnOpQrStUg").unwrap().as_bytes()
+        );
+        assert_eq!(reply.ip, None);
+        assert!(reply.warnings.is_empty());
     }
 
     #[test]
     fn test_parse_mosh_connect_with_noise() {
         let output = "Some debug output\nWarning: something\n\nMOSH CONNECT 60042 AAAAAAAAAAAAAAAAAAAAAA\nmore stuff";
-        let (port, key) = parse_mosh_connect(output).unwrap();
-        assert_eq!(port, 60042);
-        assert_eq!(key, "AAAAAAAAAAAAAAAAAAAAAA");
+        let reply = parse_server_reply(output, "").unwrap();
+        assert_eq!(reply.port, 60042);
+        assert_eq!(reply.key.as_bytes(), &[0u8; 16]);
+        assert_eq!(reply.warnings, vec!["Warning: something"]);
+    }
+
+    #[test]
+    fn test_session_debug_redacts_key() {
+        let reply = parse_server_reply("MOSH CONNECT 60001 AbCdEfGhIjKlMnOpQrStUg\n", "").unwrap();
+        let session = MoshSession {
+            port: reply.port,
+            key: reply.key,
+            remote_ip: "192.0.2.1".to_string(),
+        };
+        let shown = format!("{:?}", session);
+        assert!(shown.contains("60001") && shown.contains("<redacted>"), "{}", shown);
+        assert!(!shown.contains("AbCdEfGhIjKlMnOpQrStUg"), "{}", shown);
     }
 
     #[test]
     fn test_parse_mosh_connect_missing() {
         let output = "no connect line here\n";
-        assert!(parse_mosh_connect(output).is_err());
+        assert!(parse_server_reply(output, "").is_err());
     }
 
     #[test]
-    fn test_ssh_dir() {
-        let dir = ssh_dir();
+    fn test_parse_mosh_connect_rejects_bad_fields() {
+        for line in [
+            "MOSH CONNECT 60001 short",
+            "MOSH CONNECT 60001 AAAAAAAAAAAAAAAAAAAAA!",
+            "MOSH CONNECT 0 AAAAAAAAAAAAAAAAAAAAAA",
+            "MOSH CONNECT 70000 AAAAAAAAAAAAAAAAAAAAAA",
+            "MOSH CONNECT 60001 AAAAAAAAAAAAAAAAAAAAAA trailing",
+        ] {
+            assert!(parse_server_reply(line, "").is_err(), "{}", line);
+        }
+        // A bogus line (e.g. an echoed rc file) doesn't hide the real one
+        let output = "echo MOSH CONNECT\nMOSH CONNECT $PORT $KEY\nMOSH CONNECT 60003 AAAAAAAAAAAAAAAAAAAAAA\n";
+        assert_eq!(parse_server_reply(output, "").unwrap().port, 60003);
+    }
+
+    #[test]
+    fn test_parse_server_reply_diagnostics() {
+        let stdout = "\r\n\x1b[37;44mMOSH IP 192.0.2.7\x1b[m\r\n\r\nMOSH CONNECT 60010 AAAAAAAAAAAAAAAAAAAAAA\r\n";
+        let stderr = "mosh-server (mosh 1.4.0) [build mosh 1.4.0]\n\
+            Copyright 2012 Keith Winstein <mosh-devel@mit.edu>\n\
+            The locale requested by LC_ALL=xx_XX.UTF-8 isn't available here.\n\
+            Running `locale-gen xx_XX.UTF-8' may be necessary.\n\
+            \n[mosh-server detached, pid = 4242]\n";
+        let reply = parse_server_reply(stdout, stderr).unwrap();
+        assert_eq!(reply.port, 60010);
+        assert_eq!(reply.ip, Some("192.0.2.7".parse().unwrap()));
+        assert_eq!(reply.version.as_deref(), Some("1.4.0"));
+        assert_eq!(reply.warnings.len(), 2);
+        assert!(reply.warnings[0].starts_with("The locale requested"));
+
+        let err = parse_server_reply("", "mosh-server needs a UTF-8 native locale to run.\n")
+            .unwrap_err();
+        assert!(err.to_string().contains("UTF-8 native locale"), "{}", err);
+    }
+
+    #[test]
+    fn test_strip_escapes() {
+        assert_eq!(strip_escapes("\x1b[1;32mok\x1b[0m"), "ok");
+        assert_eq!(strip_escapes("\x1b]0;title\x07MOSH"), "MOSH");
+        assert_eq!(strip_escapes("\x1b]2;t\x1b\\x\x1b=y"), "xy");
+        assert_eq!(strip_escapes("plain"), "plain");
+    }
+
+    #[test]
+    fn test_parse_server_reply_fuzzed_noise() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+
+        const NOISE: &[&str] = &[
+            "Welcome to Ubuntu 22.04.3 LTS (GNU/Linux 5.15.0-91-generic x86_64)",
+            " * Documentation:  https://help.ubuntu.com",
+            "Last login: Tue Mar  5 10:12:44 2024 from 198.51.100.3",
+            "+ export PATH=/usr/local/bin:$PATH",
+            "+ echo MOSH CONNECT",
+            "MOSH CONNECT",
+            "MOSH CONNECT port key",
+            "MOSH IP not-an-address",
+            "\x1b]0;alice@host: ~\x07",
+            "\x1b[?2004h",
+            "bash: warning: setlocale: LC_ALL: cannot change locale (xx_XX.UTF-8)",
+            "",
+            "   ",
+            "\u{fffd}\u{fffd} binary junk \u{1}\u{7f}",
+        ];
+        let mut rng = StdRng::seed_from_u64(0x6d6f7368);
+        for _ in 0..500 {
+            let port = rng.gen_range(1..=u16::MAX);
+            let alphabet = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+            let mut key: String = (0..21).map(|_| alphabet[rng.gen_range(0..64)] as char).collect();
+            // The last character only carries two bits of the 16-byte key
+            key.push(['A', 'Q', 'g', 'w'][rng.gen_range(0..4)]);
+
+            let mut lines: Vec<String> = (0..rng.gen_range(0..12))
+                .map(|_| NOISE[rng.gen_range(0..NOISE.len())].to_string())
+                .collect();
+            let mut connect = format!("MOSH CONNECT {} {}", port, key);
+            if rng.gen_bool(0.3) {
+                connect = format!("\x1b[0m{}\x1b[K", connect);
+            }
+            let at = rng.gen_range(0..=lines.len());
+            lines.insert(at, connect);
+            let newline = if rng.gen_bool(0.5) { "\r\n" } else { "\n" };
+            let output = lines.join(newline);
+
+            let reply = parse_server_reply(&output, "").unwrap_or_else(|e| panic!("{:#}\n{:?}", e, output));
+            assert_eq!(reply.port, port, "{:?}", output);
+            assert_eq!(reply.key.as_bytes(), Base64Key::from_str(&key).unwrap().as_bytes());
+            assert_eq!(reply.ip, None);
+        }
+    }
+
+    #[test]
+    fn test_local_locale_env() {
+        let vars = [
+            ("PATH", "/usr/bin"),
+            ("LC_CTYPE", "de_DE.UTF-8"),
+            ("LANG", "en_GB.UTF-8"),
+            ("LC_ALL", ""),
+            ("LANGUAGE", "en_GB:en"),
+        ]
+        .into_iter()
+        .map(|(n, v)| (n.into(), v.into()));
+        let env = local_locale_env(vars);
+        let names: Vec<&str> = env.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(names, ["LANG", "LANGUAGE", "LC_CTYPE"]);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStringExt as _;
+            let vars = [
+                ("LC_CTYPE".into(), std::ffi::OsString::from_vec(b"de_DE.\xff".to_vec())),
+                ("LC_TIME".into(), "C".into()),
+            ];
+            let env = local_locale_env(vars.into_iter());
+            assert_eq!(env, [("LC_TIME".to_string(), "C".to_string())]);
+        }
+
+        let env = local_locale_env(std::iter::empty());
+        assert_eq!(env, [("LANG".to_string(), "en_US.UTF-8".to_string())]);
+    }
+
+    #[test]
+    fn test_server_env_on_command_line() {
+        let mut config = SshConfig::new("host", "user");
+        config.server_env.clear();
+        let config = config
+            .with_env("LANG", "C.UTF-8")
+            .with_env("EDITOR", "vim")
+            .with_env("LANG", "en_US.UTF-8");
+        assert_eq!(
+            mosh_server_command_line(&config, &config.mosh_server_command),
+            "mosh-server new -s -c 256 -l EDITOR=vim -l LANG=en_US.UTF-8"
+        );
+    }
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("new"), "new");
+        assert_eq!(shell_quote("LANG=en_US.UTF-8"), "LANG=en_US.UTF-8");
+        assert_eq!(shell_quote("60001:60010"), "60001:60010");
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote("two words"), "'two words'");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+        assert_eq!(shell_quote("''"), r"''\'''\'''");
+        assert_eq!(shell_quote("$(rm -rf ~)"), "'$(rm -rf ~)'");
+        assert_eq!(shell_quote("a;b|c&d`e`"), "'a;b|c&d`e`'");
+        assert_eq!(shell_quote("~/bin"), "'~/bin'");
+        assert_eq!(shell_quote("=ls"), "'=ls'");
+        assert_eq!(shell_quote("*.log"), "'*.log'");
+        assert_eq!(shell_quote("line\nbreak"), "'line\nbreak'");
+        assert_eq!(shell_quote("back\\slash"), "'back\\slash'");
+    }
+
+    #[test]
+    fn test_parse_port_range() {
+        assert_eq!(parse_port_range("60001").unwrap(), (60001, 60001));
+        assert_eq!(parse_port_range("60001:60010").unwrap(), (60001, 60010));
+        assert!(parse_port_range("60010:60001").is_err());
+        assert!(parse_port_range("0").is_err());
+        assert!(parse_port_range("70000").is_err());
+        assert!(parse_port_range("60001:").is_err());
+        assert!(parse_port_range("60001-60010").is_err());
+    }
+
+    #[test]
+    fn test_server_command_quotes_arguments() {
+        let mut config = SshConfig::new("host", "user").with_udp_port_range(60001, 60010);
+        config.server_env.clear();
+        config.mosh_server_command = "~/bin/mosh-server".to_string();
+        let config = config.with_env("MOTD", "it's $HOME; bye");
+        assert_eq!(
+            mosh_server_command_line(&config, &config.mosh_server_command),
+            r"~/bin/mosh-server new -s -c 256 -p 60001:60010 -l 'MOTD=it'\''s $HOME; bye'"
+        );
+
+        let config = SshConfig::new("host", "user").with_udp_port_range(60005, 60005);
+        assert!(mosh_server_command_line(&config, &config.mosh_server_command).contains(" -p 60005 "));
+    }
+
+    #[test]
+    fn test_remote_command_follows_server_options() {
+        let mut config = SshConfig::new("host", "user")
+            .with_remote_command(vec!["tmux".into(), "attach".into(), "-t".into(), "my work".into()]);
+        config.server_env.clear();
+        config.mosh_server_args.push("-v".to_string());
+        let config = config.with_env("LANG", "C.UTF-8");
+        assert_eq!(
+            mosh_server_command_line(&config, &config.mosh_server_command),
+            "mosh-server new -s -c 256 -v -l LANG=C.UTF-8 -- tmux attach -t 'my work'"
+        );
+    }
+
+    #[test]
+    fn test_ssh_dir() {
+        let dir = ssh_dir();
         assert!(dir.to_string_lossy().contains(".ssh"));
     }
+
+    // ── Certificates and known_hosts markers ─────────────────────────
+
+    fn unix_now() -> u64 {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("mosh-ssh-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn random_ed25519() -> PrivateKey {
+        ssh_key::private::Ed25519Keypair::from_seed(&rand::random()).into()
+    }
+
+    /// Sign a certificate for `key` with `ca`.
+    fn certify(
+        ca: &PrivateKey,
+        key: &PrivateKey,
+        cert_type: CertType,
+        principal: &str,
+        valid_after: u64,
+        valid_before: u64,
+    ) -> Certificate {
+        let mut builder = ssh_key::certificate::Builder::new(
+            rand::random::<[u8; 16]>(),
+            key.public_key().key_data().clone(),
+            valid_after,
+            valid_before,
+        )
+        .unwrap();
+        builder.cert_type(cert_type).unwrap();
+        builder.key_id("test").unwrap();
+        builder.valid_principal(principal).unwrap();
+        builder.sign(ca).unwrap()
+    }
+
+    /// Write `key` as an OpenSSH private key plus a CA-signed `-cert.pub` companion.
+    fn write_key_with_cert(
+        dir: &Path,
+        key: &PrivateKey,
+        cert_type: CertType,
+        valid_after: u64,
+        valid_before: u64,
+    ) -> PathBuf {
+        let key_path = dir.join("id_ed25519");
+        let pem = key.to_openssh(ssh_key::LineEnding::LF).unwrap();
+        std::fs::write(&key_path, pem.as_bytes()).unwrap();
+
+        let cert = certify(&random_ed25519(), key, cert_type, "alice", valid_after, valid_before);
+        std::fs::write(dir.join("id_ed25519-cert.pub"), cert.to_openssh().unwrap()).unwrap();
+        key_path
+    }
+
+    #[test]
+    fn test_companion_certificate_is_discovered_and_loaded() {
+        let dir = scratch_dir("cert-ok");
+        let key = random_ed25519();
+        let now = unix_now();
+        let key_path = write_key_with_cert(
+            &dir,
+            &key,
+            CertType::User,
+            now - 60,
+            now + 3600,
+        );
+
+        let cert_path = companion_certificate(&key_path).unwrap();
+        assert_eq!(cert_path, dir.join("id_ed25519-cert.pub"));
+
+        let key_pair = keys::load_secret_key(&key_path, None).unwrap();
+        let cert = load_user_certificate(&cert_path, &key_pair).unwrap();
+        assert_eq!(cert.valid_principals(), ["alice".to_string()]);
+    }
+
+    #[test]
+    fn test_expired_certificate_is_skipped() {
+        let dir = scratch_dir("cert-expired");
+        let key = random_ed25519();
+        let now = unix_now();
+        let key_path = write_key_with_cert(
+            &dir,
+            &key,
+            CertType::User,
+            now - 7200,
+            now - 3600,
+        );
+        let key_pair = keys::load_secret_key(&key_path, None).unwrap();
+        let err = load_user_certificate(&dir.join("id_ed25519-cert.pub"), &key_pair).unwrap_err();
+        assert!(err.to_string().contains("expired"));
+    }
+
+    #[test]
+    fn test_certificate_for_other_key_or_host_is_rejected() {
+        let dir = scratch_dir("cert-mismatch");
+        let now = unix_now();
+        let key_path = write_key_with_cert(
+            &dir,
+            &random_ed25519(),
+            CertType::Host,
+            now - 60,
+            now + 3600,
+        );
+        let cert_path = dir.join("id_ed25519-cert.pub");
+        let key_pair = keys::load_secret_key(&key_path, None).unwrap();
+        assert!(load_user_certificate(&cert_path, &key_pair).is_err());
+
+        write_key_with_cert(
+            &dir,
+            &random_ed25519(),
+            CertType::User,
+            now - 60,
+            now + 3600,
+        );
+        let err = load_user_certificate(&cert_path, &key_pair).unwrap_err();
+        assert!(err.to_string().contains("does not match"));
+    }
+
+    #[test]
+    fn test_no_companion_certificate() {
+        let dir = scratch_dir("cert-none");
+        assert!(companion_certificate(&dir.join("id_rsa")).is_none());
+    }
+
+    #[test]
+    fn test_host_patterns() {
+        assert!(host_patterns_match("*.example.com", "db.example.com"));
+        assert!(host_patterns_match("web?,db1", "web1"));
+        assert!(!host_patterns_match("*.example.com", "example.org"));
+        assert!(!host_patterns_match("*.example.com,!bad.example.com", "bad.example.com"));
+        assert!(host_patterns_match("[*.example.com]:2222", "[db.example.com]:2222"));
+        assert!(host_patterns_match("DB.Example.com", "db.example.com"));
+    }
+
+    #[test]
+    fn test_marked_host_keys() {
+        let dir = scratch_dir("known-hosts");
+        let ca = random_ed25519();
+        let revoked = random_ed25519();
+        let path = dir.join("known_hosts");
+        std::fs::write(
+            &path,
+            format!(
+                "# comment\n\
+                 @cert-authority *.example.com {}\n\
+                 @revoked db.example.com {}\n\
+                 other.org ssh-ed25519 AAAA\n",
+                ca.public_key().to_openssh().unwrap(),
+                revoked.public_key().to_openssh().unwrap(),
+            ),
+        )
+        .unwrap();
+
+        let marked = marked_host_keys(&path, "db.example.com", 22);
+        assert_eq!(marked.cert_authorities, [ca.public_key().clone()]);
+        assert_eq!(marked.revoked, [revoked.public_key().clone()]);
+
+        let marked = marked_host_keys(&path, "web.example.com", 22);
+        assert_eq!(marked.cert_authorities.len(), 1);
+        assert!(marked.revoked.is_empty());
+
+        let marked = marked_host_keys(&path, "db.example.com", 2222);
+        assert!(marked.cert_authorities.is_empty());
+        assert!(marked.revoked.is_empty());
+    }
+
+    #[test]
+    fn test_check_host_certificate() {
+        let ca = random_ed25519();
+        let host = random_ed25519();
+        let now = unix_now();
+        let trusted = [ca.public_key().clone()];
+        let cert = certify(&ca, &host, CertType::Host, "db.example.com", now - 60, now + 3600);
+
+        check_host_certificate(&cert, &trusted, "db.example.com", now).unwrap();
+        check_host_certificate(&cert, &trusted, "DB.example.com", now).unwrap();
+
+        let err = |cert: &Certificate, trusted: &[PublicKey], host: &str, at: u64| {
+            check_host_certificate(cert, trusted, host, at).unwrap_err().to_string()
+        };
+        assert!(err(&cert, &trusted, "web.example.com", now).contains("host name"));
+        assert!(err(&cert, &[random_ed25519().public_key().clone()], "db.example.com", now)
+            .contains("unknown certificate authority"));
+        assert!(err(&cert, &trusted, "db.example.com", now + 7200).contains("expired"));
+
+        let user = certify(&ca, &host, CertType::User, "db.example.com", now - 60, now + 3600);
+        assert!(err(&user, &trusted, "db.example.com", now).contains("not a host certificate"));
+    }
+
+    // ── Host key policies ────────────────────────────────────────────
+
+    fn policy_client(dir: &Path, mode: StrictHostKeyChecking) -> SshClient {
+        SshClient {
+            host: "db.example.com".to_string(),
+            port: 2222,
+            strict_host_key_checking: mode,
+            user_known_hosts: dir.join("known_hosts"),
+            global_known_hosts: Some(dir.join("ssh_known_hosts")),
+            hash_known_hosts: false,
+            key_mismatch: Arc::new(AtomicBool::new(false)),
+            forward_agent: false,
+            prompts: Arc::default(),
+        }
+    }
+
+    #[test]
+    fn test_strict_host_key_checking_from_str() {
+        assert_eq!("yes".parse::<StrictHostKeyChecking>().unwrap(), StrictHostKeyChecking::Yes);
+        assert_eq!(
+            "Accept-New".parse::<StrictHostKeyChecking>().unwrap(),
+            StrictHostKeyChecking::AcceptNew
+        );
+        assert_eq!("off".parse::<StrictHostKeyChecking>().unwrap(), StrictHostKeyChecking::No);
+        assert_eq!(StrictHostKeyChecking::default(), StrictHostKeyChecking::Ask);
+        assert!("maybe".parse::<StrictHostKeyChecking>().is_err());
+    }
+
+    #[test]
+    fn test_strict_yes_refuses_unknown_host() {
+        let dir = scratch_dir("strict-yes");
+        let server = random_ed25519().public_key().clone();
+        let mut client = policy_client(&dir, StrictHostKeyChecking::Yes);
+        assert!(!client.verify_host_key(&server));
+        assert!(!dir.join("known_hosts").exists());
+    }
+
+    #[test]
+    fn test_accept_new_records_then_trusts() {
+        let dir = scratch_dir("accept-new");
+        let server = random_ed25519().public_key().clone();
+        let mut client = policy_client(&dir, StrictHostKeyChecking::AcceptNew);
+        assert!(client.verify_host_key(&server));
+        let contents = std::fs::read_to_string(dir.join("known_hosts")).unwrap();
+        assert!(contents.starts_with("[db.example.com]:2222 ssh-ed25519 "));
+
+        // Now known: even strict checking accepts it
+        client.strict_host_key_checking = StrictHostKeyChecking::Yes;
+        assert!(client.verify_host_key(&server));
+
+        // A different key for the same host is refused
+        client.strict_host_key_checking = StrictHostKeyChecking::AcceptNew;
+        let imposter = random_ed25519().public_key().clone();
+        assert!(!client.verify_host_key(&imposter));
+        assert!(!client.key_mismatch.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_strict_no_lets_changed_key_through_and_flags_it() {
+        let dir = scratch_dir("strict-no");
+        let original = random_ed25519().public_key().clone();
+        learn_host_key(&dir.join("known_hosts"), "db.example.com", 2222, &original, false)
+            .unwrap();
+
+        let mut client = policy_client(&dir, StrictHostKeyChecking::No);
+        let replacement = random_ed25519().public_key().clone();
+        assert!(client.verify_host_key(&replacement));
+        assert!(client.key_mismatch.load(Ordering::SeqCst));
+        // The recorded key is left alone
+        let contents = std::fs::read_to_string(dir.join("known_hosts")).unwrap();
+        assert_eq!(contents.lines().count(), 1);
+    }
+
+    #[test]
+    fn test_global_known_hosts_is_trusted_read_only() {
+        let dir = scratch_dir("global-known-hosts");
+        let server = random_ed25519().public_key().clone();
+        learn_host_key(&dir.join("ssh_known_hosts"), "db.example.com", 2222, &server, false)
+            .unwrap();
+
+        let mut client = policy_client(&dir, StrictHostKeyChecking::Yes);
+        assert!(client.verify_host_key(&server));
+        assert!(!dir.join("known_hosts").exists());
+    }
+
+    #[test]
+    fn test_host_certificate_from_trusted_authority() {
+        let dir = scratch_dir("host-cert");
+        let ca = random_ed25519();
+        let now = unix_now();
+        let cert = certify(&ca, &random_ed25519(), CertType::Host, "db.example.com", now - 60, now + 3600);
+        let mut client = policy_client(&dir, StrictHostKeyChecking::Yes);
+
+        // No CA yet: the certified key is an unknown host key
+        assert!(!client.verify_host_certificate(&cert));
+
+        let ca_line = format!("@cert-authority [*.example.com]:2222 {}\n", ca.public_key().to_openssh().unwrap());
+        std::fs::write(dir.join("ssh_known_hosts"), &ca_line).unwrap();
+        assert!(client.verify_host_certificate(&cert));
+        assert!(!dir.join("known_hosts").exists());
+
+        // A revoked CA vouches for nothing
+        let revoked = format!("@revoked * {}\n", ca.public_key().to_openssh().unwrap());
+        std::fs::write(dir.join("known_hosts"), revoked).unwrap();
+        assert!(!client.verify_host_certificate(&cert));
+    }
+
+    #[tokio::test]
+    async fn test_host_certificate_is_negotiated() {
+        let dir = scratch_dir("host-cert-kex");
+        let ca = random_ed25519();
+        let host_key = random_ed25519();
+        let now = unix_now();
+        let cert = certify(&ca, &host_key, CertType::Host, "db.example.com", now - 60, now + 3600);
+        let ca_line = format!("@cert-authority [*.example.com]:2222 {}\n", ca.public_key().to_openssh().unwrap());
+        std::fs::write(dir.join("ssh_known_hosts"), &ca_line).unwrap();
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server_config = Arc::new(server::Config {
+            keys: vec![host_key],
+            certificates: vec![cert],
+            ..Default::default()
+        });
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            if let Ok(running) = server::run_stream(server_config, stream, TwoFactorServer).await {
+                let _ = running.await;
+            }
+        });
+
+        // Strict checking with no plain known_hosts entry: only the CA can vouch
+        let client = policy_client(&dir, StrictHostKeyChecking::Yes);
+        assert!(!client.cert_authorities().is_empty());
+        let mut config = client::Config::default();
+        config.preferred.host_key_certificates = config.preferred.key.clone();
+        client::connect(Arc::new(config), addr, client).await.unwrap();
+        assert!(!dir.join("known_hosts").exists());
+    }
+
+    #[test]
+    fn test_hashed_known_hosts_entry() {
+        let dir = scratch_dir("hashed-known-hosts");
+        let path = dir.join("known_hosts");
+        // Existing file without a trailing newline
+        std::fs::write(&path, "other.org ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA").unwrap();
+
+        let server = random_ed25519().public_key().clone();
+        let mut client = policy_client(&dir, StrictHostKeyChecking::AcceptNew);
+        client.hash_known_hosts = true;
+        assert!(client.verify_host_key(&server));
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let entry = contents.lines().nth(1).unwrap();
+        assert!(entry.starts_with("|1|"));
+        assert!(!contents.contains("db.example.com"));
+
+        // Both russh and our marker parser recognize the hashed name
+        assert!(keys::check_known_hosts_path("db.example.com", 2222, &server, &path).unwrap());
+        let pattern = entry.split_whitespace().next().unwrap();
+        assert!(host_patterns_match(pattern, "[db.example.com]:2222"));
+        assert!(!host_patterns_match(pattern, "db.example.com"));
+    }
+
+    // ── Keyboard-interactive against a local russh server ──────────────
+
+    /// Answers prompts from a fixed script and records what was shown.
+    struct ScriptedResponder {
+        answers: std::collections::VecDeque<String>,
+        seen: Vec<(String, bool)>,
+    }
+
+    impl ScriptedResponder {
+        fn new(answers: &[&str]) -> Self {
+            Self {
+                answers: answers.iter().map(|a| a.to_string()).collect(),
+                seen: Vec::new(),
+            }
+        }
+    }
+
+    impl KbdInteractiveResponder for ScriptedResponder {
+        fn respond(
+            &mut self,
+            _name: &str,
+            _instructions: &str,
+            prompts: &[client::Prompt],
+        ) -> Option<Vec<Zeroizing<String>>> {
+            let mut out = Vec::new();
+            for p in prompts {
+                self.seen.push((p.prompt.clone(), p.echo));
+                out.push(Zeroizing::new(self.answers.pop_front()?));
+            }
+            Some(out)
+        }
+    }
+
+    /// Server that asks for a password (hidden) and a TOTP code (echoed).
+    struct TwoFactorServer;
+
+    impl server::Handler for TwoFactorServer {
+        type Error = russh::Error;
+
+        async fn auth_keyboard_interactive<'a>(
+            &'a mut self,
+            _user: &str,
+            _submethods: &str,
+            response: Option<server::Response<'a>>,
+        ) -> Result<server::Auth, Self::Error> {
+            let Some(response) = response else {
+                return Ok(server::Auth::Partial {
+                    name: "Bastion".into(),
+                    instructions: "Two-factor authentication required".into(),
+                    prompts: vec![
+                        ("Password: ".into(), false),
+                        ("Verification code: ".into(), true),
+                    ]
+                    .into(),
+                });
+            };
+            let answers: Vec<_> = response.collect();
+            if answers == [b"hunter2".as_slice(), b"123456".as_slice()] {
+                Ok(server::Auth::Accept)
+            } else {
+                Ok(server::Auth::reject())
+            }
+        }
+    }
+
+    /// Client handler that trusts any host key (tests only).
+    struct TrustingClient;
+
+    impl client::Handler for TrustingClient {
+        type Error = russh::Error;
+
+        async fn check_server_key(
+            &mut self,
+            _server_key: &PublicKeyOrCertificate,
+        ) -> Result<bool, Self::Error> {
+            Ok(true)
+        }
+    }
+
+    /// Start a one-connection russh server on loopback and connect to it.
+    async fn local_session<S>(handler: S) -> client::Handle<TrustingClient>
+    where
+        S: server::Handler + Send + 'static,
+    {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = Arc::new(server::Config {
+            keys: vec![random_ed25519()],
+            auth_rejection_time: std::time::Duration::from_millis(1),
+            ..Default::default()
+        });
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            if let Ok(running) = server::run_stream(config, stream, handler).await {
+                let _ = running.await;
+            }
+        });
+        client::connect(Arc::new(client::Config::default()), addr, TrustingClient)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_keyboard_interactive_scripted_success() {
+        let mut session = local_session(TwoFactorServer).await;
+        let mut responder = ScriptedResponder::new(&["hunter2", "123456"]);
+        let ok = try_keyboard_interactive(&mut session, "alice", &mut responder)
+            .await
+            .unwrap();
+        assert!(ok);
+        assert_eq!(
+            responder.seen,
+            vec![
+                ("Password: ".to_string(), false),
+                ("Verification code: ".to_string(), true),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_keyboard_interactive_retries_then_fails() {
+        let mut session = local_session(TwoFactorServer).await;
+        let mut responder = ScriptedResponder::new(&[
+            "wrong", "000000", "wrong", "111111", "wrong", "222222",
+        ]);
+        let ok = try_keyboard_interactive(&mut session, "alice", &mut responder)
+            .await
+            .unwrap();
+        assert!(!ok);
+        assert_eq!(responder.seen.len(), 6);
+    }
+
+    #[tokio::test]
+    async fn test_keyboard_interactive_abandoned_by_responder() {
+        let mut session = local_session(TwoFactorServer).await;
+        let mut responder = ScriptedResponder::new(&[]);
+        let ok = try_keyboard_interactive(&mut session, "alice", &mut responder)
+            .await
+            .unwrap();
+        assert!(!ok);
+    }
+
+    // ── Agent authentication over a Unix socket ──────────────────────
+
+    /// Server that accepts exactly one public key, and certificates signed
+    /// by `trusted_ca`.
+    #[cfg(unix)]
+    struct PublicKeyServer {
+        accepted: PublicKey,
+        trusted_ca: Option<PublicKey>,
+    }
+
+    #[cfg(unix)]
+    impl PublicKeyServer {
+        fn accepting(accepted: &PrivateKey) -> Self {
+            Self {
+                accepted: accepted.public_key().clone(),
+                trusted_ca: None,
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    impl server::Handler for PublicKeyServer {
+        type Error = russh::Error;
+
+        async fn auth_publickey(
+            &mut self,
+            _user: &str,
+            public_key: &PublicKey,
+        ) -> Result<server::Auth, Self::Error> {
+            if public_key.key_data() == self.accepted.key_data() {
+                Ok(server::Auth::Accept)
+            } else {
+                Ok(server::Auth::reject())
+            }
+        }
+
+        async fn auth_openssh_certificate(
+            &mut self,
+            _user: &str,
+            certificate: &Certificate,
+        ) -> Result<server::Auth, Self::Error> {
+            match &self.trusted_ca {
+                Some(ca) if ca.key_data() == certificate.signature_key() => Ok(server::Auth::Accept),
+                _ => Ok(server::Auth::reject()),
+            }
+        }
+    }
+
+    /// Run an in-process agent on a Unix socket, loaded with `keys`.
+    #[cfg(unix)]
+    async fn local_agent(name: &str, keys: &[&PrivateKey]) -> AgentSource {
+        let sock = scratch_dir(name).join("agent.sock");
+        let listener = tokio::net::UnixListener::bind(&sock).unwrap();
+        tokio::spawn(russh::keys::agent::server::serve(
+            tokio_stream::wrappers::UnixListenerStream::new(listener),
+            (),
+        ));
+
+        let mut loader = russh::keys::agent::client::AgentClient::connect_uds(&sock)
+            .await
+            .unwrap();
+        for k in keys {
+            loader.add_identity(k, &[]).await.unwrap();
+        }
+        AgentSource::UnixSocket(sock)
+    }
+
+    /// Like `local_agent`, but the agent also lists `certs` ahead of its
+    /// keys. The russh agent can't hold certificates, so a relay in front
+    /// of it splices them into the identities answer and turns signing
+    /// requests for a certificate into requests for its key.
+    #[cfg(unix)]
+    async fn local_agent_with_certs(
+        name: &str,
+        keys: &[&PrivateKey],
+        certs: &[Certificate],
+    ) -> AgentSource {
+        use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+        async fn read_frame(stream: &mut tokio::net::UnixStream) -> Option<Vec<u8>> {
+            let len = stream.read_u32().await.ok()?;
+            let mut body = vec![0; len as usize];
+            stream.read_exact(&mut body).await.ok()?;
+            Some(body)
+        }
+
+        fn put_string(out: &mut Vec<u8>, bytes: &[u8]) {
+            out.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+            out.extend_from_slice(bytes);
+        }
+
+        let AgentSource::UnixSocket(inner) = local_agent(name, keys).await;
+        let sock = inner.with_file_name("relay.sock");
+        let listener = tokio::net::UnixListener::bind(&sock).unwrap();
+        // (certificate blob, blob of the key it certifies)
+        let blobs: Vec<(Vec<u8>, Vec<u8>)> = certs
+            .iter()
+            .map(|c| {
+                let key = PublicKey::new(c.public_key().clone(), "");
+                (c.to_bytes().unwrap(), key.to_bytes().unwrap())
+            })
+            .collect();
+        tokio::spawn(async move {
+            while let Ok((mut client, _)) = listener.accept().await {
+                let mut agent = tokio::net::UnixStream::connect(&inner).await.unwrap();
+                let blobs = blobs.clone();
+                tokio::spawn(async move {
+                    while let Some(mut request) = read_frame(&mut client).await {
+                        if request.first() == Some(&13) {
+                            let len = u32::from_be_bytes(request[1..5].try_into().unwrap()) as usize;
+                            let (blob, rest) = request[5..].split_at(len);
+                            if let Some((_, key)) = blobs.iter().find(|(cert, _)| cert == blob) {
+                                let mut rewritten = vec![13];
+                                put_string(&mut rewritten, key);
+                                rewritten.extend_from_slice(rest);
+                                request = rewritten;
+                            }
+                        }
+                        agent.write_u32(request.len() as u32).await.unwrap();
+                        agent.write_all(&request).await.unwrap();
+                        let Some(mut reply) = read_frame(&mut agent).await else { return };
+                        if request == [11] && reply.first() == Some(&12) {
+                            let count = u32::from_be_bytes(reply[1..5].try_into().unwrap());
+                            let mut spliced = vec![12];
+                            spliced.extend_from_slice(&(count + blobs.len() as u32).to_be_bytes());
+                            for (cert, _) in &blobs {
+                                put_string(&mut spliced, cert);
+                                put_string(&mut spliced, b"alice@example.com cert");
+                            }
+                            spliced.extend_from_slice(&reply[5..]);
+                            reply = spliced;
+                        }
+                        client.write_u32(reply.len() as u32).await.unwrap();
+                        client.write_all(&reply).await.unwrap();
+                    }
+                });
+            }
+        });
+        AgentSource::UnixSocket(sock)
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_agent_auth_over_unix_socket() {
+        let other = random_ed25519();
+        let wanted = random_ed25519();
+        let source = local_agent("agent-ok", &[&other, &wanted]).await;
+
+        let mut session = local_session(PublicKeyServer::accepting(&wanted)).await;
+        let ok = try_agent_source(&mut session, "alice", &source).await.unwrap();
+        assert!(ok);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_agent_certificate_auth() {
+        let ca = random_ed25519();
+        let held = random_ed25519();
+        let now = unix_now();
+        let cert = certify(&ca, &held, CertType::User, "alice", now - 60, now + 3600);
+        let source = local_agent_with_certs("agent-cert", &[&held], &[cert]).await;
+
+        // The server only knows the CA, not the key itself
+        let mut session = local_session(PublicKeyServer {
+            accepted: random_ed25519().public_key().clone(),
+            trusted_ca: Some(ca.public_key().clone()),
+        })
+        .await;
+        let ok = try_agent_source(&mut session, "alice", &source).await.unwrap();
+        assert!(ok);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_rejected_agent_certificate_falls_back_to_plain_key() {
+        let wanted = random_ed25519();
+        let now = unix_now();
+        let cert = certify(&random_ed25519(), &wanted, CertType::User, "alice", now - 60, now + 3600);
+        let source = local_agent_with_certs("agent-cert-fallback", &[&wanted], &[cert]).await;
+
+        let mut session = local_session(PublicKeyServer::accepting(&wanted)).await;
+        let ok = try_agent_source(&mut session, "alice", &source).await.unwrap();
+        assert!(ok);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_agent_without_accepted_key_fails() {
+        let held = random_ed25519();
+        let source = local_agent("agent-reject", &[&held]).await;
+
+        let mut session = local_session(PublicKeyServer::accepting(&random_ed25519())).await;
+        let ok = try_agent_source(&mut session, "alice", &source).await.unwrap();
+        assert!(!ok);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_missing_agent_socket_is_an_error() {
+        let source = AgentSource::UnixSocket(scratch_dir("agent-missing").join("agent.sock"));
+        let mut session = local_session(PublicKeyServer::accepting(&random_ed25519())).await;
+        assert!(try_agent_source(&mut session, "alice", &source).await.is_err());
+    }
+
+    // ── Starting mosh-server against a scripted remote shell ─────────
+
+    /// Maps a remote command and its stdin to (stdout, stderr, exit status).
+    type ShellScript = Arc<dyn Fn(&str, &[u8]) -> (String, String, u32) + Send + Sync>;
+
+    /// Commands a `FakeShell` ran, with the stdin each received.
+    type ExecLog = Arc<std::sync::Mutex<Vec<(String, Vec<u8>)>>>;
+
+    /// Server that answers exec requests from a script and logs them.
+    struct FakeShell {
+        script: ShellScript,
+        log: ExecLog,
+        /// Commands waiting for stdin EOF before they "run".
+        pending: std::collections::HashMap<ChannelId, (String, Vec<u8>)>,
+    }
+
+    impl FakeShell {
+        fn reply(
+            &self,
+            channel: ChannelId,
+            command: String,
+            stdin: Vec<u8>,
+            session: &mut server::Session,
+        ) -> Result<(), russh::Error> {
+            let (stdout, stderr, status) = (self.script)(&command, &stdin);
+            self.log.lock().unwrap().push((command, stdin));
+            session.data(channel, stdout.into_bytes())?;
+            if !stderr.is_empty() {
+                session.extended_data(channel, 1, stderr.into_bytes())?;
+            }
+            session.eof(channel)?;
+            session.exit_status_request(channel, status)?;
+            session.close(channel)
+        }
+    }
+
+    impl server::Handler for FakeShell {
+        type Error = russh::Error;
+
+        async fn auth_none(&mut self, _user: &str) -> Result<server::Auth, Self::Error> {
+            Ok(server::Auth::Accept)
+        }
+
+        async fn channel_open_session(
+            &mut self,
+            _channel: Channel<server::Msg>,
+            reply: server::ChannelOpenHandle,
+            _session: &mut server::Session,
+        ) -> Result<(), Self::Error> {
+            reply.accept().await;
+            Ok(())
+        }
+
+        async fn exec_request(
+            &mut self,
+            channel: ChannelId,
+            data: &[u8],
+            session: &mut server::Session,
+        ) -> Result<(), Self::Error> {
+            let command = String::from_utf8_lossy(data).into_owned();
+            if command.contains("cat >") {
+                self.pending.insert(channel, (command, Vec::new()));
+            } else {
+                self.reply(channel, command, Vec::new(), session)?;
+            }
+            Ok(())
+        }
+
+        async fn data(
+            &mut self,
+            channel: ChannelId,
+            data: &[u8],
+            _session: &mut server::Session,
+        ) -> Result<(), Self::Error> {
+            if let Some((_, stdin)) = self.pending.get_mut(&channel) {
+                stdin.extend_from_slice(data);
+            }
+            Ok(())
+        }
+
+        async fn channel_eof(
+            &mut self,
+            channel: ChannelId,
+            session: &mut server::Session,
+        ) -> Result<(), Self::Error> {
+            if let Some((command, stdin)) = self.pending.remove(&channel) {
+                self.reply(channel, command, stdin, session)?;
+            }
+            Ok(())
+        }
+    }
+
+    const NOT_FOUND: (&str, &str, u32) = ("", "sh: 1: mosh-server: not found\n", 127);
+
+    /// Run `start_mosh_server` against `script`, returning the result and the commands seen.
+    async fn start_with_script(
+        config: &SshConfig,
+        script: impl Fn(&str, &[u8]) -> (&'static str, &'static str, u32) + Send + Sync + 'static,
+    ) -> (Result<ServerReply>, Vec<(String, Vec<u8>)>) {
+        let log = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let shell = FakeShell {
+            script: Arc::new(move |cmd: &str, stdin: &[u8]| {
+                let (out, err, status) = script(cmd, stdin);
+                (out.to_string(), err.to_string(), status)
+            }),
+            log: log.clone(),
+            pending: Default::default(),
+        };
+        let mut session = local_session(shell).await;
+        assert!(session.authenticate_none("alice").await.unwrap().success());
+        let result = start_mosh_server(&session, config).await;
+        let log = log.lock().unwrap().clone();
+        (result, log)
+    }
+
+    #[test]
+    fn test_discovery_helpers() {
+        assert!(is_bare_command("mosh-server"));
+        assert!(!is_bare_command("/opt/mosh/bin/mosh-server"));
+        assert!(!is_bare_command("sudo mosh-server"));
+        assert!(discovery_script("mosh-server").contains("\"/opt/homebrew/bin\" \"/opt/local/bin\""));
+        assert_eq!(
+            login_shell_command("mosh-server new -l 'A=b c'"),
+            r#"exec "${SHELL:-/bin/sh}" -lc 'mosh-server new -l '\''A=b c'\'''"#
+        );
+    }
+
+    #[tokio::test]
+    async fn test_server_found_in_common_location() {
+        let config = SshConfig::new("host", "alice");
+        let (result, log) = start_with_script(&config, |cmd, _| {
+            if cmd.starts_with("mosh-server ") {
+                NOT_FOUND
+            } else if cmd.starts_with("for d in") {
+                ("/opt/homebrew/bin/mosh-server\n", "", 0)
+            } else if cmd.starts_with("/opt/homebrew/bin/mosh-server new ") {
+                ("\r\nMOSH CONNECT 60004 AAAAAAAAAAAAAAAAAAAAAA\r\n", "", 0)
+            } else {
+                ("", "unexpected", 2)
+            }
+        })
+        .await;
+        let reply = result.unwrap();
+        assert_eq!((reply.port, reply.key.as_bytes()), (60004, &[0u8; 16]));
+        assert_eq!(log.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_server_found_through_login_shell() {
+        let config = SshConfig::new("host", "alice");
+        let (result, log) = start_with_script(&config, |cmd, _| {
+            if cmd.starts_with("exec \"${SHELL:-/bin/sh}\" -lc 'mosh-server new ") {
+                ("MOSH CONNECT 60005 BBBBBBBBBBBBBBBBBBBBBA\n", "", 0)
+            } else if cmd.starts_with("for d in") {
+                ("", "", 1)
+            } else {
+                NOT_FOUND
+            }
+        })
+        .await;
+        assert_eq!(result.unwrap().port, 60005);
+        assert_eq!(log.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_server_binary_is_uploaded_when_missing() {
+        let dir = scratch_dir("upload-server");
+        let binary = dir.join("mosh-server");
+        std::fs::write(&binary, b"\x7fELF static mosh-server").unwrap();
+        let config = SshConfig::new("host", "alice").with_server_binary(binary);
+
+        let (result, log) = start_with_script(&config, |cmd, _| {
+            if cmd.contains("cat >") {
+                ("/home/alice/.cache/mosh-client/mosh-server-0123\n", "", 0)
+            } else if cmd.starts_with("/home/alice/.cache/mosh-client/mosh-server-0123 new ") {
+                ("MOSH CONNECT 60006 CCCCCCCCCCCCCCCCCCCCCA\n", "", 0)
+            } else if cmd.starts_with("for d in") || cmd.starts_with("t=") {
+                ("", "", 1)
+            } else {
+                NOT_FOUND
+            }
+        })
+        .await;
+        assert_eq!(result.unwrap().port, 60006);
+        let (upload, stdin) = log.iter().find(|(cmd, _)| cmd.contains("cat >")).unwrap();
+        assert!(upload.contains("mosh-client/mosh-server-"));
+        assert_eq!(stdin, b"\x7fELF static mosh-server");
+    }
+
+    #[tokio::test]
+    async fn test_missing_server_suggests_fixes() {
+        let config = SshConfig::new("host", "alice");
+        let (result, _) = start_with_script(&config, |cmd, _| {
+            if cmd.starts_with("for d in") {
+                ("", "", 1)
+            } else {
+                NOT_FOUND
+            }
+        })
+        .await;
+        let err = format!("{:#}", result.unwrap_err());
+        assert!(err.contains("--upload-server"), "{}", err);
+    }
+
+    #[test]
+    fn test_command_not_found_needs_the_shell_complaint() {
+        let output = |stdout: &str, stderr: &str, status| RemoteOutput {
+            stdout: Zeroizing::new(stdout.as_bytes().to_vec()),
+            stderr: stderr.as_bytes().to_vec(),
+            exit_status: Some(status),
+        };
+        for complaint in [
+            "sh: 1: mosh-server: not found",
+            "bash: line 1: mosh-server: command not found",
+            "zsh:1: command not found: mosh-server",
+            "fish: Unknown command: mosh-server",
+            "mosh-server: Command not found.",
+        ] {
+            assert!(output("", complaint, 127).command_not_found("mosh-server"), "{}", complaint);
+        }
+        // Under a PTY, on stdout with CRLF.
+        assert!(output("bash: mosh-server: command not found\r\n", "", 127).command_not_found("mosh-server"));
+        assert!(output("", "bash: /opt/mosh/bin/mosh-server: No such file or directory\n", 127)
+            .command_not_found("/opt/mosh/bin/mosh-server"));
+
+        // Not a missing mosh-server: other status, other command, or its own failure.
+        assert!(!output("", "sh: 1: mosh-server: not found", 1).command_not_found("mosh-server"));
+        assert!(!output("", "sh: 1: locale: not found", 127).command_not_found("mosh-server"));
+        assert!(!output("", "", 127).command_not_found("mosh-server"));
+        let loader = "mosh-server: error while loading shared libraries: libprotobuf.so.32: \
+                      cannot open shared object file: No such file or directory";
+        assert!(!output("", loader, 127).command_not_found("mosh-server"));
+    }
+
+    #[tokio::test]
+    async fn test_server_failure_is_not_retried() {
+        let config = SshConfig::new("host", "alice");
+        let (result, log) = start_with_script(&config, |_, _| {
+            ("", "mosh-server needs a UTF-8 native locale to run.\n", 1)
+        })
+        .await;
+        let err = format!("{:#}", result.unwrap_err());
+        assert!(err.contains("status 1") && err.contains("UTF-8"), "{}", err);
+        assert_eq!(log.len(), 1);
+    }
+
+    // ── Bootstrap deadlines ──────────────────────────────────────────
+
+    /// TCP listener that accepts connections and never says anything.
+    async fn silent_listener() -> (u16, tokio::task::JoinHandle<()>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let task = tokio::spawn(async move {
+            let mut held = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                held.push(stream);
+            }
+        });
+        (port, task)
+    }
+
+    #[tokio::test]
+    async fn test_connect_times_out_on_silent_server() {
+        let (port, listener) = silent_listener().await;
+        let config = SshConfig::new("127.0.0.1", "alice")
+            .with_port(port)
+            .with_connect_timeout(Duration::from_millis(200));
+
+        let started = std::time::Instant::now();
+        let err = format!("{:#}", bootstrap(&config).await.unwrap_err());
+        assert!(err.contains("SSH connection timed out after 200ms"), "{}", err);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        listener.abort();
+    }
+
+    /// Server that authenticates anyone and opens channels, then never answers.
+    struct SilentShell;
+
+    impl server::Handler for SilentShell {
+        type Error = russh::Error;
+
+        async fn auth_none(&mut self, _user: &str) -> Result<server::Auth, Self::Error> {
+            Ok(server::Auth::Accept)
+        }
+
+        async fn channel_open_session(
+            &mut self,
+            _channel: Channel<server::Msg>,
+            reply: server::ChannelOpenHandle,
+            _session: &mut server::Session,
+        ) -> Result<(), Self::Error> {
+            reply.accept().await;
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_server_start_times_out_when_exec_hangs() {
+        let mut session = local_session(SilentShell).await;
+        assert!(session.authenticate_none("alice").await.unwrap().success());
+        let config = SshConfig::new("host", "alice");
+
+        let err = with_deadline(
+            &PromptActivity::default(),
+            "mosh-server start-up",
+            Duration::from_millis(200),
+            start_mosh_server(&session, &config),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.to_string(), "mosh-server start-up timed out after 200ms");
+    }
+
+    #[tokio::test]
+    async fn test_deadline_ignores_time_at_prompts() {
+        let limit = Duration::from_millis(100);
+        let prompts = PromptActivity::default();
+        let result = with_deadline(&prompts, "authentication", limit, async {
+            // Simulate a user taking longer than the limit to answer.
+            let prompt = prompts.prompt();
+            tokio::time::sleep(Duration::from_millis(250)).await;
+            drop(prompt);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            Ok(42)
+        })
+        .await;
+        assert_eq!(result.unwrap(), 42);
+
+        let stalled =
+            with_deadline(&prompts, "authentication", limit, std::future::pending::<Result<()>>());
+        assert!(stalled.await.is_err());
+
+        // A prompt in another bootstrap doesn't stretch this one's deadline.
+        let other = PromptActivity::default();
+        let _elsewhere = other.prompt();
+        let stalled =
+            with_deadline(&prompts, "authentication", limit, std::future::pending::<Result<()>>());
+        assert!(stalled.await.is_err());
+    }
+
+    // ── Secret sources ───────────────────────────────────────────────
+
+    #[test]
+    fn test_select_askpass() {
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |name: &str| {
+                vars.iter()
+                    .find(|(k, _)| *k == name)
+                    .map(|(_, v)| v.to_string())
+            }
+        };
+        let ssh = &[("SSH_ASKPASS", "/usr/bin/ssh-askpass")];
+        assert_eq!(select_askpass(env(ssh), true), None);
+        assert_eq!(
+            select_askpass(env(ssh), false),
+            Some(PathBuf::from("/usr/bin/ssh-askpass"))
+        );
+        let forced = &[("SSH_ASKPASS", "/a"), ("SSH_ASKPASS_REQUIRE", "force")];
+        assert_eq!(select_askpass(env(forced), true), Some(PathBuf::from("/a")));
+        let never = &[("SSH_ASKPASS", "/a"), ("SSH_ASKPASS_REQUIRE", "never")];
+        assert_eq!(select_askpass(env(never), false), None);
+        let mosh = &[("MOSH_ASKPASS", "/m"), ("SSH_ASKPASS", "/a")];
+        assert_eq!(select_askpass(env(mosh), true), Some(PathBuf::from("/m")));
+        assert_eq!(select_askpass(env(&[]), false), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_askpass() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = scratch_dir("askpass");
+        let script = dir.join("askpass");
+        std::fs::write(
+            &script,
+            "#!/bin/sh\ncase \"$1\" in *passphrase*) printf 's3cret\\r\\nignored\\n' ;; *) exit 1 ;; esac\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o700)).unwrap();
+
+        let prompts = PromptActivity::default();
+        let secret = run_askpass(&prompts, &script, "Enter passphrase for key 'k': ").unwrap();
+        assert_eq!(secret.as_str(), "s3cret");
+        assert!(run_askpass(&prompts, &script, "alice@host's password: ").is_none());
+        assert!(run_askpass(&prompts, &dir.join("missing"), "x").is_none());
+    }
+
+    #[test]
+    fn test_read_password_file() {
+        let dir = scratch_dir("password-file");
+        let path = dir.join("pass");
+        std::fs::write(&path, "hunter2 with spaces\r\nsecond line\n").unwrap();
+        assert_eq!(read_password_file(&path).unwrap().as_str(), "hunter2 with spaces");
+        std::fs::write(&path, "no-newline").unwrap();
+        assert_eq!(read_password_file(&path).unwrap().as_str(), "no-newline");
+        assert!(read_password_file(&dir.join("missing")).is_err());
+    }
 }
@@ -0,0 +1,350 @@
+//! Exit codes and the `--status-json` event stream.
+//!
+//! Every way the client can end maps to an `ExitStatus`, whose value is the
+//! process exit code. Failures are classified by attaching the status as
+//! anyhow context where they happen (`.context(ExitStatus::SshAuth)`); the
+//! status's `Display` text reads like the context messages it replaces.
+//!
+//! With `--status-json`, progress events are written to stderr as JSON lines,
+//! ending with an `exit` event carrying the same code and status name.
+//! `--stats-log` writes periodic `stats` events in the same format to a file.
+
+use crate::prediction::PredictionStats;
+use crate::transport::TransportStats;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::fmt;
+use std::io::Write as _;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How often `--stats-log` gets a line.
+pub const STATS_LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Why the client exited. The discriminant is the process exit code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExitStatus {
+    /// The remote shell exited and the server ended the session
+    /// (or there was nothing to connect to, as with `--print-config`).
+    Success = 0,
+    /// A failure that doesn't fit any other class.
+    Error = 1,
+    /// Bad command-line arguments (clap's own exit code).
+    Usage = 2,
+    /// The user quit with the command key (`Ctrl-^ .`).
+    UserQuit = 10,
+    /// The server went away without ending the session.
+    RemoteClosed = 11,
+    /// The SSH connection (TCP or handshake) failed or timed out.
+    SshConnect = 20,
+    /// The server's host key was refused.
+    HostKey = 21,
+    /// No SSH authentication method succeeded.
+    SshAuth = 22,
+    /// mosh-server couldn't be found or started, or didn't report MOSH CONNECT.
+    ServerStart = 23,
+    /// The server speaks a different mosh protocol version.
+    ProtocolVersion = 30,
+    /// Ctrl-C during start-up.
+    Interrupted = 130,
+}
+
+impl ExitStatus {
+    /// The process exit code.
+    pub fn code(self) -> u8 {
+        self as u8
+    }
+
+    /// Classify an error by the first `ExitStatus` attached to it.
+    pub fn of(err: &anyhow::Error) -> ExitStatus {
+        err.downcast_ref::<ExitStatus>()
+            .copied()
+            .unwrap_or(ExitStatus::Error)
+    }
+}
+
+impl fmt::Display for ExitStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Success => "session ended",
+            Self::Error => "error",
+            Self::Usage => "invalid arguments",
+            Self::UserQuit => "quit on user request",
+            Self::RemoteClosed => "remote host closed session",
+            Self::SshConnect => "SSH connection failed",
+            Self::HostKey => "Host key verification failed",
+            Self::SshAuth => "SSH authentication failed",
+            Self::ServerStart => "Failed to start mosh-server",
+            Self::ProtocolVersion => "mosh protocol version mismatch",
+            Self::Interrupted => "interrupted",
+        })
+    }
+}
+
+impl std::error::Error for ExitStatus {}
+
+/// A `--status-json` event.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum StatusEvent<'a> {
+    /// SSH bootstrap is starting.
+    Connecting { host: &'a str, port: u16, user: &'a str },
+    /// mosh-server is running (or `--direct` was given); UDP is starting.
+    ServerStarted { addr: String },
+    /// The first packet arrived from the server.
+    Connected,
+    /// No packet from the server for `seconds`.
+    Stalled { seconds: u64 },
+    /// Contact with the server resumed after a stall.
+    Resumed,
+    /// Session statistics (`--stats-log` only).
+    Stats(&'a SessionStats),
+    /// The client is exiting.
+    Exit {
+        code: u8,
+        status: ExitStatus,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+    },
+}
+
+/// Writes `--status-json` events to stderr, or nothing when disabled.
+#[derive(Debug, Clone, Copy)]
+pub struct StatusReporter {
+    enabled: bool,
+}
+
+impl StatusReporter {
+    pub fn new(enabled: bool) -> Self {
+        Self { enabled }
+    }
+
+    pub fn emit(&self, event: &StatusEvent) {
+        if !self.enabled {
+            return;
+        }
+        let line = to_json_line(event);
+        let mut stderr = std::io::stderr().lock();
+        let _ = stderr.write_all(line.as_bytes());
+        let _ = stderr.flush();
+    }
+}
+
+/// Transport and prediction statistics, for the overlay and `--stats-log`.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct SessionStats {
+    #[serde(flatten)]
+    pub transport: TransportStats,
+    #[serde(flatten)]
+    pub prediction: PredictionStats,
+}
+
+impl SessionStats {
+    /// The overlay text, one line per screen row.
+    pub fn overlay_lines(&self) -> Vec<String> {
+        let t = &self.transport;
+        let predictions = match self.prediction.hit_rate() {
+            Some(rate) => format!(
+                "{:.0}% of {} right",
+                rate * 100.0,
+                self.prediction.confirmed + self.prediction.wrong
+            ),
+            None => "none judged".to_string(),
+        };
+        vec![
+            format!(
+                "rtt {:.0}ms \u{b1}{:.0}ms, rto {}ms, send interval {}ms, mtu {}",
+                t.srtt_ms, t.rttvar_ms, t.rto_ms, t.send_interval_ms, t.mtu
+            ),
+            format!(
+                "sent {} pkts / {}, received {} pkts / {}",
+                t.traffic.packets_sent,
+                human_bytes(t.traffic.bytes_sent),
+                t.traffic.packets_received,
+                human_bytes(t.traffic.bytes_received)
+            ),
+            format!(
+                "retransmits {}, bad packets {}, queued states {} out / {} in",
+                t.traffic.retransmits, t.traffic.decrypt_failures, t.sent_states, t.received_states
+            ),
+            format!("predictions: {}", predictions),
+        ]
+    }
+}
+
+fn human_bytes(n: u64) -> String {
+    match n {
+        0..=9_999 => format!("{} B", n),
+        10_000..=9_999_999 => format!("{:.1} KiB", n as f64 / 1024.0),
+        _ => format!("{:.1} MiB", n as f64 / (1024.0 * 1024.0)),
+    }
+}
+
+/// Appends a `stats` event to a file every `STATS_LOG_INTERVAL`.
+pub struct StatsLog {
+    file: std::fs::File,
+    last: Instant,
+}
+
+impl StatsLog {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open stats log {}", path.display()))?;
+        Ok(Self { file, last: Instant::now() })
+    }
+
+    /// When the next line is due.
+    pub fn next_due(&self) -> Instant {
+        self.last + STATS_LOG_INTERVAL
+    }
+
+    /// Write a line if one is due; `stats` is only called then.
+    pub fn poll(&mut self, stats: impl FnOnce() -> SessionStats) {
+        if self.last.elapsed() >= STATS_LOG_INTERVAL {
+            self.write(&stats());
+        }
+    }
+
+    pub fn write(&mut self, stats: &SessionStats) {
+        self.last = Instant::now();
+        if let Err(e) = self.file.write_all(to_json_line(&StatusEvent::Stats(stats)).as_bytes()) {
+            log::warn!("Failed to write stats log: {}", e);
+        }
+    }
+}
+
+/// One JSON line for `event`, stamped with the Unix time in seconds.
+/// Lines end in `\r\n` so they stay readable on a raw-mode terminal.
+fn to_json_line(event: &StatusEvent) -> String {
+    let mut value = serde_json::to_value(event).unwrap_or_default();
+    if let Some(fields) = value.as_object_mut() {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or_default();
+        fields.insert("time".to_string(), now.into());
+    }
+    format!("{}\r\n", value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scratch::ScratchFile;
+    use anyhow::Context;
+
+    #[test]
+    fn test_status_found_through_context() {
+        let err = std::io::Error::new(std::io::ErrorKind::TimedOut, "timed out");
+        let err = Err::<(), _>(err)
+            .context(ExitStatus::SshConnect)
+            .context("while bootstrapping")
+            .unwrap_err();
+        assert_eq!(ExitStatus::of(&err), ExitStatus::SshConnect);
+        assert!(format!("{:#}", err).contains("SSH connection failed: timed out"));
+
+        let plain = anyhow::anyhow!("something else");
+        assert_eq!(ExitStatus::of(&plain), ExitStatus::Error);
+    }
+
+    #[test]
+    fn test_exit_codes_are_distinct() {
+        let all = [
+            ExitStatus::Success,
+            ExitStatus::Error,
+            ExitStatus::Usage,
+            ExitStatus::UserQuit,
+            ExitStatus::RemoteClosed,
+            ExitStatus::SshConnect,
+            ExitStatus::HostKey,
+            ExitStatus::SshAuth,
+            ExitStatus::ServerStart,
+            ExitStatus::ProtocolVersion,
+            ExitStatus::Interrupted,
+        ];
+        let mut codes: Vec<u8> = all.iter().map(|s| s.code()).collect();
+        codes.sort_unstable();
+        codes.dedup();
+        assert_eq!(codes.len(), all.len());
+    }
+
+    #[test]
+    fn test_event_json() {
+        let line = to_json_line(&StatusEvent::Exit {
+            code: ExitStatus::SshAuth.code(),
+            status: ExitStatus::SshAuth,
+            message: Some("no method accepted".to_string()),
+        });
+        assert!(line.ends_with("\r\n"));
+        let value: serde_json::Value = serde_json::from_str(line.trim_end()).unwrap();
+        assert_eq!(value["event"], "exit");
+        assert_eq!(value["code"], 22);
+        assert_eq!(value["status"], "ssh-auth");
+        assert_eq!(value["message"], "no method accepted");
+        assert!(value["time"].as_f64().unwrap() > 0.0);
+
+        let line = to_json_line(&StatusEvent::Connecting { host: "h", port: 22, user: "u" });
+        let value: serde_json::Value = serde_json::from_str(line.trim_end()).unwrap();
+        assert_eq!(value["event"], "connecting");
+        assert_eq!(value["port"], 22);
+    }
+
+    fn sample_stats() -> SessionStats {
+        SessionStats {
+            transport: TransportStats {
+                srtt_ms: 42.4,
+                rttvar_ms: 5.0,
+                rto_ms: 62,
+                send_interval_ms: 21,
+                mtu: 1472,
+                traffic: crate::transport::Traffic {
+                    packets_sent: 120,
+                    bytes_sent: 15_600,
+                    packets_received: 98,
+                    bytes_received: 812,
+                    retransmits: 2,
+                    decrypt_failures: 1,
+                },
+                sent_states: 3,
+                received_states: 2,
+            },
+            prediction: PredictionStats { confirmed: 39, wrong: 1 },
+        }
+    }
+
+    #[test]
+    fn test_stats_event_json() {
+        let stats = sample_stats();
+        let line = to_json_line(&StatusEvent::Stats(&stats));
+        let value: serde_json::Value = serde_json::from_str(line.trim_end()).unwrap();
+        assert_eq!(value["event"], "stats");
+        assert_eq!(value["srtt_ms"], 42.4);
+        assert_eq!(value["retransmits"], 2);
+        assert_eq!(value["decrypt_failures"], 1);
+        assert_eq!(value["confirmed"], 39);
+    }
+
+    #[test]
+    fn test_stats_overlay_lines() {
+        let lines = sample_stats().overlay_lines();
+        assert_eq!(lines[0], "rtt 42ms \u{b1}5ms, rto 62ms, send interval 21ms, mtu 1472");
+        assert_eq!(lines[1], "sent 120 pkts / 15.2 KiB, received 98 pkts / 812 B");
+        assert_eq!(lines[3], "predictions: 98% of 40 right");
+    }
+
+    #[test]
+    fn test_stats_log_appends() {
+        let file = ScratchFile::new("stats.jsonl");
+        let mut log = StatsLog::open(file.path()).unwrap();
+        log.poll(|| unreachable!("not due yet"));
+        log.write(&sample_stats());
+        log.write(&sample_stats());
+        drop(log);
+        let text = std::fs::read_to_string(file.path()).unwrap();
+        assert_eq!(text.lines().count(), 2);
+    }
+}
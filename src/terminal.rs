@@ -18,19 +18,14 @@ pub struct Attributes {
 }
 
 /// Terminal color representation.
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
 pub enum Color {
+    #[default]
     Default,
     Indexed(u8),
     Rgb(u8, u8, u8),
 }
 
-impl Default for Color {
-    fn default() -> Self {
-        Color::Default
-    }
-}
-
 /// A single cell in the terminal framebuffer.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Cell {
@@ -54,21 +49,6 @@ impl Default for Cell {
     }
 }
 
-/// Cursor style.
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-#[allow(dead_code)]
-pub enum CursorStyle {
-    Block,
-    Underline,
-    Bar,
-}
-
-impl Default for CursorStyle {
-    fn default() -> Self {
-        CursorStyle::Block
-    }
-}
-
 /// The terminal framebuffer: a 2D grid of cells plus cursor state.
 #[derive(Clone)]
 pub struct Framebuffer {
@@ -78,7 +58,6 @@ pub struct Framebuffer {
     pub cursor_row: usize,
     pub cursor_col: usize,
     pub cursor_visible: bool,
-    pub cursor_style: CursorStyle,
     /// Current drawing attributes for new characters.
     current_attrs: Attributes,
     current_fg: Color,
@@ -120,7 +99,6 @@ impl Framebuffer {
             cursor_row: 0,
             cursor_col: 0,
             cursor_visible: true,
-            cursor_style: CursorStyle::default(),
             current_attrs: Attributes::default(),
             current_fg: Color::Default,
             current_bg: Color::Default,
@@ -142,10 +120,8 @@ impl Framebuffer {
         let mut new_cells = vec![vec![Cell::default(); new_width]; new_height];
         let copy_rows = self.height.min(new_height);
         let copy_cols = self.width.min(new_width);
-        for row in 0..copy_rows {
-            for col in 0..copy_cols {
-                new_cells[row][col] = self.cells[row][col].clone();
-            }
+        for (new_row, old_row) in new_cells.iter_mut().zip(&self.cells).take(copy_rows) {
+            new_row[..copy_cols].clone_from_slice(&old_row[..copy_cols]);
         }
         self.cells = new_cells;
         self.width = new_width;
@@ -554,49 +530,41 @@ impl Framebuffer {
                 29 => self.current_attrs.strikethrough = false,
                 // Standard foreground colors
                 30..=37 => self.current_fg = Color::Indexed((params[i] - 30) as u8),
-                38 => {
-                    // Extended foreground color
-                    if i + 1 < params.len() {
-                        match params[i + 1] {
-                            5 if i + 2 < params.len() => {
-                                self.current_fg = Color::Indexed(params[i + 2] as u8);
-                                i += 2;
-                            }
-                            2 if i + 4 < params.len() => {
-                                self.current_fg = Color::Rgb(
-                                    params[i + 2] as u8,
-                                    params[i + 3] as u8,
-                                    params[i + 4] as u8,
-                                );
-                                i += 4;
-                            }
-                            _ => {}
-                        }
+                // Extended foreground color
+                38 if i + 1 < params.len() => match params[i + 1] {
+                    5 if i + 2 < params.len() => {
+                        self.current_fg = Color::Indexed(params[i + 2] as u8);
+                        i += 2;
                     }
-                }
+                    2 if i + 4 < params.len() => {
+                        self.current_fg = Color::Rgb(
+                            params[i + 2] as u8,
+                            params[i + 3] as u8,
+                            params[i + 4] as u8,
+                        );
+                        i += 4;
+                    }
+                    _ => {}
+                },
                 39 => self.current_fg = Color::Default,
                 // Standard background colors
                 40..=47 => self.current_bg = Color::Indexed((params[i] - 40) as u8),
-                48 => {
-                    // Extended background color
-                    if i + 1 < params.len() {
-                        match params[i + 1] {
-                            5 if i + 2 < params.len() => {
-                                self.current_bg = Color::Indexed(params[i + 2] as u8);
-                                i += 2;
-                            }
-                            2 if i + 4 < params.len() => {
-                                self.current_bg = Color::Rgb(
-                                    params[i + 2] as u8,
-                                    params[i + 3] as u8,
-                                    params[i + 4] as u8,
-                                );
-                                i += 4;
-                            }
-                            _ => {}
-                        }
+                // Extended background color
+                48 if i + 1 < params.len() => match params[i + 1] {
+                    5 if i + 2 < params.len() => {
+                        self.current_bg = Color::Indexed(params[i + 2] as u8);
+                        i += 2;
                     }
-                }
+                    2 if i + 4 < params.len() => {
+                        self.current_bg = Color::Rgb(
+                            params[i + 2] as u8,
+                            params[i + 3] as u8,
+                            params[i + 4] as u8,
+                        );
+                        i += 4;
+                    }
+                    _ => {}
+                },
                 49 => self.current_bg = Color::Default,
                 // Bright foreground colors
                 90..=97 => self.current_fg = Color::Indexed((params[i] - 90 + 8) as u8),
@@ -636,7 +604,7 @@ impl<'a> vte::Perform for VtPerformer<'a> {
                 self.fb.wrap_pending = wrap_state;
             }
             // LF, VT, FF - line feed
-            0x0A | 0x0B | 0x0C => {
+            0x0A..=0x0C => {
                 self.fb.move_rows_autoscroll(1);
             }
             // CR - carriage return
@@ -646,10 +614,8 @@ impl<'a> vte::Perform for VtPerformer<'a> {
             // SO, SI - shift out/in (charset switching, minimal support)
             0x0E | 0x0F => {}
             // HTS - horizontal tab set
-            0x88 => {
-                if self.fb.cursor_col < self.fb.tab_stops.len() {
-                    self.fb.tab_stops[self.fb.cursor_col] = true;
-                }
+            0x88 if self.fb.cursor_col < self.fb.tab_stops.len() => {
+                self.fb.tab_stops[self.fb.cursor_col] = true;
             }
             _ => {}
         }
@@ -688,7 +654,7 @@ impl<'a> vte::Perform for VtPerformer<'a> {
         _ignore: bool,
         action: char,
     ) {
-        let params_vec: Vec<u16> = params.iter().flat_map(|sub| sub.iter().map(|&v| v as u16)).collect();
+        let params_vec: Vec<u16> = params.iter().flat_map(|sub| sub.iter().copied()).collect();
         let p1 = params_vec.first().copied().unwrap_or(0);
         let p2 = params_vec.get(1).copied().unwrap_or(0);
 
@@ -821,10 +787,8 @@ impl<'a> vte::Perform for VtPerformer<'a> {
             }
             // TBC - Tab clear
             'g' => match p1 {
-                0 => {
-                    if self.fb.cursor_col < self.fb.tab_stops.len() {
-                        self.fb.tab_stops[self.fb.cursor_col] = false;
-                    }
+                0 if self.fb.cursor_col < self.fb.tab_stops.len() => {
+                    self.fb.tab_stops[self.fb.cursor_col] = false;
                 }
                 3 => {
                     for tab in &mut self.fb.tab_stops {
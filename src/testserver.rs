@@ -0,0 +1,234 @@
+//! A mosh-server stand-in for end-to-end tests.
+//!
+//! `TestServer` is a `ServerTransport` on a loopback UDP socket with a
+//! scripted host in place of the pty, so a client `Transport` can run a
+//! whole session against it: keystrokes go to its `HostSource`, and the
+//! output comes back as the same framebuffer diffs `mosh-client server`
+//! sends, with resizes and echo acks.
+
+use crate::crypto::Base64Key;
+use crate::display::same_frame;
+use crate::server::ServerTransport;
+use crate::terminal::Terminal;
+use crate::transport::Transport;
+use crate::userstream::{UserEvent, UserStream};
+use anyhow::{Context, Result};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Where the stand-in's host output comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HostSource {
+    /// Keystrokes come back the way a terminal in echo mode shows them,
+    /// with CR echoed as CRLF.
+    Echo,
+    /// Output only comes from `TestServer::write`.
+    Scripted,
+}
+
+pub struct TestServer {
+    transport: ServerTransport,
+    source: HostSource,
+}
+
+impl TestServer {
+    /// Listen on an ephemeral loopback port.
+    pub async fn bind(key: &Base64Key, source: HostSource, width: usize, height: usize) -> Result<Self> {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").context("Failed to bind UDP socket")?;
+        Ok(Self {
+            transport: ServerTransport::new(key, socket, width, height)?,
+            source,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.transport.local_addr()
+    }
+
+    pub fn terminal(&self) -> &Terminal {
+        self.transport.terminal()
+    }
+
+    /// Everything the client has typed so far.
+    pub fn client_input(&self) -> &UserStream {
+        self.transport.client_input()
+    }
+
+    /// Whether the client has started shutting the session down.
+    pub fn client_shut_down(&self) -> bool {
+        self.transport.client_shut_down()
+    }
+
+    /// Produce host output, as the shell would.
+    pub fn write(&mut self, bytes: &[u8]) {
+        self.transport.host_output(bytes);
+    }
+
+    /// End the session from the server side.
+    pub fn close(&mut self) {
+        self.transport.close();
+    }
+
+    /// Whether the client acknowledged our shutdown.
+    pub fn shutdown_acknowledged(&self) -> bool {
+        self.transport.shutdown_acknowledged()
+    }
+
+    /// Handle whatever arrived, then send if anything is due.
+    pub async fn step(&mut self) -> Result<()> {
+        self.transport.drain_recv()?;
+        let mut echo = Vec::new();
+        for event in self.transport.take_user_events() {
+            match event {
+                UserEvent::Keystroke(b'\r') => echo.extend_from_slice(b"\r\n"),
+                UserEvent::Keystroke(byte) => echo.push(byte),
+                UserEvent::Resize { .. } => {}
+            }
+        }
+        if self.source == HostSource::Echo && !echo.is_empty() {
+            self.write(&echo);
+        }
+        self.transport.tick().await
+    }
+}
+
+/// One round trip: the client handles what arrived and sends, then the server.
+pub async fn exchange(client: &mut Transport, server: &mut TestServer) {
+    client.drain_recv().unwrap();
+    client.tick().await.unwrap();
+    server.step().await.unwrap();
+    tokio::time::sleep(Duration::from_millis(1)).await;
+}
+
+/// Run both ends until `done`, failing after five seconds.
+pub async fn run_until(
+    client: &mut Transport,
+    server: &mut TestServer,
+    done: impl Fn(&Transport, &TestServer) -> bool,
+) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !done(client, server) {
+        assert!(Instant::now() < deadline, "session didn't get there in time");
+        exchange(client, server).await;
+    }
+}
+
+/// Run both ends until everything typed is echoed and the client's screen
+/// matches the server's.
+pub async fn settle(client: &mut Transport, server: &mut TestServer) {
+    let typed = client.sent_state_last_num();
+    run_until(client, server, |c, s| {
+        c.latest_remote_echo_ack() >= typed && same_frame(c.latest_remote_framebuffer(), &s.terminal().fb)
+    })
+    .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::Direction;
+    use crate::terminal::Framebuffer;
+
+    async fn session(source: HostSource) -> (Transport, TestServer) {
+        let key = Base64Key::from_str("AAAAAAAAAAAAAAAAAAAAAA").unwrap();
+        let server = TestServer::bind(&key, source, 80, 24).await.unwrap();
+        let mut client = Transport::new(&key, server.local_addr(), Direction::ToServer, 80, 24)
+            .await
+            .unwrap();
+        client.push_resize(80, 24);
+        (client, server)
+    }
+
+    fn row(fb: &Framebuffer, r: usize) -> String {
+        fb.cells[r].iter().map(|c| c.character).collect::<String>().trim_end().to_string()
+    }
+
+    #[tokio::test]
+    async fn echo_session() {
+        let (mut client, mut server) = session(HostSource::Echo).await;
+        client.push_user_input(b"echo hi\r");
+        run_until(&mut client, &mut server, |c, _| {
+            row(c.latest_remote_framebuffer(), 0) == "echo hi"
+        })
+        .await;
+        settle(&mut client, &mut server).await;
+        assert_eq!(client.latest_remote_framebuffer().cursor_row, 1);
+
+        let mut expected = UserStream::new();
+        expected.push_resize(80, 24);
+        expected.push_keystrokes(b"echo hi\r");
+        assert_eq!(server.client_input(), &expected);
+    }
+
+    #[tokio::test]
+    async fn scripted_output_and_resize() {
+        let (mut client, mut server) = session(HostSource::Scripted).await;
+        server.write(b"\x1b[2J\x1b[H\x1b[1mbanner\x1b[0m\r\n");
+        run_until(&mut client, &mut server, |c, _| {
+            row(c.latest_remote_framebuffer(), 0) == "banner"
+        })
+        .await;
+        assert!(client.latest_remote_framebuffer().cells[0][0].attrs.bold);
+
+        client.push_resize(100, 30);
+        run_until(&mut client, &mut server, |c, _| c.latest_remote_framebuffer().width == 100).await;
+        assert_eq!(server.terminal().fb.height, 30);
+
+        // Typing doesn't echo with a scripted source.
+        client.push_user_input(b"x");
+        run_until(&mut client, &mut server, |_, s| s.client_input().len() == 3).await;
+        server.write(b"done");
+        run_until(&mut client, &mut server, |c, s| {
+            same_frame(c.latest_remote_framebuffer(), &s.terminal().fb)
+                && row(c.latest_remote_framebuffer(), 1) == "done"
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn large_output_is_fragmented() {
+        let (mut client, mut server) = session(HostSource::Scripted).await;
+        client.push_resize(200, 60);
+        run_until(&mut client, &mut server, |_, s| s.terminal().fb.width == 200).await;
+
+        // A screenful of noise compresses to well over one datagram.
+        let mut seed = 1u64;
+        for i in 0..300 {
+            let line: String = (0..150)
+                .map(|_| {
+                    seed = seed.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1_442_695_040_888_963_407);
+                    (b'!' + (seed >> 33) as u8 % 94) as char
+                })
+                .collect();
+            server.write(format!("{:03} {}\r\n", i, line).as_bytes());
+        }
+        run_until(&mut client, &mut server, |c, s| {
+            same_frame(c.latest_remote_framebuffer(), &s.terminal().fb)
+        })
+        .await;
+        assert!(row(client.latest_remote_framebuffer(), 58).starts_with("299 "));
+    }
+
+    #[tokio::test]
+    async fn client_initiated_shutdown() {
+        let (mut client, mut server) = session(HostSource::Echo).await;
+        client.push_user_input(b"exit\r");
+        run_until(&mut client, &mut server, |_, s| s.client_input().len() == 6).await;
+        client.start_shutdown();
+        run_until(&mut client, &mut server, |c, _| c.shutdown_acknowledged()).await;
+        assert!(server.client_shut_down());
+    }
+
+    #[tokio::test]
+    async fn server_initiated_shutdown() {
+        let (mut client, mut server) = session(HostSource::Echo).await;
+        server.write(b"logout\r\n");
+        server.close();
+        run_until(&mut client, &mut server, |c, s| {
+            c.counterparty_shutdown_ack_sent() && s.shutdown_acknowledged()
+        })
+        .await;
+        assert_eq!(row(client.latest_remote_framebuffer(), 0), "logout");
+    }
+}
@@ -6,10 +6,13 @@
 //! - Processes incoming diffs and acknowledgments
 //! - Handles retransmission timing
 
+use crate::asciicast::Asciicast;
 use crate::crypto::{self, Base64Key, Direction, Session};
 use crate::network::{
-    current_timestamp, Fragment, FragmentAssembly, Fragmenter, Packet, MAX_FRAG_PAYLOAD,
+    timestamp_since, Fragment, FragmentAssembly, Fragmenter, Packet, PathMtu,
 };
+use crate::recording::{Flow, Recorder};
+use crate::status::ExitStatus;
 use crate::terminal::{Framebuffer, Terminal};
 use crate::userstream::UserStream;
 use anyhow::{Context, Result};
@@ -20,11 +23,12 @@ use prost::Message;
 use rand::RngCore;
 use std::io::{Read as _, Write as _};
 use std::net::SocketAddr;
-use std::time::{Duration, Instant};
+use std::time::Duration;
 use tokio::net::UdpSocket;
+use tokio::time::Instant;
 
 /// Protocol version number (matches upstream mosh).
-const MOSH_PROTOCOL_VERSION: u32 = 2;
+pub(crate) const MOSH_PROTOCOL_VERSION: u32 = 2;
 
 // ── Timing constants (1:1 with mosh transportsender.h) ─────────────────────
 const SEND_INTERVAL_MIN: u64 = 20;           // ms between frames
@@ -37,6 +41,7 @@ const SEND_MINDELAY: u64 = 8;                // ms to collect all input
 const RECEIVED_QUEUE_LIMIT: usize = 1024;
 const RECEIVER_QUENCH_MS: u64 = 15_000;
 const CHAFF_MAX_LEN: usize = 16;
+const RECV_BUFFER_LEN: usize = 65_536;       // largest possible UDP datagram
 
 // ── RTT estimator constants ────────────────────────────────────────────────
 const RTO_MIN_MS: u64 = 50;
@@ -162,20 +167,20 @@ struct TimestampedState {
 
 /// Remote terminal state modeled like upstream `statesync::Complete`.
 #[derive(Debug, Clone)]
-struct RemoteState {
+pub struct RemoteState {
     terminal: Terminal,
     echo_ack: u64,
 }
 
 impl RemoteState {
-    fn new(width: usize, height: usize) -> Self {
+    pub fn new(width: usize, height: usize) -> Self {
         Self {
             terminal: Terminal::new(width, height),
             echo_ack: 0,
         }
     }
 
-    fn apply_string(&mut self, diff: &[u8]) -> Result<()> {
+    pub fn apply_string(&mut self, diff: &[u8]) -> Result<()> {
         if diff.is_empty() {
             return Ok(());
         }
@@ -205,6 +210,10 @@ impl RemoteState {
 
         Ok(())
     }
+
+    pub fn framebuffer(&self) -> &Framebuffer {
+        &self.terminal.fb
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -249,6 +258,100 @@ impl RttEstimator {
     }
 }
 
+// ── Stats ──────────────────────────────────────────────────────────────────
+
+/// Datagram totals since the transport started.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize)]
+pub struct Traffic {
+    pub packets_sent: u64,
+    pub bytes_sent: u64,
+    pub packets_received: u64,
+    pub bytes_received: u64,
+    /// States sent again because they weren't acknowledged in time.
+    pub retransmits: u64,
+    /// Datagrams dropped because they failed to decrypt.
+    pub decrypt_failures: u64,
+}
+
+/// A snapshot of the transport's timing, traffic and queues.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize)]
+pub struct TransportStats {
+    pub srtt_ms: f64,
+    pub rttvar_ms: f64,
+    pub rto_ms: u64,
+    pub send_interval_ms: u64,
+    pub mtu: usize,
+    #[serde(flatten)]
+    pub traffic: Traffic,
+    /// Our states the server hasn't acknowledged yet, plus the acked one.
+    pub sent_states: usize,
+    /// Server states kept as bases for incoming diffs.
+    pub received_states: usize,
+}
+
+// ── Link ───────────────────────────────────────────────────────────────────
+
+/// Where datagrams go: the UDP socket, or an in-process simulated network in
+/// tests. The client's socket is connected to the server; the server's
+/// isn't, so it uses `send_to` and `try_recv_from`.
+pub(crate) enum Link {
+    Udp(UdpSocket),
+    #[cfg(test)]
+    Sim(crate::linksim::SimEndpoint),
+}
+
+impl Link {
+    async fn send(&self, datagram: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Link::Udp(socket) => socket.send(datagram).await,
+            #[cfg(test)]
+            Link::Sim(endpoint) => endpoint.send(datagram),
+        }
+    }
+
+    #[cfg(any(unix, test))]
+    pub(crate) async fn send_to(&self, datagram: &[u8], to: SocketAddr) -> std::io::Result<usize> {
+        match self {
+            Link::Udp(socket) => socket.send_to(datagram, to).await,
+            #[cfg(test)]
+            Link::Sim(endpoint) => endpoint.send(datagram),
+        }
+    }
+
+    fn try_recv(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Link::Udp(socket) => socket.try_recv(buf),
+            #[cfg(test)]
+            Link::Sim(endpoint) => endpoint.try_recv(buf),
+        }
+    }
+
+    #[cfg(any(unix, test))]
+    pub(crate) fn try_recv_from(&self, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+        match self {
+            Link::Udp(socket) => socket.try_recv_from(buf),
+            #[cfg(test)]
+            Link::Sim(endpoint) => Ok((endpoint.try_recv(buf)?, endpoint.peer_addr())),
+        }
+    }
+
+    pub(crate) async fn readable(&self) -> std::io::Result<()> {
+        match self {
+            Link::Udp(socket) => socket.readable().await,
+            #[cfg(test)]
+            Link::Sim(endpoint) => endpoint.readable().await,
+        }
+    }
+
+    pub(crate) fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        match self {
+            Link::Udp(socket) => socket.local_addr(),
+            #[cfg(test)]
+            Link::Sim(endpoint) => Ok(endpoint.local_addr()),
+        }
+    }
+}
+
 // ── Transport ──────────────────────────────────────────────────────────────
 
 /// The Mosh transport: manages the SSP state exchange over encrypted UDP.
@@ -263,12 +366,21 @@ impl RttEstimator {
 pub struct Transport {
     // ── Network ──────────────────────────────────────────────────────
     session: Session,
-    socket: UdpSocket,
+    link: Link,
     direction: Direction,
+    /// Zero point of our packet timestamps.
+    epoch: Instant,
     next_seq: u64,
     fragmenter: Fragmenter,
     assembly: FragmentAssembly,
     rtt: RttEstimator,
+    path_mtu: PathMtu,
+    recv_buf: Vec<u8>,
+    traffic: Traffic,
+    /// `--record` output, if any.
+    recorder: Option<Recorder>,
+    /// `--asciicast` output, if any.
+    asciicast: Option<Asciicast>,
 
     // ── TransportSender state (1:1 with mosh) ─────────────────────
     /// The current full user input state.
@@ -308,6 +420,8 @@ pub struct Transport {
     last_recv_time: Instant,
     /// Remote session closure status (e.g., ICMP port unreachable after logout).
     remote_closed: Option<String>,
+    /// Whether the closure followed a shutdown handshake.
+    remote_closed_gracefully: bool,
 }
 
 impl Transport {
@@ -318,11 +432,32 @@ impl Transport {
         width: usize,
         height: usize,
     ) -> Result<Self> {
-        let session = Session::new(key)?;
         let bind_addr = if remote_addr.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" };
         let socket = UdpSocket::bind(bind_addr).await.context("Failed to bind UDP socket")?;
         socket.connect(remote_addr).await.context("Failed to connect UDP socket")?;
+        Self::with_link(key, Link::Udp(socket), remote_addr.is_ipv6(), direction, width, height)
+    }
 
+    /// A client transport over a simulated network (see `linksim`).
+    #[cfg(test)]
+    pub(crate) fn with_sim_link(
+        key: &Base64Key,
+        endpoint: crate::linksim::SimEndpoint,
+        width: usize,
+        height: usize,
+    ) -> Result<Self> {
+        Self::with_link(key, Link::Sim(endpoint), false, Direction::ToServer, width, height)
+    }
+
+    fn with_link(
+        key: &Base64Key,
+        link: Link,
+        ipv6: bool,
+        direction: Direction,
+        width: usize,
+        height: usize,
+    ) -> Result<Self> {
+        let session = Session::new(key)?;
         let now = Instant::now();
         let initial_state = UserStream::new();
         let initial_ts = TimestampedState { timestamp: now, num: 0, state: initial_state.clone() };
@@ -333,11 +468,17 @@ impl Transport {
         };
 
         Ok(Self {
-            session, socket, direction,
+            session, link, direction,
+            epoch: now,
             next_seq: 0,
             fragmenter: Fragmenter::new(),
             assembly: FragmentAssembly::new(),
             rtt: RttEstimator::new(),
+            path_mtu: PathMtu::new(ipv6, now),
+            recv_buf: vec![0u8; RECV_BUFFER_LEN],
+            traffic: Traffic::default(),
+            recorder: None,
+            asciicast: None,
             current_state: initial_state,
             sent_states: vec![initial_ts],
             assumed_receiver_state: 0,
@@ -357,11 +498,12 @@ impl Transport {
             last_recv_timestamp: u16::MAX,
             last_recv_time: now,
             remote_closed: None,
+            remote_closed_gracefully: false,
         })
     }
 
     pub fn local_addr(&self) -> Result<SocketAddr> {
-        self.socket.local_addr().context("Failed to get local addr")
+        self.link.local_addr().context("Failed to get local addr")
     }
 
     pub fn time_since_last_recv(&self) -> Duration { self.last_recv_time.elapsed() }
@@ -382,6 +524,12 @@ impl Transport {
         self.remote_closed.as_deref()
     }
 
+    /// Whether the remote side closed after a shutdown handshake, rather than
+    /// disappearing mid-session.
+    pub fn remote_closed_gracefully(&self) -> bool {
+        self.remote_closed_gracefully
+    }
+
     pub fn start_shutdown(&mut self) {
         if !self.shutdown_in_progress {
             self.shutdown_in_progress = true;
@@ -416,6 +564,21 @@ impl Transport {
         self.counterparty_shutdown_ack_sent
     }
 
+    /// Log every instruction from now on (`--record`), starting with a
+    /// header carrying the size the remote screen started at.
+    pub fn record_to(&mut self, mut recorder: Recorder) {
+        let fb = self.received_states[0].state.framebuffer();
+        recorder.header(fb.width, fb.height);
+        self.recorder = Some(recorder);
+    }
+
+    /// Write the remote screen to an asciicast from now on (`--asciicast`).
+    pub fn cast_to(&mut self, mut cast: Asciicast) {
+        let fb = self.received_states[0].state.framebuffer();
+        cast.header(fb.width, fb.height);
+        self.asciicast = Some(cast);
+    }
+
     pub fn latest_remote_framebuffer(&self) -> &Framebuffer {
         &self
             .received_states
@@ -587,6 +750,7 @@ impl Transport {
         } else if self.current_state == self.sent_states.last().unwrap().state {
             // Previously sent same state — reuse number, update timestamp
             self.sent_states.last_mut().unwrap().timestamp = Instant::now();
+            self.traffic.retransmits += 1;
             back_num
         } else {
             let n = back_num + 1;
@@ -608,10 +772,12 @@ impl Transport {
     // ── send_empty_ack (1:1 with mosh) ─────────────────────────────
     async fn send_empty_ack(&mut self) -> Result<()> {
         // Match mosh transportsender: empty ACK advances state number.
-        let mut new_num = self.sent_states.last().unwrap().num + 1;
-        if self.shutdown_in_progress {
-            new_num = u64::MAX;
-        }
+        // (Checked first: the last state may already be the shutdown state.)
+        let new_num = if self.shutdown_in_progress {
+            u64::MAX
+        } else {
+            self.sent_states.last().unwrap().num + 1
+        };
         let state_clone = self.current_state.clone();
         self.add_sent_state(Instant::now(), new_num, &state_clone);
         let assumed_num = self.sent_states[self.assumed_receiver_state].num;
@@ -640,16 +806,42 @@ impl Transport {
             self.counterparty_shutdown_ack_sent = true;
         }
 
+        if let Some(recorder) = self.recorder.as_mut() {
+            recorder.instruction(Flow::Sent, &instruction);
+        }
         let encoded = instruction.encode_to_vec();
         let compressed = zlib_compress(&encoded)?;
-        let fragments = self.fragmenter.make_fragments(&compressed, MAX_FRAG_PAYLOAD);
+        let fragments = self
+            .fragmenter
+            .make_fragments(&compressed, self.path_mtu.max_frag_payload());
+        let mut largest = 0;
         for frag in fragments {
-            self.send_packet(&frag.to_bytes()).await?;
+            largest = largest.max(self.send_packet(&frag.to_bytes()).await?);
         }
+        self.path_mtu.on_send(new_num, largest, Instant::now());
         self.pending_data_ack = false;
         Ok(())
     }
 
+    // ── wait_time (1:1 with mosh) ──────────────────────────────────
+    /// When `tick` next has something to do: the next send or ack, or the
+    /// end of the shutdown handshake's retry window. `None` once the
+    /// remote side has closed, as only packets can matter then.
+    pub fn next_wakeup(&mut self) -> Option<Instant> {
+        if self.remote_closed.is_some() {
+            return None;
+        }
+        self.calculate_timers();
+        let mut next = self.next_ack_time;
+        if let Some(send) = self.next_send_time {
+            next = next.min(send);
+        }
+        if let Some(start) = self.shutdown_start {
+            next = next.min(start + Duration::from_millis(ACTIVE_RETRY_TIMEOUT));
+        }
+        Some(next)
+    }
+
     // ── tick (1:1 with mosh) ───────────────────────────────────────
     pub async fn tick(&mut self) -> Result<()> {
         if self.remote_closed.is_some() {
@@ -657,6 +849,7 @@ impl Transport {
         }
 
         self.calculate_timers();
+        self.path_mtu.poll(Instant::now());
 
         if !self.has_remote_addr() {
             return Ok(());
@@ -709,37 +902,45 @@ impl Transport {
 
     // ── Packet send/recv ───────────────────────────────────────────
 
-    async fn send_packet(&mut self, payload: &[u8]) -> Result<()> {
+    /// Encrypt and send one fragment; returns the datagram length.
+    async fn send_packet(&mut self, payload: &[u8]) -> Result<usize> {
         let seq = self.next_seq;
         self.next_seq += 1;
         let nonce = crypto::make_nonce(self.direction, seq);
         let pkt = Packet {
-            timestamp: current_timestamp(),
+            timestamp: timestamp_since(self.epoch),
             timestamp_reply: self.last_recv_timestamp,
             payload: payload.to_vec(),
         };
         let encrypted = self.session.encrypt(&nonce, &pkt.to_bytes())?;
-        if let Err(e) = self.socket.send(&encrypted).await {
+        self.traffic.packets_sent += 1;
+        self.traffic.bytes_sent += encrypted.len() as u64;
+        if let Err(e) = self.link.send(&encrypted).await {
             if is_remote_close_error(&e) {
                 self.mark_remote_closed(e);
-                return Ok(());
+                return Ok(encrypted.len());
             }
             return Err(e.into());
         }
-        Ok(())
+        Ok(encrypted.len())
     }
 
     pub async fn readable(&self) -> Result<()> {
-        self.socket.readable().await.context("socket readable failed")?;
+        self.link.readable().await.context("socket readable failed")?;
         Ok(())
     }
 
     /// Drain all currently readable UDP datagrams.
     pub fn drain_recv(&mut self) -> Result<()> {
-        let mut buf = [0u8; 2048];
+        let mut buf = std::mem::take(&mut self.recv_buf);
+        let result = self.drain_recv_into(&mut buf);
+        self.recv_buf = buf;
+        result
+    }
 
+    fn drain_recv_into(&mut self, buf: &mut [u8]) -> Result<()> {
         loop {
-            let n = match self.socket.try_recv(&mut buf) {
+            let n = match self.link.try_recv(buf) {
                 Ok(n) => n,
                 Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
                 Err(e) if is_remote_close_error(&e) => {
@@ -749,6 +950,8 @@ impl Transport {
                 Err(e) => return Err(e.into()),
             };
 
+            self.traffic.packets_received += 1;
+            self.traffic.bytes_received += n as u64;
             self.process_datagram(&buf[..n])?;
         }
 
@@ -758,6 +961,7 @@ impl Transport {
     fn mark_remote_closed(&mut self, err: std::io::Error) {
         if self.remote_closed.is_none() {
             let graceful = self.shutdown_in_progress || self.ack_num == u64::MAX;
+            self.remote_closed_gracefully = graceful;
             if graceful {
                 self.remote_closed = Some("server closed the session".to_string());
             } else {
@@ -782,7 +986,15 @@ impl Transport {
     }
 
     fn process_datagram(&mut self, datagram: &[u8]) -> Result<()> {
-        let (nonce, plaintext) = self.session.decrypt(datagram)?;
+        // Mosh carries on past packets that fail to authenticate.
+        let (nonce, plaintext) = match self.session.decrypt(datagram) {
+            Ok(decrypted) => decrypted,
+            Err(e) => {
+                self.traffic.decrypt_failures += 1;
+                log::debug!("Dropped datagram: {:#}", e);
+                return Ok(());
+            }
+        };
         let _ = crypto::parse_nonce(&{
             let mut w = [0u8; 8]; w.copy_from_slice(&nonce[4..12]); w
         });
@@ -791,10 +1003,11 @@ impl Transport {
         self.last_recv_time = Instant::now();
         self.last_recv_timestamp = packet.timestamp;
         self.last_heard = Instant::now();
+        self.path_mtu.on_receive();
 
         // RTT from timestamp echo
         if packet.timestamp_reply != u16::MAX {
-            let now_ts = current_timestamp();
+            let now_ts = timestamp_since(self.epoch);
             let rtt_ms = if now_ts >= packet.timestamp_reply {
                 (now_ts - packet.timestamp_reply) as f64
             } else {
@@ -817,16 +1030,17 @@ impl Transport {
 
             let ver = ti.protocol_version.unwrap_or_default();
             if ver != MOSH_PROTOCOL_VERSION {
-                anyhow::bail!(
-                    "mosh protocol version mismatch: peer={} local={}",
-                    ver,
-                    MOSH_PROTOCOL_VERSION
-                );
+                return Err(anyhow::anyhow!("peer={} local={}", ver, MOSH_PROTOCOL_VERSION)
+                    .context(ExitStatus::ProtocolVersion));
+            }
+            if let Some(recorder) = self.recorder.as_mut() {
+                recorder.instruction(Flow::Received, &ti);
             }
 
             // Process ack (mosh: process_acknowledgment_through + set_ack_num)
             let ack = ti.ack_num.unwrap_or_default();
             self.process_acknowledgment_through(ack);
+            self.path_mtu.on_ack(ack);
 
             let new_num = ti.new_num.unwrap_or_default();
 
@@ -907,6 +1121,10 @@ impl Transport {
             }
             if latest_num > prev_latest {
                 self.remote_state_changed = true;
+                if let Some(cast) = self.asciicast.as_mut() {
+                    let fb = self.received_states.last().expect("just inserted").state.framebuffer();
+                    cast.state(old_num, new_num, &diff, fb);
+                }
             }
         }
         Ok(())
@@ -926,11 +1144,24 @@ impl Transport {
     pub fn send_interval_ms(&self) -> u64 {
         self.send_interval()
     }
+
+    pub fn stats(&self) -> TransportStats {
+        TransportStats {
+            srtt_ms: self.rtt.srtt,
+            rttvar_ms: self.rtt.rttvar,
+            rto_ms: self.rtt.rto_ms(),
+            send_interval_ms: self.send_interval(),
+            mtu: self.path_mtu.mtu(),
+            traffic: self.traffic,
+            sent_states: self.sent_states.len(),
+            received_states: self.received_states.len(),
+        }
+    }
 }
 
 // ── Zlib compression (Mosh compresses protobuf before encryption) ───────────
 
-fn zlib_compress(data: &[u8]) -> Result<Vec<u8>> {
+pub(crate) fn zlib_compress(data: &[u8]) -> Result<Vec<u8>> {
     let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
     encoder
         .write_all(data)
@@ -948,7 +1179,7 @@ fn make_chaff() -> Vec<u8> {
     out
 }
 
-fn zlib_decompress(data: &[u8]) -> Result<Vec<u8>> {
+pub(crate) fn zlib_decompress(data: &[u8]) -> Result<Vec<u8>> {
     let mut decoder = ZlibDecoder::new(data);
     let mut decompressed = Vec::new();
     decoder
@@ -972,6 +1203,7 @@ fn is_remote_close_error(err: &std::io::Error) -> bool {
 mod tests {
     use super::*;
     use crate::crypto::{make_nonce, Direction, Session};
+    use crate::network::DEFAULT_MTU;
 
     async fn test_transport() -> (Transport, UdpSocket, Base64Key) {
         let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
@@ -1223,6 +1455,318 @@ mod tests {
         assert!(transport.counterparty_shutdown_ack_sent());
     }
 
+    /// Receive whatever the client sent, dropping datagrams over `limit`
+    /// bytes as a path black hole would; returns completed instructions.
+    async fn peer_receive(
+        peer: &UdpSocket,
+        session: &Session,
+        assembly: &mut FragmentAssembly,
+        limit: usize,
+    ) -> Vec<proto::transportinstruction::Instruction> {
+        let mut buf = vec![0u8; RECV_BUFFER_LEN];
+        let mut out = Vec::new();
+        while let Ok(Ok(n)) =
+            tokio::time::timeout(Duration::from_millis(50), peer.recv(&mut buf)).await
+        {
+            if n > limit {
+                continue;
+            }
+            let (_, plaintext) = session.decrypt(&buf[..n]).unwrap();
+            let packet = Packet::from_bytes(&plaintext).unwrap();
+            let fragment = Fragment::from_bytes(&packet.payload).unwrap();
+            if let Some(compressed) = assembly.add_fragment(fragment) {
+                let bytes = zlib_decompress(&compressed).unwrap();
+                out.push(proto::transportinstruction::Instruction::decode(bytes.as_slice()).unwrap());
+            }
+        }
+        out
+    }
+
+    #[tokio::test]
+    async fn large_datagram_black_hole_lowers_mtu() {
+        let (mut transport, peer, key) = test_transport().await;
+        let session = Session::new(&key).unwrap();
+        let mut assembly = FragmentAssembly::new();
+        let limit = 1100;
+
+        // Incompressible input spanning several fragments.
+        let mut input = vec![0u8; 3000];
+        rand::thread_rng().fill_bytes(&mut input);
+        transport.push_user_input(&input);
+
+        let mut server_seq = 0;
+        let mut acked = None;
+        for _ in 0..20 {
+            // Make the next (re)transmission due now.
+            transport.sent_states.last_mut().unwrap().timestamp =
+                Instant::now() - Duration::from_secs(5);
+            transport.mindelay_clock = Some(Instant::now() - Duration::from_secs(1));
+            transport.tick().await.unwrap();
+
+            let got = peer_receive(&peer, &session, &mut assembly, limit).await;
+            let complete = got
+                .iter()
+                .find(|ti| !ti.diff.as_deref().unwrap_or_default().is_empty());
+            // Small packets still get through: the server keeps talking.
+            let ack = complete.and_then(|ti| ti.new_num).unwrap_or(0);
+            server_seq += 1;
+            let reply = proto::transportinstruction::Instruction {
+                protocol_version: Some(MOSH_PROTOCOL_VERSION),
+                old_num: Some(0),
+                new_num: Some(server_seq),
+                ack_num: Some(ack),
+                throwaway_num: Some(0),
+                diff: Some(Vec::new()),
+                chaff: None,
+            };
+            let datagram = build_server_datagram(&key, server_seq, reply);
+            transport.process_datagram(&datagram).unwrap();
+            if complete.is_some() {
+                acked = Some(ack);
+                break;
+            }
+        }
+
+        assert!(acked.is_some(), "instruction never got through");
+        assert!(transport.path_mtu.mtu() <= limit);
+        assert_eq!(transport.acked_state_num(), acked.unwrap());
+    }
+
+    #[tokio::test]
+    async fn receives_datagrams_larger_than_default_mtu() {
+        let (mut transport, peer, key) = test_transport().await;
+
+        let mut output = vec![0u8; 1400];
+        rand::thread_rng().fill_bytes(&mut output);
+        let ti = proto::transportinstruction::Instruction {
+            protocol_version: Some(MOSH_PROTOCOL_VERSION),
+            old_num: Some(0),
+            new_num: Some(1),
+            ack_num: Some(0),
+            throwaway_num: Some(0),
+            diff: Some(host_diff(&output)),
+            chaff: None,
+        };
+        let datagram = build_server_datagram(&key, 0, ti);
+        assert!(datagram.len() > DEFAULT_MTU);
+        let local = transport.local_addr().unwrap();
+        peer.send_to(&datagram, ("127.0.0.1", local.port())).await.unwrap();
+
+        transport.readable().await.unwrap();
+        transport.drain_recv().unwrap();
+        assert_eq!(transport.ack_num, 1);
+    }
+
+    // ── Simulated network ──────────────────────────────────────────────
+
+    use crate::linksim::{sim_pair, FakeServer, Impairment, LinkStats, Side};
+
+    fn sim_session(up: Impairment, down: Impairment, seed: u64) -> (Transport, FakeServer) {
+        let key = Base64Key::from_str("AAAAAAAAAAAAAAAAAAAAAA").unwrap();
+        let (client, server) = sim_pair(up, down, seed);
+        let transport = Transport::with_sim_link(&key, client, 80, 24).unwrap();
+        (transport, FakeServer::new(&key, server))
+    }
+
+    /// Run both ends for `duration` of virtual time in 1 ms steps.
+    async fn run_sim(transport: &mut Transport, server: &mut FakeServer, duration: Duration) {
+        let end = Instant::now() + duration;
+        while Instant::now() < end {
+            transport.drain_recv().unwrap();
+            transport.tick().await.unwrap();
+            server.poll().await;
+            tokio::time::advance(Duration::from_millis(1)).await;
+        }
+    }
+
+    fn link_stats(transport: &Transport, from: Side) -> LinkStats {
+        match &transport.link {
+            Link::Sim(endpoint) => endpoint.stats(from),
+            Link::Udp(_) => unreachable!("not a simulated link"),
+        }
+    }
+
+    fn set_impairment(transport: &Transport, from: Side, impairment: Impairment) {
+        match &transport.link {
+            Link::Sim(endpoint) => endpoint.set_impairment(from, impairment),
+            Link::Udp(_) => unreachable!("not a simulated link"),
+        }
+    }
+
+    fn keystrokes(bytes: &[u8]) -> UserStream {
+        let mut stream = UserStream::new();
+        stream.push_keystrokes(bytes);
+        stream
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sim_input_survives_lossy_link() {
+        let bad = Impairment {
+            loss: 0.2,
+            duplicate: 0.05,
+            reorder: 0.1,
+            delay: Duration::from_millis(30),
+            jitter: Duration::from_millis(20),
+            bandwidth: Some(50_000),
+        };
+        let (mut transport, mut server) = sim_session(bad.clone(), bad, 1);
+
+        let mut typed = Vec::new();
+        for i in 0..100u32 {
+            let burst = format!("line {} {}\r", i, "x".repeat((i % 7) as usize));
+            transport.push_user_input(burst.as_bytes());
+            typed.extend_from_slice(burst.as_bytes());
+            run_sim(&mut transport, &mut server, Duration::from_millis(50)).await;
+        }
+        run_sim(&mut transport, &mut server, Duration::from_secs(5)).await;
+
+        let (num, stream) = server.latest();
+        assert_eq!(stream, &keystrokes(&typed));
+        assert_eq!(transport.acked_state_num(), num);
+        let link = link_stats(&transport, Side::Client);
+        assert!(link.dropped > 0 && link.duplicated > 0, "{:?}", link);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sim_next_wakeup_follows_the_timers() {
+        let (mut transport, mut server) = sim_session(Impairment::default(), Impairment::default(), 3);
+        run_sim(&mut transport, &mut server, Duration::from_secs(1)).await;
+
+        // Idle: nothing until the heartbeat.
+        let idle = transport.next_wakeup().unwrap() - Instant::now();
+        assert!(idle > Duration::from_secs(1) && idle <= Duration::from_millis(ACK_INTERVAL), "{:?}", idle);
+
+        // Typing: the send is due within the send interval.
+        transport.push_user_input(b"x");
+        let typing = transport.next_wakeup().unwrap() - Instant::now();
+        assert!(typing <= Duration::from_millis(SEND_INTERVAL_MAX), "{:?}", typing);
+        tokio::time::advance(typing).await;
+        let sent = transport.stats().traffic.packets_sent;
+        transport.tick().await.unwrap();
+        assert_eq!(transport.stats().traffic.packets_sent, sent + 1);
+
+        // Waking early does nothing.
+        let next = transport.next_wakeup().unwrap();
+        tokio::time::advance((next - Instant::now()) / 2).await;
+        transport.tick().await.unwrap();
+        assert_eq!(transport.stats().traffic.packets_sent, sent + 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sim_stats_count_traffic() {
+        let lossy = Impairment { loss: 0.3, ..Impairment::default() };
+        let (mut transport, mut server) = sim_session(lossy, Impairment::default(), 7);
+        for i in 0..20u32 {
+            transport.push_user_input(format!("{}\r", i).as_bytes());
+            run_sim(&mut transport, &mut server, Duration::from_millis(100)).await;
+        }
+        run_sim(&mut transport, &mut server, Duration::from_secs(3)).await;
+
+        let stats = transport.stats();
+        let (up, down) = (link_stats(&transport, Side::Client), link_stats(&transport, Side::Server));
+        assert_eq!(stats.traffic.packets_sent, up.sent);
+        assert_eq!(stats.traffic.packets_received, down.delivered);
+        assert!(stats.traffic.bytes_sent > stats.traffic.packets_sent * 20);
+        assert!(stats.traffic.retransmits > 0, "{:?}", stats);
+        assert_eq!(stats.traffic.decrypt_failures, 0);
+        assert!(stats.sent_states >= 1 && stats.received_states >= 1);
+
+        // Junk is counted and dropped; the session carries on.
+        server.send_raw(&[0u8; 40]);
+        tokio::time::advance(Duration::from_millis(1)).await;
+        transport.drain_recv().unwrap();
+        assert_eq!(transport.stats().traffic.decrypt_failures, 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sim_same_seed_same_session() {
+        let bad = Impairment {
+            loss: 0.3,
+            reorder: 0.2,
+            delay: Duration::from_millis(25),
+            jitter: Duration::from_millis(25),
+            ..Default::default()
+        };
+        let mut traces = Vec::new();
+        for _ in 0..2 {
+            let (mut transport, mut server) = sim_session(bad.clone(), bad.clone(), 42);
+            for i in 0..20u8 {
+                transport.push_user_input(&[b'a' + i]);
+                run_sim(&mut transport, &mut server, Duration::from_millis(40)).await;
+            }
+            run_sim(&mut transport, &mut server, Duration::from_secs(2)).await;
+            let trace: Vec<(u64, u64)> = server
+                .received()
+                .iter()
+                .map(|ti| (ti.old_num.unwrap_or_default(), ti.new_num.unwrap_or_default()))
+                .collect();
+            traces.push(trace);
+        }
+        assert!(!traces[0].is_empty());
+        assert_eq!(traces[0], traces[1]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sim_rtt_estimate_tracks_link_delay() {
+        let slow = Impairment { delay: Duration::from_millis(100), ..Default::default() };
+        let (mut transport, mut server) = sim_session(slow.clone(), slow, 1);
+        for _ in 0..50 {
+            transport.push_user_input(b"k");
+            run_sim(&mut transport, &mut server, Duration::from_millis(300)).await;
+        }
+        let srtt = transport.rtt.srtt;
+        assert!((195.0..=215.0).contains(&srtt), "srtt {}", srtt);
+        assert!(transport.rtt.rttvar < 20.0, "rttvar {}", transport.rtt.rttvar);
+        assert_eq!(transport.send_interval_ms(), (srtt / 2.0).ceil() as u64);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sim_prospective_resend_rebases_on_acked_state() {
+        let clean = Impairment { delay: Duration::from_millis(10), ..Default::default() };
+        let (mut transport, mut server) = sim_session(clean.clone(), clean.clone(), 1);
+        run_sim(&mut transport, &mut server, Duration::from_millis(100)).await;
+
+        // The state carrying "a" is lost...
+        set_impairment(&transport, Side::Client, Impairment { loss: 1.0, ..clean.clone() });
+        transport.push_user_input(b"a");
+        run_sim(&mut transport, &mut server, Duration::from_millis(30)).await;
+        let lost = transport.sent_state_last_num();
+        assert!(server.received().iter().all(|ti| ti.new_num != Some(lost)));
+
+        // ...and the next one is sent as a diff from the acknowledged state,
+        // well before the lost one times out, rather than from the lost one.
+        set_impairment(&transport, Side::Client, clean);
+        transport.push_user_input(b"b");
+        let acked = transport.acked_state_num();
+        run_sim(&mut transport, &mut server, Duration::from_millis(60)).await;
+        let first = server.received().iter().find(|ti| ti.new_num > Some(lost)).unwrap();
+        assert_eq!(first.old_num, Some(acked));
+        assert_eq!(server.latest().1, &keystrokes(b"ab"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sim_shutdown_completes_over_lossy_link() {
+        let bad = Impairment {
+            loss: 0.3,
+            delay: Duration::from_millis(40),
+            jitter: Duration::from_millis(10),
+            ..Default::default()
+        };
+        let (mut transport, mut server) = sim_session(bad.clone(), bad, 3);
+        transport.push_user_input(b"exit\r");
+        run_sim(&mut transport, &mut server, Duration::from_secs(1)).await;
+
+        transport.start_shutdown();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !transport.shutdown_acknowledged() && Instant::now() < deadline {
+            run_sim(&mut transport, &mut server, Duration::from_millis(10)).await;
+            assert!(!transport.shutdown_ack_timed_out());
+        }
+        assert!(transport.shutdown_acknowledged());
+        assert_eq!(server.latest().0, u64::MAX);
+        assert!(transport.shutdown_tries > 1, "no retransmission was needed");
+    }
+
     #[test]
     fn connection_reset_is_treated_as_remote_close() {
         let err = std::io::Error::new(std::io::ErrorKind::ConnectionReset, "reset");
@@ -53,6 +53,11 @@ impl UserStream {
         self.actions.len()
     }
 
+    /// The events in order (mosh: `get_action`).
+    pub fn events(&self) -> &[UserEvent] {
+        &self.actions
+    }
+
     /// Remove a prefix of events that match `prefix`.
     /// Mosh: `UserStream::subtract(const UserStream *prefix)`.
     pub fn subtract(&mut self, prefix: &UserStream) {